@@ -0,0 +1,63 @@
+//! Measures connection handling with and without read buffers being reused,
+//! for a request that fits the buffer's first allocation and one that grows
+//! it. Run with `cargo run --release --example pool_bench`.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+
+use http_server_starter_rust::{
+    config::{Config, LogLevel},
+    pool::BufferPool,
+    server::{self, AppState, State},
+};
+
+const CONNECTIONS: u32 = 20_000;
+const RUNS: usize = 5;
+
+/// Time per connection to serve a request, best of RUNS
+async fn measure(request: &[u8], pool: fn() -> BufferPool) -> Duration {
+    let config = Arc::new(Config {
+        log_level: LogLevel::Error,
+        ..Default::default()
+    });
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let state = State::new(AppState {
+            buffer_pool: pool(),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        for _ in 0..CONNECTIONS {
+            let (mut client, server_side) = io::duplex(64 * 1024);
+            let conn = server::handle_conn(server_side, None, Arc::clone(&config), state.clone());
+            let client = async {
+                client.write_all(request).await.unwrap();
+                client.shutdown().await.unwrap();
+                let mut response = Vec::new();
+                client.read_to_end(&mut response).await.unwrap();
+            };
+            let (result, ()) = tokio::join!(conn, client);
+            result.unwrap();
+        }
+        best = best.min(start.elapsed() / CONNECTIONS);
+    }
+    best
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let small = b"GET /echo/abc HTTP/1.1\r\nConnection: close\r\n\r\n".to_vec();
+    let mut large = b"POST /echo/abc HTTP/1.1\r\nConnection: close\r\n".to_vec();
+    large.extend_from_slice(b"Content-Length: 16384\r\n\r\n");
+    large.resize(large.len() + 16384, b'a');
+
+    for (name, request) in [("small request", &small), ("16 KiB body", &large)] {
+        let pooled = measure(request, BufferPool::default).await;
+        let unpooled = measure(request, || BufferPool::new(0)).await;
+        println!("{name}: {pooled:?} pooled, {unpooled:?} without reuse");
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{date::log_date, http};
+
+/// A file that a line is appended to for every request
+#[derive(Debug)]
+pub struct AccessLog {
+    file: Mutex<File>,
+}
+
+impl AccessLog {
+    /// Open the log for appending, creating it if needed
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append a line for a request and the response to it. Failing to log
+    /// shouldn't fail the request, so errors are only reported.
+    pub fn record(
+        &self,
+        peer_addr: Option<SocketAddr>,
+        req: &http::Request,
+        resp: &http::Response,
+    ) {
+        let line = combined_line(peer_addr, SystemTime::now(), req, resp);
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            println!("  Access log - fail, {e}");
+        }
+    }
+}
+
+/// A line in the Combined Log Format, such as
+/// `127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] "GET / HTTP/1.1" 200 5 "-" "curl/8.0"`
+pub fn combined_line(
+    peer_addr: Option<SocketAddr>,
+    time: SystemTime,
+    req: &http::Request,
+    resp: &http::Response,
+) -> String {
+    let client = peer_addr.map_or_else(|| String::from("-"), |addr| addr.ip().to_string());
+    let size = match resp.body.as_ref() {
+        Some(body) if !body.is_empty() => body.len().to_string(),
+        _ => String::from("-"),
+    };
+    format!(
+        "{client} - - [{}] \"{} {} {}\" {} {size} \"{}\" \"{}\"\n",
+        log_date(time),
+        req.method_str(),
+        req.req_line.target,
+        req.req_line.version,
+        resp.status_line.status.code(),
+        quoted(req.header("referer")),
+        quoted(req.header("user-agent")),
+    )
+}
+
+/// A header value made safe to put between quotes, or `-` if it's missing
+fn quoted(value: Option<&str>) -> String {
+    value.map_or_else(
+        || String::from("-"),
+        |value| value.replace('\\', "\\\\").replace('"', "\\\""),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn test_combined_line() {
+        let input = b"GET /echo/abc?x=1 HTTP/1.1\r\nReferer: https://a.example/\r\nUser-Agent: curl/8.0 \"test\"\r\n\r\n";
+        let (_, req) = http::Request::parser(input).unwrap();
+        let resp = http::Response::new(http::Status::Ok).with_body(b"abc", "text/plain");
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(784111777);
+        let peer_addr = "127.0.0.1:5000".parse().ok();
+
+        assert_eq!(
+            combined_line(peer_addr, time, &req, &resp),
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /echo/abc?x=1 HTTP/1.1\" 200 3 \
+             \"https://a.example/\" \"curl/8.0 \\\"test\\\"\"\n"
+        );
+
+        let resp = http::Response::new(http::Status::NoContent);
+        let (_, req) = http::Request::parser(b"DELETE /files/a HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(
+            combined_line(None, time, &req, &resp),
+            "- - - [06/Nov/1994:08:49:37 +0000] \"DELETE /files/a HTTP/1.1\" 204 - \"-\" \"-\"\n"
+        );
+    }
+
+    #[test]
+    fn test_access_log_file() {
+        let dir = std::env::temp_dir().join(format!("access-log-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        fs::write(&path, "earlier line\n").unwrap();
+
+        let log = AccessLog::open(&path).unwrap();
+        let (_, req) = http::Request::parser(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        log.record(None, &req, &http::Response::new(http::Status::Ok));
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "earlier line");
+        assert!(lines[1].starts_with("- - - ["));
+        assert!(lines[1].ends_with("] \"GET / HTTP/1.1\" 200 - \"-\" \"-\""));
+
+        assert!(AccessLog::open(&dir.join("missing").join("access.log")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
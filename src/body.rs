@@ -0,0 +1,196 @@
+use std::{
+    fmt, io, ptr,
+    sync::{Mutex, PoisonError},
+};
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+/// Largest frame of a streamed body
+pub const FRAME_SIZE: usize = 16 * 1024;
+
+/// Frames read from the connection but not yet taken by the handler. This
+/// bounds how much of an upload is in memory at once.
+const QUEUED_FRAMES: usize = 4;
+
+enum Frame {
+    Data(Bytes),
+    End,
+}
+
+/// A request body handed to the handler a frame at a time while it's still
+/// being read from the connection, so that a large upload can be processed
+/// without holding all of it in memory
+pub struct BodyStream {
+    frames: Mutex<Receiver>,
+}
+
+struct Receiver {
+    frames: mpsc::Receiver<Frame>,
+    ended: bool,
+}
+
+/// The connection's end of a BodyStream, which passes on the body as it's read
+pub struct BodySender {
+    frames: mpsc::Sender<Frame>,
+}
+
+/// A BodyStream along with the sender that feeds it
+pub fn channel() -> (BodySender, BodyStream) {
+    let (tx, rx) = mpsc::channel(QUEUED_FRAMES);
+    let stream = BodyStream {
+        frames: Mutex::new(Receiver {
+            frames: rx,
+            ended: false,
+        }),
+    };
+    (BodySender { frames: tx }, stream)
+}
+
+impl BodyStream {
+    /// The next frame, or None once the whole body has arrived. A body that's
+    /// cut short, because reading it failed or was given up, ends with an
+    /// error rather than looking complete. This blocks waiting for the frame,
+    /// so it's for handlers, which run on the blocking thread pool.
+    pub fn blocking_next(&self) -> Option<io::Result<Bytes>> {
+        let mut receiver = self.frames.lock().unwrap_or_else(PoisonError::into_inner);
+        if receiver.ended {
+            return None;
+        }
+        match receiver.frames.blocking_recv() {
+            Some(Frame::Data(frame)) => Some(Ok(frame)),
+            Some(Frame::End) => {
+                receiver.ended = true;
+                None
+            }
+            None => {
+                receiver.ended = true;
+                Some(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "request body cut short",
+                )))
+            }
+        }
+    }
+
+    /// Take no more frames, so that the connection discards the rest of the
+    /// body rather than waiting for them to be taken
+    pub fn close(&self) {
+        let mut receiver = self.frames.lock().unwrap_or_else(PoisonError::into_inner);
+        receiver.frames.close();
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyStream").finish_non_exhaustive()
+    }
+}
+
+// A stream is only ever equal to itself, which lets requests holding one still
+// be compared
+impl PartialEq for BodyStream {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl Eq for BodyStream {}
+
+impl BodySender {
+    /// Pass on the next frame. Returns false if the handler has stopped
+    /// taking frames, in which case the rest of the body can be discarded.
+    pub async fn send(&self, frame: Bytes) -> bool {
+        self.frames.send(Frame::Data(frame)).await.is_ok()
+    }
+
+    /// Mark the end of the body. Dropping the sender without calling this
+    /// tells the handler the body was cut short.
+    pub async fn finish(self) {
+        // The handler may have stopped taking frames, which is fine
+        let _ = self.frames.send(Frame::End).await;
+    }
+}
+
+/// The frames of a request body, from Request::body_stream
+pub struct BodyFrames<'a>(Source<'a>);
+
+enum Source<'a> {
+    Buffered(Option<&'a [u8]>),
+    Streamed(&'a BodyStream),
+}
+
+impl<'a> BodyFrames<'a> {
+    pub(crate) fn buffered(body: &'a [u8]) -> Self {
+        Self(Source::Buffered(Some(body).filter(|body| !body.is_empty())))
+    }
+
+    pub(crate) fn streamed(stream: &'a BodyStream) -> Self {
+        Self(Source::Streamed(stream))
+    }
+}
+
+impl Iterator for BodyFrames<'_> {
+    type Item = io::Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            Source::Buffered(body) => body.take().map(|body| Ok(Bytes::copy_from_slice(body))),
+            Source::Streamed(stream) => stream.blocking_next(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_body_stream() {
+        let body: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let (sender, stream) = channel();
+        let consumer = tokio::task::spawn_blocking(move || {
+            let mut received = Vec::new();
+            for frame in BodyFrames::streamed(&stream) {
+                received.extend_from_slice(&frame.unwrap());
+            }
+            received
+        });
+
+        for frame in body.chunks(FRAME_SIZE) {
+            assert!(sender.send(Bytes::copy_from_slice(frame)).await);
+        }
+        sender.finish().await;
+        assert_eq!(consumer.await.unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn test_body_stream_cut_short() {
+        let (sender, stream) = channel();
+        let consumer = tokio::task::spawn_blocking(move || {
+            let frames: Vec<_> = BodyFrames::streamed(&stream).collect();
+            assert_eq!(&frames[0].as_ref().unwrap()[..], b"abc");
+            let err = frames[1].as_ref().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            assert_eq!(frames.len(), 2);
+        });
+
+        assert!(sender.send(Bytes::from_static(b"abc")).await);
+        drop(sender);
+        consumer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_body_stream_closed() {
+        let (sender, stream) = channel();
+        stream.close();
+        assert!(!sender.send(Bytes::from_static(b"abc")).await);
+    }
+
+    #[test]
+    fn test_buffered_frames() {
+        let frames: Vec<_> = BodyFrames::buffered(b"abc").map(Result::unwrap).collect();
+        assert_eq!(frames, [Bytes::from_static(b"abc")]);
+        assert_eq!(BodyFrames::buffered(b"").count(), 0);
+    }
+}
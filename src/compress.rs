@@ -0,0 +1,1098 @@
+//! Body compression and decompression. DEFLATE is implemented here using the
+//! fixed Huffman codes, which keeps things small while still compressing well
+//! for the repetitive text bodies a server usually sends. Decompression
+//! handles every block type so that bodies from any client can be read.
+//! Brotli uses the same LZ77 matches, but with prefix codes built to suit the
+//! body, and is only produced.
+
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum DecompressError {
+    #[error("invalid compressed data")]
+    Invalid,
+    #[error("decompressed data is larger than {0} bytes")]
+    TooLarge(usize),
+}
+
+/// A content-coding the server can produce
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Preferred order when the client rates several codings equally
+    pub const PREFERENCE: [Self; 3] = [Self::Brotli, Self::Gzip, Self::Deflate];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    /// Pick the best of the offered codings given the client's
+    /// Accept-Encoding preferences, which are (coding, q-value) pairs sorted
+    /// by descending quality. Offered codings are in order of preference.
+    pub fn negotiate(accepted: &[(String, f32)], offered: &[Self]) -> Option<Self> {
+        let quality = |enc: Self| {
+            accepted
+                .iter()
+                .find(|(name, _)| name == enc.name())
+                .or_else(|| accepted.iter().find(|(name, _)| name == "*"))
+                .map_or(0.0, |(_, q)| *q)
+        };
+
+        let mut best: Option<(Self, f32)> = None;
+        for &enc in offered {
+            let q = quality(enc);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((enc, q));
+            }
+        }
+        best.map(|(enc, _)| enc)
+    }
+
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Brotli => brotli(data),
+            Self::Gzip => gzip(data),
+            Self::Deflate => zlib(data),
+        }
+    }
+}
+
+/// Smallest body worth compressing for each content type
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CompressionPolicy {
+    /// (content type, minimum length) pairs, where the first matching one
+    /// wins. A type like `text/*` matches the whole family. Types without a
+    /// rule are always compressed.
+    pub rules: Vec<(String, usize)>,
+}
+
+impl CompressionPolicy {
+    /// The minimum length for a content type, ignoring any parameters
+    fn min_len(&self, content_type: &str) -> usize {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        let family = mime.split_once('/').map_or(mime, |(family, _)| family);
+        self.rules
+            .iter()
+            .find(|(rule, _)| match rule.strip_suffix("/*") {
+                Some(rule_family) => rule_family.eq_ignore_ascii_case(family),
+                None => rule.eq_ignore_ascii_case(mime),
+            })
+            .map_or(0, |(_, min_len)| *min_len)
+    }
+}
+
+/// Whether a body of this type and length should be compressed
+pub fn should_compress(content_type: &str, len: usize, policy: &CompressionPolicy) -> bool {
+    len >= policy.min_len(content_type)
+}
+
+/// Wrap raw DEFLATE data in the gzip format (RFC 1952)
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    // Magic, deflate method, no flags, no mtime, no extra flags, unknown OS
+    let mut output = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    output.extend(deflate(data));
+    output.extend(crc32(data).to_le_bytes());
+    output.extend((data.len() as u32).to_le_bytes());
+    output
+}
+
+/// Wrap raw DEFLATE data in the zlib format (RFC 1950), which is what the
+/// `deflate` content-coding means
+pub fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![0x78, 0x01];
+    output.extend(deflate(data));
+    output.extend(adler32(data).to_be_bytes());
+    output
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for x in chunk {
+            a += u32::from(*x);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+const HASH_SIZE: usize = 1 << 15;
+
+/// Base length and number of extra bits for length codes 257..=285
+#[rustfmt::skip]
+pub(crate) const LENGTH_CODES: [(u16, u8); 29] = [
+    (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 0),
+    (11, 1), (13, 1), (15, 1), (17, 1), (19, 2), (23, 2), (27, 2), (31, 2),
+    (35, 3), (43, 3), (51, 3), (59, 3), (67, 4), (83, 4), (99, 4), (115, 4),
+    (131, 5), (163, 5), (195, 5), (227, 5), (258, 0),
+];
+
+/// Base distance and number of extra bits for distance codes 0..=29
+#[rustfmt::skip]
+pub(crate) const DISTANCE_CODES: [(u16, u8); 30] = [
+    (1, 0), (2, 0), (3, 0), (4, 0), (5, 1), (7, 1), (9, 2), (13, 2),
+    (17, 3), (25, 3), (33, 4), (49, 4), (65, 5), (97, 5), (129, 6), (193, 6),
+    (257, 7), (385, 7), (513, 8), (769, 8), (1025, 9), (1537, 9), (2049, 10), (3073, 10),
+    (4097, 11), (6145, 11), (8193, 12), (12289, 12), (16385, 13), (24577, 13),
+];
+
+/// A step of LZ77 output: a byte as it is, or a copy of earlier bytes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Token {
+    Literal(u8),
+    Match { len: u16, dist: u16 },
+}
+
+/// Find repeats of earlier data, up to WINDOW_SIZE back
+fn lz77(data: &[u8]) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut head = vec![usize::MAX; HASH_SIZE];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+
+    let mut i = 0;
+    while i < data.len() {
+        // Walk the chain of earlier positions with the same hash, keeping the
+        // longest match
+        let (mut best_len, mut best_dist) = (0, 0);
+        if i + MIN_MATCH <= data.len() {
+            let max_len = MAX_MATCH.min(data.len() - i);
+            let mut candidate = head[hash(data, i)];
+            let mut chain = 0;
+            while candidate != usize::MAX && i - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[i..i + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    best_len = len;
+                    best_dist = i - candidate;
+                    if len == max_len {
+                        break;
+                    }
+                }
+                let next = prev[candidate % WINDOW_SIZE];
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+
+        if best_len >= MIN_MATCH {
+            tokens.push(Token::Match {
+                len: best_len as u16,
+                dist: best_dist as u16,
+            });
+            for j in i..i + best_len {
+                insert(data, j, &mut head, &mut prev);
+            }
+            i += best_len;
+        } else {
+            tokens.push(Token::Literal(data[i]));
+            insert(data, i, &mut head, &mut prev);
+            i += 1;
+        }
+    }
+    tokens
+}
+
+/// Compress to a single DEFLATE block (RFC 1951) using the fixed Huffman codes
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::default();
+    out.write_bits(1, 1); // Final block
+    out.write_bits(1, 2); // Fixed Huffman codes
+
+    for token in lz77(data) {
+        match token {
+            Token::Literal(byte) => out.write_symbol(u16::from(byte)),
+            Token::Match { len, dist } => {
+                out.write_length(len);
+                out.write_distance(dist);
+            }
+        }
+    }
+
+    out.write_symbol(256); // End of block
+    out.finish()
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let h = (u32::from(data[i]) << 16) | (u32::from(data[i + 1]) << 8) | u32::from(data[i + 2]);
+    (h.wrapping_mul(2654435761) >> 17) as usize % HASH_SIZE
+}
+
+/// Record position i as the most recent start of its 3 byte sequence
+fn insert(data: &[u8], i: usize, head: &mut [usize], prev: &mut [usize]) {
+    if i + MIN_MATCH <= data.len() {
+        let h = hash(data, i);
+        prev[i % WINDOW_SIZE] = head[h];
+        head[h] = i;
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    output: Vec<u8>,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    /// Write the low `count` bits of `value`, least significant first
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.bit_buf |= value << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.output.push(self.bit_buf as u8);
+            self.bit_buf >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    /// Huffman codes are packed most significant bit first
+    fn write_code(&mut self, code: u32, len: u32) {
+        self.write_bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    /// Write a literal/length symbol using the fixed code
+    fn write_symbol(&mut self, sym: u16) {
+        let sym = u32::from(sym);
+        match sym {
+            0..=143 => self.write_code(0x30 + sym, 8),
+            144..=255 => self.write_code(0x190 + sym - 144, 9),
+            256..=279 => self.write_code(sym - 256, 7),
+            _ => self.write_code(0xc0 + sym - 280, 8),
+        }
+    }
+
+    fn write_length(&mut self, len: u16) {
+        let idx = LENGTH_CODES
+            .iter()
+            .rposition(|(base, _)| *base <= len)
+            .unwrap();
+        let (base, extra) = LENGTH_CODES[idx];
+        self.write_symbol(257 + idx as u16);
+        self.write_bits(u32::from(len - base), u32::from(extra));
+    }
+
+    fn write_distance(&mut self, dist: u16) {
+        let idx = DISTANCE_CODES
+            .iter()
+            .rposition(|(base, _)| *base <= dist)
+            .unwrap();
+        let (base, extra) = DISTANCE_CODES[idx];
+        self.write_code(idx as u32, 5);
+        self.write_bits(u32::from(dist - base), u32::from(extra));
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.output.push(self.bit_buf as u8);
+        }
+        self.output
+    }
+}
+
+/// Most bytes a Brotli meta-block can hold
+const MAX_META_BLOCK: usize = 1 << 24;
+
+/// Longest code allowed in a Brotli prefix code, and in the prefix code that
+/// sends its code lengths
+const MAX_CODE_LEN: u8 = 15;
+const MAX_CODE_LEN_CODE_LEN: u8 = 5;
+
+/// Code length symbol standing for a run of zero lengths
+const REPEAT_ZERO: usize = 17;
+
+/// Order in which the lengths of the code length code are sent
+const CODE_LEN_ORDER: [usize; 18] = [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+/// Base insert length and number of extra bits for insert length codes
+#[rustfmt::skip]
+const INSERT_CODES: [(u32, u8); 24] = [
+    (0, 0), (1, 0), (2, 0), (3, 0), (4, 0), (5, 0), (6, 1), (8, 1),
+    (10, 2), (14, 2), (18, 3), (26, 3), (34, 4), (50, 4), (66, 5), (98, 5),
+    (130, 6), (194, 7), (322, 8), (578, 9), (1090, 10), (2114, 12), (6210, 14), (22594, 24),
+];
+
+/// Base copy length and number of extra bits for copy length codes
+#[rustfmt::skip]
+const COPY_CODES: [(u32, u8); 24] = [
+    (2, 0), (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0),
+    (10, 1), (12, 1), (14, 2), (18, 2), (22, 3), (30, 3), (38, 4), (54, 4),
+    (70, 5), (102, 5), (134, 6), (198, 7), (326, 8), (582, 9), (1094, 10), (2118, 24),
+];
+
+/// A Brotli command: some literal bytes, then a copy of earlier output. The
+/// last command in a meta-block may have no copy.
+struct Command {
+    literals: std::ops::Range<usize>,
+    copy: Option<(u16, u16)>,
+}
+
+impl Command {
+    /// The insert-and-copy length symbol, followed by the extra bits for each
+    /// length as (value, count) pairs
+    fn lengths(&self) -> (usize, [(u32, u32); 2]) {
+        let code = |codes: &[(u32, u8)], len: u32| {
+            let idx = codes.iter().rposition(|(base, _)| *base <= len).unwrap();
+            let (base, extra) = codes[idx];
+            (idx, (len - base, u32::from(extra)))
+        };
+        let (insert, insert_extra) = code(&INSERT_CODES, self.literals.len() as u32);
+        // The copy length doesn't matter for a command that ends the data
+        let copy_len = self.copy.map_or(2, |(len, _)| u32::from(len));
+        let (copy, copy_extra) = code(&COPY_CODES, copy_len);
+
+        // Symbols are grouped in 64s by the high bits of both codes. These are
+        // the groups that read an explicit distance.
+        let group = match (insert >> 3, copy >> 3) {
+            (0, 0) => 128,
+            (0, 1) => 192,
+            (1, 0) => 256,
+            (1, 1) => 320,
+            (0, 2) => 384,
+            (2, 0) => 448,
+            (1, 2) => 512,
+            (2, 1) => 576,
+            _ => 640,
+        };
+        let symbol = group + ((insert & 7) << 3) + (copy & 7);
+        (symbol, [insert_extra, copy_extra])
+    }
+}
+
+/// The distance symbol and its extra bits, with no direct distance codes and
+/// no postfix bits
+fn distance_symbol(dist: u16) -> (usize, (u32, u32)) {
+    let value = u32::from(dist) + 3;
+    let bits = 31 - value.leading_zeros() - 1;
+    let high = (value >> bits) & 1;
+    let symbol = 16 + 2 * (bits - 1) + high;
+    (symbol as usize, (value - ((2 + high) << bits), bits))
+}
+
+/// Compress to the Brotli format (RFC 7932), which is the `br` content-coding
+pub fn brotli(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::default();
+    out.write_bits(0, 1); // A 64K window, which covers the LZ77 window
+    if data.is_empty() {
+        out.write_bits(0b11, 2); // Last meta-block, and empty
+        return out.finish();
+    }
+
+    // Each meta-block is matched alone, since they're rarely more than one
+    let mut blocks = data.chunks(MAX_META_BLOCK).peekable();
+    while let Some(block) = blocks.next() {
+        write_meta_block(&mut out, block, blocks.peek().is_none());
+    }
+    out.finish()
+}
+
+fn write_meta_block(out: &mut BitWriter, data: &[u8], last: bool) {
+    let mut commands = Vec::new();
+    let (mut pos, mut start) = (0, 0);
+    for token in lz77(data) {
+        match token {
+            Token::Literal(_) => pos += 1,
+            Token::Match { len, dist } => {
+                commands.push(Command {
+                    literals: start..pos,
+                    copy: Some((len, dist)),
+                });
+                pos += usize::from(len);
+                start = pos;
+            }
+        }
+    }
+    if start < pos {
+        commands.push(Command {
+            literals: start..pos,
+            copy: None,
+        });
+    }
+
+    let mut literal_counts = [0; 256];
+    let mut command_counts = [0; 704];
+    let mut distance_counts = [0; 64];
+    for command in &commands {
+        for byte in &data[command.literals.clone()] {
+            literal_counts[usize::from(*byte)] += 1;
+        }
+        command_counts[command.lengths().0] += 1;
+        if let Some((_, dist)) = command.copy {
+            distance_counts[distance_symbol(dist).0] += 1;
+        }
+    }
+    let literal_code = PrefixCode::new(&literal_counts, MAX_CODE_LEN);
+    let command_code = PrefixCode::new(&command_counts, MAX_CODE_LEN);
+    let distance_code = PrefixCode::new(&distance_counts, MAX_CODE_LEN);
+
+    out.write_bits(u32::from(last), 1);
+    if last {
+        out.write_bits(0, 1); // Not empty
+    }
+    let len = data.len() as u32 - 1;
+    let nibbles = (32 - len.leading_zeros()).div_ceil(4).max(4);
+    out.write_bits(nibbles - 4, 2);
+    out.write_bits(len, nibbles * 4);
+    if !last {
+        out.write_bits(0, 1); // Compressed
+    }
+    out.write_bits(0, 3); // One block type each for literals, commands and distances
+    out.write_bits(0, 6); // No postfix bits or direct distance codes
+    out.write_bits(0, 2); // Context mode, which doesn't matter with one literal code
+    out.write_bits(0, 2); // One literal code and one distance code
+    literal_code.write(out);
+    command_code.write(out);
+    distance_code.write(out);
+
+    for command in &commands {
+        let (symbol, extra) = command.lengths();
+        command_code.write_symbol(out, symbol);
+        for (value, count) in extra {
+            out.write_bits(value, count);
+        }
+        for byte in &data[command.literals.clone()] {
+            literal_code.write_symbol(out, usize::from(*byte));
+        }
+        if let Some((_, dist)) = command.copy {
+            let (symbol, (value, count)) = distance_symbol(dist);
+            distance_code.write_symbol(out, symbol);
+            out.write_bits(value, count);
+        }
+    }
+}
+
+/// A prefix code built for how often each symbol appears
+struct PrefixCode {
+    lengths: Vec<u8>,
+    codes: Vec<u32>,
+}
+
+impl PrefixCode {
+    fn new(counts: &[u32], max_len: u8) -> Self {
+        let lengths = code_lengths(counts, max_len);
+
+        // Canonical codes, in order of length and then symbol
+        let mut len_counts = [0u32; 16];
+        for len in &lengths {
+            len_counts[usize::from(*len)] += 1;
+        }
+        len_counts[0] = 0;
+        let mut next_code = [0u32; 16];
+        for len in 1..16 {
+            next_code[len] = (next_code[len - 1] + len_counts[len - 1]) << 1;
+        }
+        let codes = lengths
+            .iter()
+            .map(|len| {
+                let code = next_code[usize::from(*len)];
+                next_code[usize::from(*len)] += 1;
+                code
+            })
+            .collect();
+
+        Self { lengths, codes }
+    }
+
+    fn write_symbol(&self, out: &mut BitWriter, symbol: usize) {
+        out.write_code(self.codes[symbol], u32::from(self.lengths[symbol]));
+    }
+
+    /// Send the code lengths, themselves with a prefix code. A run of zero
+    /// lengths is sent as repeat symbols, each multiplying the run by 8, and
+    /// the lengths stop at the last used symbol.
+    fn write(&self, out: &mut BitWriter) {
+        let used = self.lengths.iter().rposition(|len| *len != 0).unwrap() + 1;
+        let mut symbols = Vec::new();
+        let mut i = 0;
+        while i < used {
+            let run = self.lengths[i..]
+                .iter()
+                .take_while(|len| **len == 0)
+                .count();
+            if run < 3 {
+                symbols.push((usize::from(self.lengths[i]), 0));
+                i += 1;
+                continue;
+            }
+
+            let mut repeats = run - 3;
+            let start = symbols.len();
+            loop {
+                symbols.push((REPEAT_ZERO, repeats as u32 & 7));
+                repeats >>= 3;
+                if repeats == 0 {
+                    break;
+                }
+                repeats -= 1;
+            }
+            symbols[start..].reverse();
+            i += run;
+        }
+
+        let mut counts = [0; 18];
+        for (symbol, _) in &symbols {
+            counts[*symbol] += 1;
+        }
+        let code = PrefixCode::new(&counts, MAX_CODE_LEN_CODE_LEN);
+
+        out.write_bits(0, 2); // Complex code, with no lengths skipped
+        let sent = CODE_LEN_ORDER
+            .iter()
+            .rposition(|symbol| code.lengths[*symbol] != 0)
+            .unwrap();
+        for symbol in &CODE_LEN_ORDER[..=sent] {
+            // A fixed code for lengths 0 to 5
+            let (bits, count) = [(0, 2), (7, 4), (3, 3), (2, 2), (1, 2), (15, 4)]
+                [usize::from(code.lengths[*symbol])];
+            out.write_bits(bits, count);
+        }
+        for (symbol, extra) in symbols {
+            code.write_symbol(out, symbol);
+            if symbol == REPEAT_ZERO {
+                out.write_bits(extra, 3);
+            }
+        }
+    }
+}
+
+/// Huffman code lengths no longer than max_len. At least two symbols get a
+/// code, even if they're not used, since a code with one symbol is sent
+/// differently.
+fn code_lengths(counts: &[u32], max_len: u8) -> Vec<u8> {
+    let mut counts = counts.to_vec();
+    for i in 0..2 {
+        if counts.iter().filter(|c| **c > 0).count() < 2 && counts[i] == 0 {
+            counts[i] = 1;
+        }
+    }
+
+    // Raising the smallest counts flattens the tree until it's short enough
+    let mut floor = 1;
+    loop {
+        let lengths = huffman_lengths(&counts, floor);
+        if lengths.iter().all(|len| *len <= max_len) {
+            return lengths;
+        }
+        floor *= 2;
+    }
+}
+
+fn huffman_lengths(counts: &[u32], floor: u32) -> Vec<u8> {
+    use std::{cmp::Reverse, collections::BinaryHeap};
+
+    // Leaves are the symbols, then each merge adds a node
+    let mut parents = vec![usize::MAX; counts.len()];
+    let mut heap: BinaryHeap<_> = counts
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(symbol, count)| Reverse((u64::from((*count).max(floor)), symbol)))
+        .collect();
+    while heap.len() > 1 {
+        let Reverse((a, a_node)) = heap.pop().unwrap();
+        let Reverse((b, b_node)) = heap.pop().unwrap();
+        let node = parents.len();
+        parents.push(usize::MAX);
+        parents[a_node] = node;
+        parents[b_node] = node;
+        heap.push(Reverse((a + b, node)));
+    }
+
+    (0..counts.len())
+        .map(|symbol| {
+            if counts[symbol] == 0 {
+                return 0;
+            }
+            let mut len = 0;
+            let mut node = symbol;
+            while parents[node] != usize::MAX {
+                node = parents[node];
+                len += 1;
+            }
+            len
+        })
+        .collect()
+}
+
+/// Undo the gzip format, refusing to produce more than max_len bytes
+pub fn gunzip(data: &[u8], max_len: usize) -> Result<Vec<u8>, DecompressError> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    let header = data.get(0..10).ok_or(DecompressError::Invalid)?;
+    if header[0..3] != [0x1f, 0x8b, 8] {
+        return Err(DecompressError::Invalid);
+    }
+    let flags = header[3];
+
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let xlen = data.get(pos..pos + 2).ok_or(DecompressError::Invalid)?;
+        pos += 2 + usize::from(u16::from_le_bytes([xlen[0], xlen[1]]));
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let field = data.get(pos..).ok_or(DecompressError::Invalid)?;
+            pos += field
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or(DecompressError::Invalid)?
+                + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+
+    let (output, used) = inflate(data.get(pos..).ok_or(DecompressError::Invalid)?, max_len)?;
+    let trailer = data
+        .get(pos + used..pos + used + 8)
+        .ok_or(DecompressError::Invalid)?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc != crc32(&output) || size != output.len() as u32 {
+        return Err(DecompressError::Invalid);
+    }
+
+    Ok(output)
+}
+
+/// Decompress raw DEFLATE data, returning the output and the number of input
+/// bytes used. Refuses to produce more than max_len bytes.
+pub fn inflate(data: &[u8], max_len: usize) -> Result<(Vec<u8>, usize), DecompressError> {
+    let mut input = BitReader::new(data);
+    let mut output = Vec::new();
+
+    loop {
+        let last = input.read_bits(1)? == 1;
+        match input.read_bits(2)? {
+            0 => {
+                input.align();
+                let len = input.read_bits(16)?;
+                let nlen = input.read_bits(16)?;
+                if len != !nlen & 0xffff {
+                    return Err(DecompressError::Invalid);
+                }
+                for _ in 0..len {
+                    output.push(input.read_bits(8)? as u8);
+                }
+                if output.len() > max_len {
+                    return Err(DecompressError::TooLarge(max_len));
+                }
+            }
+            1 => {
+                let (lit, dist) = fixed_huffman();
+                inflate_block(&mut input, &mut output, &lit, &dist, max_len)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_huffman(&mut input)?;
+                inflate_block(&mut input, &mut output, &lit, &dist, max_len)?;
+            }
+            _ => return Err(DecompressError::Invalid),
+        }
+
+        if last {
+            break;
+        }
+    }
+
+    input.align();
+    Ok((output, input.pos))
+}
+
+fn inflate_block(
+    input: &mut BitReader,
+    output: &mut Vec<u8>,
+    lit: &Huffman,
+    dist: &Huffman,
+    max_len: usize,
+) -> Result<(), DecompressError> {
+    loop {
+        let sym = lit.decode(input)?;
+        match sym {
+            0..=255 => output.push(sym as u8),
+            256 => return Ok(()),
+            _ => {
+                let (base, extra) = *LENGTH_CODES
+                    .get(usize::from(sym - 257))
+                    .ok_or(DecompressError::Invalid)?;
+                let len = usize::from(base) + input.read_bits(u32::from(extra))? as usize;
+
+                let (base, extra) = *DISTANCE_CODES
+                    .get(usize::from(dist.decode(input)?))
+                    .ok_or(DecompressError::Invalid)?;
+                let distance = usize::from(base) + input.read_bits(u32::from(extra))? as usize;
+                if distance > output.len() {
+                    return Err(DecompressError::Invalid);
+                }
+
+                // Byte by byte since the copy may overlap what it's producing
+                let start = output.len() - distance;
+                for i in 0..len {
+                    output.push(output[start + i]);
+                }
+            }
+        }
+
+        if output.len() > max_len {
+            return Err(DecompressError::TooLarge(max_len));
+        }
+    }
+}
+
+fn fixed_huffman() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[0..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..288].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_huffman(input: &mut BitReader) -> Result<(Huffman, Huffman), DecompressError> {
+    const ORDER: [usize; 19] = [
+        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+    ];
+
+    let hlit = input.read_bits(5)? as usize + 257;
+    let hdist = input.read_bits(5)? as usize + 1;
+    let hclen = input.read_bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for i in ORDER.iter().take(hclen) {
+        code_lengths[*i] = input.read_bits(3)? as u8;
+    }
+    let code_huffman = Huffman::new(&code_lengths);
+
+    // Literal/length and distance code lengths are sent as one sequence, with
+    // run length encoding that may cross from one to the other
+    let mut lengths = Vec::with_capacity(hlit + hdist);
+    while lengths.len() < hlit + hdist {
+        let (value, repeat) = match code_huffman.decode(input)? {
+            sym @ 0..=15 => (sym as u8, 1),
+            16 => {
+                let prev = *lengths.last().ok_or(DecompressError::Invalid)?;
+                (prev, 3 + input.read_bits(2)?)
+            }
+            17 => (0, 3 + input.read_bits(3)?),
+            18 => (0, 11 + input.read_bits(7)?),
+            _ => return Err(DecompressError::Invalid),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() != hlit + hdist {
+        return Err(DecompressError::Invalid);
+    }
+
+    Ok((
+        Huffman::new(&lengths[0..hlit]),
+        Huffman::new(&lengths[hlit..]),
+    ))
+}
+
+/// Canonical Huffman code, stored as the number of codes of each length and
+/// the symbols ordered by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for len in lengths {
+            counts[usize::from(*len)] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+
+        let mut symbols = vec![0; lengths.len()];
+        for (sym, len) in lengths.iter().enumerate() {
+            if *len != 0 {
+                symbols[usize::from(offsets[usize::from(*len)])] = sym as u16;
+                offsets[usize::from(*len)] += 1;
+            }
+        }
+
+        Self { counts, symbols }
+    }
+
+    /// Read one code bit by bit, checking at each length whether the code
+    /// read so far falls within the codes of that length
+    fn decode(&self, input: &mut BitReader) -> Result<u16, DecompressError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for count in &self.counts[1..] {
+            code |= input.read_bits(1)? as i32;
+            let count = i32::from(*count);
+            if code - count < first {
+                return self
+                    .symbols
+                    .get((index + code - first) as usize)
+                    .copied()
+                    .ok_or(DecompressError::Invalid);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err(DecompressError::Invalid)
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            bit_buf: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Read `count` bits, least significant first
+    fn read_bits(&mut self, count: u32) -> Result<u32, DecompressError> {
+        while self.bit_count < count {
+            let byte = *self.data.get(self.pos).ok_or(DecompressError::Invalid)?;
+            self.bit_buf |= u32::from(byte) << self.bit_count;
+            self.pos += 1;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buf & ((1u64 << count) - 1) as u32;
+        self.bit_buf >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    /// Skip to the next byte boundary
+    fn align(&mut self) {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted(list: &[(&str, f32)]) -> Vec<(String, f32)> {
+        list.iter().map(|(n, q)| (n.to_string(), *q)).collect()
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn test_gzip_framing() {
+        let data = b"abcabcabcabcabcabcabcabcabcabcabcabcabcabcabcabc";
+        let output = gzip(data);
+        assert_eq!(output[0..3], [0x1f, 0x8b, 8]);
+        assert_eq!(
+            output[output.len() - 8..output.len() - 4],
+            crc32(data).to_le_bytes()
+        );
+        assert_eq!(
+            output[output.len() - 4..],
+            (data.len() as u32).to_le_bytes()
+        );
+        assert!(output.len() < data.len());
+    }
+
+    #[test]
+    fn test_deflate_empty() {
+        // Fixed block containing only the end of block symbol
+        assert_eq!(deflate(b""), [0x03, 0x00]);
+    }
+
+    #[test]
+    fn test_brotli() {
+        // Checked against the reference decoder
+        assert_eq!(brotli(b""), [0x06]);
+        assert_eq!(
+            brotli(b"abcabcabcabcabcabcabcabcabcabcabcabc"),
+            [
+                0x62, 0x04, 0x00, 0x00, 0xb0, 0x01, 0x1c, 0x10, 0x7f, 0x1c, 0x00, 0xa7, 0x6a, 0x70,
+                0x00, 0x9c, 0x58, 0xd7, 0x02
+            ]
+        );
+
+        let text = "the quick brown fox jumps over the lazy dog. ".repeat(100);
+        assert!(brotli(text.as_bytes()).len() < gzip(text.as_bytes()).len());
+    }
+
+    #[test]
+    fn test_code_lengths() {
+        // Complete codes, even with fewer than two symbols used
+        for counts in [&[5, 1, 1, 3][..], &[0, 0, 7], &[0, 0, 0], &[1; 300]] {
+            let lengths = code_lengths(counts, MAX_CODE_LEN);
+            let kraft: u32 = lengths
+                .iter()
+                .filter(|len| **len > 0)
+                .map(|len| 1 << (15 - len))
+                .sum();
+            assert_eq!(kraft, 1 << 15, "{counts:?}");
+        }
+        assert_eq!(code_lengths(&[5, 1, 1, 3], MAX_CODE_LEN), [1, 3, 3, 2]);
+
+        // Counts that would make a deep tree get flattened
+        let counts: Vec<u32> = (0..20).map(|i| 1 << i).collect();
+        let lengths = code_lengths(&counts, MAX_CODE_LEN_CODE_LEN);
+        assert!(lengths.iter().all(|len| (1..=5).contains(len)));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = "the quick brown fox jumps over the lazy dog. ".repeat(100);
+        let inputs: [&[u8]; 4] = [b"", b"a", b"abcabcabcabcabcabc", text.as_bytes()];
+        for input in inputs {
+            assert_eq!(gunzip(&gzip(input), usize::MAX).unwrap(), input);
+            let (output, used) = inflate(&deflate(input), usize::MAX).unwrap();
+            assert_eq!(output, input);
+            assert_eq!(used, deflate(input).len());
+        }
+    }
+
+    #[test]
+    fn test_gunzip_dynamic_block() {
+        // Output from zlib, which picks a dynamic Huffman block for this input
+        #[rustfmt::skip]
+        let data = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcd, 0xcc, 0xd1, 0x0d,
+            0x80, 0x20, 0x0c, 0x05, 0xc0, 0x55, 0xde, 0x00, 0xc6, 0x29, 0xf8, 0x70, 0x8d, 0x0a,
+            0x0f, 0x25, 0x42, 0x31, 0xb4, 0x24, 0xba, 0xbd, 0x6b, 0x78, 0x03, 0x5c, 0x78, 0x55,
+            0x5a, 0x89, 0xd8, 0x66, 0xce, 0x4d, 0x14, 0x7b, 0xed, 0xf1, 0x32, 0xc8, 0x20, 0xa6,
+            0x31, 0x21, 0xf7, 0x81, 0xda, 0xf5, 0xe0, 0x80, 0xf3, 0xf1, 0x05, 0xb5, 0x5c, 0x84,
+            0x9f, 0xc5, 0x60, 0x54, 0xa7, 0x46, 0x62, 0xf0, 0xa6, 0x38, 0xd3, 0x8a, 0xf0, 0xe3,
+            0xed, 0x03, 0xc2, 0xea, 0x65, 0x85, 0xea, 0x00, 0x00, 0x00,
+        ];
+        let expected =
+            "Dynamic Huffman blocks are used for longer text, like this sentence repeated. ";
+        assert_eq!(
+            gunzip(&data, usize::MAX).unwrap(),
+            expected.repeat(3).as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_inflate_stored_block() {
+        let data = [
+            0x01, 0x06, 0x00, 0xf9, 0xff, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x64,
+        ];
+        assert_eq!(
+            inflate(&data, usize::MAX).unwrap(),
+            (b"stored".to_vec(), data.len())
+        );
+    }
+
+    #[test]
+    fn test_gunzip_limit() {
+        let data = gzip(&[0; 10000]);
+        assert_eq!(gunzip(&data, 1000), Err(DecompressError::TooLarge(1000)));
+        assert_eq!(
+            gunzip(&data[0..data.len() - 1], 10000),
+            Err(DecompressError::Invalid)
+        );
+    }
+
+    #[test]
+    fn test_should_compress() {
+        let policy = CompressionPolicy {
+            rules: vec![
+                (String::from("text/html"), 0),
+                (String::from("application/json"), 512),
+                (String::from("image/*"), usize::MAX),
+            ],
+        };
+
+        assert!(should_compress("text/html", 10, &policy));
+        assert!(should_compress("text/html; charset=utf-8", 10, &policy));
+        assert!(!should_compress("application/json", 100, &policy));
+        assert!(should_compress("Application/JSON", 512, &policy));
+        assert!(!should_compress("image/png", 100_000, &policy));
+        assert!(should_compress("text/plain", 1, &policy));
+        assert!(should_compress("", 1, &policy));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let all = Encoding::PREFERENCE;
+        let enc = Encoding::negotiate(&accepted(&[("gzip", 1.0), ("deflate", 1.0)]), &all);
+        assert_eq!(enc, Some(Encoding::Gzip));
+
+        let enc = Encoding::negotiate(&accepted(&[("deflate", 1.0), ("gzip", 0.5)]), &all);
+        assert_eq!(enc, Some(Encoding::Deflate));
+
+        let enc = Encoding::negotiate(&accepted(&[("gzip", 0.0), ("*", 0.5)]), &all);
+        assert_eq!(enc, Some(Encoding::Brotli));
+
+        let enc = Encoding::negotiate(&accepted(&[("identity", 1.0)]), &all);
+        assert_eq!(enc, None);
+    }
+
+    #[test]
+    fn test_negotiate_brotli() {
+        let list = accepted(&[("gzip", 1.0), ("deflate", 1.0), ("br", 1.0)]);
+        assert_eq!(
+            Encoding::negotiate(&list, &Encoding::PREFERENCE),
+            Some(Encoding::Brotli)
+        );
+
+        let list = accepted(&[("br", 1.0), ("gzip", 0.8), ("deflate", 0.5)]);
+        assert_eq!(
+            Encoding::negotiate(&list, &Encoding::PREFERENCE),
+            Some(Encoding::Brotli)
+        );
+
+        // Falling back when Brotli isn't offered
+        let offered = [Encoding::Gzip, Encoding::Deflate];
+        assert_eq!(Encoding::negotiate(&list, &offered), Some(Encoding::Gzip));
+        let list = accepted(&[("br", 1.0)]);
+        assert_eq!(Encoding::negotiate(&list, &offered), None);
+    }
+}
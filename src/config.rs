@@ -0,0 +1,539 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::compress::{CompressionPolicy, Encoding};
+
+/// How much the server logs, from least to most
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Error,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    /// The next more verbose level, staying at the most verbose one
+    fn raise(self) -> Self {
+        match self {
+            Self::Error => Self::Info,
+            Self::Info => Self::Debug,
+            Self::Debug | Self::Trace => Self::Trace,
+        }
+    }
+}
+
+/// Where log lines go. That's stdout unless the logger was made by capture,
+/// which keeps them so that tests can check what was logged.
+#[derive(Clone, Debug, Default)]
+pub struct Logger {
+    captured: Option<Arc<Mutex<Vec<String>>>>,
+}
+
+impl Logger {
+    pub fn capture() -> Self {
+        Self {
+            captured: Some(Arc::default()),
+        }
+    }
+
+    pub fn write(&self, line: fmt::Arguments) {
+        match &self.captured {
+            Some(lines) => lines.lock().unwrap().push(line.to_string()),
+            None => println!("{line}"),
+        }
+    }
+
+    /// The lines kept so far, which is none unless capturing
+    pub fn lines(&self) -> Vec<String> {
+        self.captured
+            .as_ref()
+            .map_or_else(Vec::new, |lines| lines.lock().unwrap().clone())
+    }
+}
+
+/// Server settings parsed from the command line
+#[derive(Debug)]
+pub struct Config {
+    pub log_level: LogLevel,
+    pub logger: Logger,
+    pub directory: Option<PathBuf>,
+    /// A single file to serve at the root
+    pub file: Option<PathBuf>,
+    /// A home page to serve at the root, with its content type going by its
+    /// extension
+    pub index_file: Option<PathBuf>,
+    /// Directories served for particular hosts instead of `directory`
+    pub vhosts: Vec<(String, PathBuf)>,
+    /// Subdirectories of the served directory holding variants of files for
+    /// clients whose User-Agent contains a pattern, checked in order
+    pub user_agent_dirs: Vec<(String, String)>,
+    /// Redirect requests for a directory to the same path with a trailing
+    /// slash, so relative links in its index file resolve inside it
+    pub trailing_slash_redirect: bool,
+    /// Filenames tried in order when a directory is requested
+    pub index_files: Vec<String>,
+    pub strong_etag: bool,
+    pub read_only: bool,
+    pub download: bool,
+    /// Enables diagnostic options which should never be used in production
+    pub debug_routes: bool,
+    /// Time to wait before sending each response. Only used with debug_routes.
+    pub response_delay: Option<Duration>,
+    /// Requests served on one connection before it is closed
+    pub max_requests: usize,
+    /// How long an idle connection is kept open waiting for another request
+    pub keep_alive_timeout: Duration,
+    /// Longest time allowed to read, handle and respond to one request
+    pub request_timeout: Duration,
+    /// Longest time allowed to write a response, so that a client which stops
+    /// reading can't hold on to the connection
+    pub write_timeout: Duration,
+    /// Slowest rate in bytes per second that a request body may arrive at,
+    /// once it has been arriving for body_rate_window
+    pub min_body_rate: Option<u64>,
+    /// How long a request body may take before its rate is checked
+    pub body_rate_window: Duration,
+    /// Largest request body accepted after undoing its Content-Encoding
+    pub max_decompressed_size: usize,
+    /// Largest request head, which is the request line and headers
+    pub max_head_size: usize,
+    /// Largest request body read, however it's delimited
+    pub max_body_size: usize,
+    /// HTML template for error response bodies
+    pub error_template: Option<PathBuf>,
+    /// Seconds that clients may cache file responses for
+    pub cache_max_age: Option<u64>,
+    /// Cache-Control value for file responses, replacing the max-age one
+    pub cache_control: Option<String>,
+    /// Largest total size of the files in a served directory that uploads may
+    /// grow it to
+    pub max_dir_size: Option<u64>,
+    /// File reads and writes allowed to run at the same time
+    pub max_file_ops: usize,
+    /// Port to listen on for plain HTTP requests, which are all redirected to
+    /// HTTPS. Only allowed when TLS is served.
+    pub redirect_http_port: Option<u16>,
+    /// Origins allowed to read responses cross-origin
+    pub cors_origins: Vec<String>,
+    /// Smallest response bodies to compress, by content type
+    pub compression_policy: CompressionPolicy,
+    /// Offer Brotli for response bodies, which compresses better than gzip but
+    /// takes longer
+    pub brotli: bool,
+    /// Response status codes after which the connection is always closed
+    pub close_on_status: Vec<u32>,
+    /// Send a SHA-256 Digest header with response bodies
+    pub digest: bool,
+    /// Add headers asking browsers to apply extra protections to responses
+    pub security_headers: bool,
+    /// File to append a Combined Log Format line to for every request
+    pub access_log: Option<PathBuf>,
+    /// Stream server-sent events to clients at /events
+    pub events: bool,
+    /// Leave the reason phrase out of status lines, sending only the code
+    pub no_reason_phrase: bool,
+    /// A file holding a raw request to parse and describe, instead of running
+    /// the server
+    pub parse_file: Option<PathBuf>,
+    /// Accept bare LF line endings in requests as well as CRLF
+    pub lenient_line_endings: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: LogLevel::default(),
+            logger: Logger::default(),
+            directory: None,
+            file: None,
+            index_file: None,
+            vhosts: Vec::new(),
+            user_agent_dirs: Vec::new(),
+            trailing_slash_redirect: false,
+            index_files: vec![String::from("index.html")],
+            strong_etag: false,
+            read_only: false,
+            download: false,
+            debug_routes: false,
+            response_delay: None,
+            max_requests: 100,
+            keep_alive_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            min_body_rate: None,
+            body_rate_window: Duration::from_secs(5),
+            max_decompressed_size: 10 * 1024 * 1024,
+            max_head_size: 8 * 1024,
+            max_body_size: 10 * 1024 * 1024,
+            error_template: None,
+            cache_max_age: None,
+            cache_control: None,
+            max_dir_size: None,
+            max_file_ops: 64,
+            redirect_http_port: None,
+            cors_origins: Vec::new(),
+            compression_policy: CompressionPolicy::default(),
+            brotli: true,
+            close_on_status: vec![400, 408, 413, 431, 500],
+            digest: false,
+            security_headers: false,
+            access_log: None,
+            events: false,
+            no_reason_phrase: false,
+            lenient_line_endings: false,
+            parse_file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Whether messages at the level are logged
+    pub fn logs(&self, level: LogLevel) -> bool {
+        self.log_level >= level
+    }
+
+    /// Write a line to the logger if messages at the level are logged
+    pub fn log(&self, level: LogLevel, line: fmt::Arguments) {
+        if self.logs(level) {
+            self.logger.write(line);
+        }
+    }
+
+    /// Content-codings offered for response bodies, most preferred first
+    pub fn encodings(&self) -> Vec<Encoding> {
+        Encoding::PREFERENCE
+            .into_iter()
+            .filter(|enc| self.brotli || *enc != Encoding::Brotli)
+            .collect()
+    }
+
+    /// The directory to serve files from for a request to the given host
+    pub fn directory_for(&self, host: Option<&str>) -> Option<&Path> {
+        host.and_then(|host| {
+            self.vhosts
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(host))
+        })
+        .map(|(_, dir)| dir.as_path())
+        .or(self.directory.as_deref())
+    }
+
+    /// The subdirectory holding variants of files for a client with the given
+    /// User-Agent, matched case-insensitively
+    pub fn user_agent_dir(&self, user_agent: Option<&str>) -> Option<&str> {
+        let user_agent = user_agent?.to_ascii_lowercase();
+        self.user_agent_dirs
+            .iter()
+            .find(|(pattern, _)| user_agent.contains(&pattern.to_ascii_lowercase()))
+            .map(|(_, dir)| dir.as_str())
+    }
+
+    /// Parse from an argument list, which includes the program name. Unknown
+    /// arguments are ignored.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Self {
+        let mut config = Self::default();
+        let mut index_files = Vec::new();
+
+        let mut args = args.into_iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--quiet" => config.log_level = LogLevel::Error,
+                "-v" | "--verbose" => config.log_level = config.log_level.raise(),
+                "--directory" => config.directory = args.next().map(PathBuf::from),
+                "--parse-file" => config.parse_file = args.next().map(PathBuf::from),
+                "--index-file" => config.index_file = args.next().map(PathBuf::from),
+                "--file" => config.file = args.next().map(PathBuf::from),
+                "--vhost" => {
+                    if let Some((host, dir)) = args.next().as_ref().and_then(|v| v.split_once('='))
+                    {
+                        config.vhosts.push((host.to_owned(), PathBuf::from(dir)));
+                    }
+                }
+                "--user-agent-dir" => {
+                    if let Some((pattern, dir)) =
+                        args.next().as_ref().and_then(|v| v.split_once('='))
+                    {
+                        config
+                            .user_agent_dirs
+                            .push((pattern.to_owned(), dir.to_owned()));
+                    }
+                }
+                "--trailing-slash-redirect" => config.trailing_slash_redirect = true,
+                "--index" => index_files.extend(args.next()),
+                "--strong-etag" => config.strong_etag = true,
+                "--read-only" => config.read_only = true,
+                "--download" => config.download = true,
+                "--debug-routes" => config.debug_routes = true,
+                "--digest" => config.digest = true,
+                "--no-brotli" => config.brotli = false,
+                "--security-headers" => config.security_headers = true,
+                "--no-reason-phrase" => config.no_reason_phrase = true,
+                "--events" => config.events = true,
+                "--access-log" => config.access_log = args.next().map(PathBuf::from),
+                "--lenient-line-endings" => config.lenient_line_endings = true,
+                "--response-delay" => {
+                    config.response_delay = args
+                        .next()
+                        .and_then(|s| s.parse().ok())
+                        .map(Duration::from_millis)
+                }
+                "--error-template" => config.error_template = args.next().map(PathBuf::from),
+                "--max-requests" => {
+                    if let Some(n) = args.next().and_then(|s| s.parse().ok()) {
+                        config.max_requests = n;
+                    }
+                }
+                "--request-timeout" => {
+                    if let Some(secs) = args.next().and_then(|s| s.parse().ok()) {
+                        config.request_timeout = Duration::from_secs(secs);
+                    }
+                }
+                "--write-timeout" => {
+                    if let Some(secs) = args.next().and_then(|s| s.parse().ok()) {
+                        config.write_timeout = Duration::from_secs(secs);
+                    }
+                }
+                "--max-file-ops" => {
+                    if let Some(n) = args.next().and_then(|s| s.parse().ok()) {
+                        config.max_file_ops = n;
+                    }
+                }
+                "--min-body-rate" => {
+                    config.min_body_rate = args.next().and_then(|s| s.parse().ok())
+                }
+                "--max-decompressed-size" => {
+                    if let Some(n) = args.next().and_then(|s| s.parse().ok()) {
+                        config.max_decompressed_size = n;
+                    }
+                }
+                "--max-head-size" => {
+                    if let Some(n) = args.next().and_then(|s| s.parse().ok()) {
+                        config.max_head_size = n;
+                    }
+                }
+                "--max-body-size" => {
+                    if let Some(n) = args.next().and_then(|s| s.parse().ok()) {
+                        config.max_body_size = n;
+                    }
+                }
+                "--max-dir-size" => config.max_dir_size = args.next().and_then(|s| s.parse().ok()),
+                "--cache-max-age" => {
+                    config.cache_max_age = args.next().and_then(|s| s.parse().ok())
+                }
+                "--redirect-http-port" => {
+                    config.redirect_http_port = args.next().and_then(|s| s.parse().ok())
+                }
+                "--cors-origin" => config.cors_origins.extend(args.next()),
+                "--compress-min" => {
+                    if let Some((content_type, min_len)) =
+                        args.next().as_ref().and_then(|v| v.split_once('='))
+                    {
+                        if let Ok(min_len) = min_len.parse() {
+                            config
+                                .compression_policy
+                                .rules
+                                .push((content_type.to_owned(), min_len));
+                        }
+                    }
+                }
+                "--cache-control" => config.cache_control = args.next(),
+                "--close-on-status" => {
+                    if let Some(list) = args.next() {
+                        config.close_on_status = list
+                            .split(',')
+                            .filter_map(|s| s.trim().parse().ok())
+                            .collect();
+                    }
+                }
+                "--keep-alive-timeout" => {
+                    if let Some(secs) = args.next().and_then(|s| s.parse().ok()) {
+                        config.keep_alive_timeout = Duration::from_secs(secs);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // Any given index files replace the default
+        if !index_files.is_empty() {
+            config.index_files = index_files;
+        }
+
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(ToOwned::to_owned).collect()
+    }
+
+    #[test]
+    fn test_from_args() {
+        let config = Config::from_args(args("server"));
+        assert_eq!(config.directory, None);
+        assert!(!config.strong_etag);
+        assert!(!config.read_only);
+        assert!(!config.lenient_line_endings);
+
+        let config = Config::from_args(args(
+            "server --directory /tmp/abc --strong-etag --read-only",
+        ));
+        assert_eq!(config.directory, Some(PathBuf::from("/tmp/abc")));
+        assert!(config.strong_etag);
+        assert!(config.read_only);
+        assert_eq!(config.file, None);
+        assert_eq!(config.index_files, ["index.html"]);
+
+        let config = Config::from_args(args(
+            "server --lenient-line-endings --security-headers --digest --no-reason-phrase --events",
+        ));
+        assert!(config.events);
+        assert!(config.no_reason_phrase);
+        assert!(config.lenient_line_endings);
+        assert!(config.security_headers);
+        assert!(config.digest);
+        assert_eq!(config.encodings(), Encoding::PREFERENCE);
+
+        let config = Config::from_args(args("server --no-brotli"));
+        assert_eq!(config.encodings(), [Encoding::Gzip, Encoding::Deflate]);
+
+        let config = Config::from_args(args("server --file /tmp/a.txt"));
+        assert_eq!(config.file, Some(PathBuf::from("/tmp/a.txt")));
+        assert_eq!(config.parse_file, None);
+
+        let config = Config::from_args(args("server --access-log /var/log/access.log"));
+        assert_eq!(
+            config.access_log,
+            Some(PathBuf::from("/var/log/access.log"))
+        );
+
+        let config = Config::from_args(args("server --index-file /srv/home.html"));
+        assert_eq!(config.index_file, Some(PathBuf::from("/srv/home.html")));
+
+        let config = Config::from_args(args("server --parse-file /tmp/request.txt"));
+        assert_eq!(config.parse_file, Some(PathBuf::from("/tmp/request.txt")));
+
+        let config = Config::from_args(args("server --index index.htm --index default.html"));
+        assert_eq!(config.index_files, ["index.htm", "default.html"]);
+        assert!(!config.trailing_slash_redirect);
+
+        let config = Config::from_args(args("server --trailing-slash-redirect"));
+        assert!(config.trailing_slash_redirect);
+
+        let config = Config::from_args(args(
+            "server --compress-min application/json=512 --compress-min image/*=bad",
+        ));
+        assert_eq!(
+            config.compression_policy.rules,
+            [(String::from("application/json"), 512)]
+        );
+
+        let config = Config::from_args(args(
+            "server --cors-origin https://a.example --cors-origin https://b.example",
+        ));
+        assert_eq!(
+            config.cors_origins,
+            ["https://a.example", "https://b.example"]
+        );
+    }
+
+    #[test]
+    fn test_directory_for() {
+        let config = Config::from_args(args(
+            "server --directory /srv/default --vhost files.example.com=/srv/files --vhost static.example.com=/srv/static",
+        ));
+        assert_eq!(
+            config.directory_for(Some("files.example.com")),
+            Some(Path::new("/srv/files"))
+        );
+        assert_eq!(
+            config.directory_for(Some("Static.Example.com")),
+            Some(Path::new("/srv/static"))
+        );
+        assert_eq!(
+            config.directory_for(Some("other.example.com")),
+            Some(Path::new("/srv/default"))
+        );
+        assert_eq!(config.directory_for(None), Some(Path::new("/srv/default")));
+    }
+
+    #[test]
+    fn test_user_agent_dir() {
+        let config = Config::from_args(args(
+            "server --user-agent-dir iPhone=mobile --user-agent-dir Android=mobile --user-agent-dir bot=plain",
+        ));
+        assert_eq!(
+            config.user_agent_dir(Some("Mozilla/5.0 (iPhone; CPU iPhone OS 17_0)")),
+            Some("mobile")
+        );
+        assert_eq!(config.user_agent_dir(Some("Googlebot/2.1")), Some("plain"));
+        assert_eq!(
+            config.user_agent_dir(Some("Mozilla/5.0 (X11; Linux)")),
+            None
+        );
+        assert_eq!(config.user_agent_dir(None), None);
+    }
+
+    #[test]
+    fn test_from_args_log_level() {
+        let config = Config::from_args(args("server"));
+        assert_eq!(config.log_level, LogLevel::Info);
+
+        let config = Config::from_args(args("server --quiet"));
+        assert_eq!(config.log_level, LogLevel::Error);
+        assert!(config.logs(LogLevel::Error));
+        assert!(!config.logs(LogLevel::Info));
+
+        let config = Config::from_args(args("server --verbose"));
+        assert_eq!(config.log_level, LogLevel::Debug);
+
+        let config = Config::from_args(args("server -v -v -v"));
+        assert_eq!(config.log_level, LogLevel::Trace);
+    }
+
+    #[test]
+    fn test_from_args_numbers() {
+        let config = Config::from_args(args(
+            "server --max-requests 3 --keep-alive-timeout 30 --request-timeout 60 --write-timeout 10",
+        ));
+        assert_eq!(config.write_timeout, Duration::from_secs(10));
+        assert_eq!(config.max_requests, 3);
+        assert_eq!(config.keep_alive_timeout, Duration::from_secs(30));
+        assert_eq!(config.request_timeout, Duration::from_secs(60));
+
+        let config = Config::from_args(args("server --close-on-status 400,503 --max-file-ops 8"));
+        assert_eq!(config.close_on_status, [400, 503]);
+        assert_eq!(config.max_file_ops, 8);
+
+        let config = Config::from_args(args("server --debug-routes --response-delay 250"));
+        assert!(config.debug_routes);
+        assert_eq!(config.response_delay, Some(Duration::from_millis(250)));
+
+        let config = Config::from_args(args(
+            "server --max-head-size 512 --max-body-size 1024 --max-dir-size 4096",
+        ));
+        assert_eq!(config.max_head_size, 512);
+        assert_eq!(config.max_body_size, 1024);
+        assert_eq!(config.max_dir_size, Some(4096));
+
+        let config = Config::from_args(args("server --min-body-rate 512"));
+        assert_eq!(config.min_body_rate, Some(512));
+
+        let config = Config::from_args(args("server --redirect-http-port 8080"));
+        assert_eq!(config.redirect_http_port, Some(8080));
+
+        // Invalid values leave the default in place
+        let config = Config::from_args(args("server --max-requests lots"));
+        assert_eq!(config.max_requests, 100);
+    }
+}
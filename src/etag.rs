@@ -0,0 +1,199 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    http::{Request, Response, Status},
+    sha1::{sha1, to_hex},
+};
+
+/// An entity tag, as sent in ETag, If-Match and If-None-Match
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ETag {
+    pub weak: bool,
+    /// The tag without its quotes
+    pub tag: String,
+}
+
+/// How two entity tags are compared, per RFC 9110 section 8.8.3.2
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Comparison {
+    /// Both must be strong and have the same tag. Used for If-Match.
+    Strong,
+    /// The tags must be the same, ignoring weakness. Used for If-None-Match.
+    Weak,
+}
+
+impl ETag {
+    /// Parse a single entity tag such as `"abc"` or `W/"abc"`
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let (weak, quoted) = match s.strip_prefix("W/") {
+            Some(quoted) => (true, quoted),
+            None => (false, s),
+        };
+        let tag = quoted.strip_prefix('"')?.strip_suffix('"')?;
+        if tag.contains('"') {
+            return None;
+        }
+        Some(Self {
+            weak,
+            tag: tag.to_owned(),
+        })
+    }
+
+    pub fn matches(&self, other: &Self, comparison: Comparison) -> bool {
+        match comparison {
+            Comparison::Strong => !self.weak && !other.weak && self.tag == other.tag,
+            Comparison::Weak => self.tag == other.tag,
+        }
+    }
+
+    /// Whether the value of an If-Match or If-None-Match header matches this
+    /// tag. `*` matches any tag, and invalid list entries are ignored.
+    pub fn matches_list(&self, list: &str, comparison: Comparison) -> bool {
+        list.trim() == "*"
+            || list
+                .split(',')
+                .filter_map(Self::parse)
+                .any(|other| self.matches(&other, comparison))
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            write!(f, "W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+/// Weak validator derived from the file's size and modification time
+pub fn weak_etag(meta: &Metadata) -> String {
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("W/\"{:x}-{:x}\"", meta.len(), mtime.as_secs())
+}
+
+/// Strong validator derived from the file's contents
+pub fn strong_etag(data: &[u8]) -> String {
+    format!("\"{}\"", to_hex(&sha1(data)))
+}
+
+/// A ready 304 Not Modified if the request's If-None-Match matches the ETag
+/// of the resource, so a handler can return it instead of the resource
+pub fn check_not_modified(req: &Request, etag: &str) -> Option<Response> {
+    let if_none_match = req.header("if-none-match")?;
+    let parsed = ETag::parse(etag)?;
+    parsed
+        .matches_list(if_none_match, Comparison::Weak)
+        .then(|| Response::new(Status::NotModified).with_etag(etag))
+}
+
+/// Remembers content hashes so unchanged files aren't hashed on every request
+#[derive(Debug, Default)]
+pub struct EtagCache {
+    entries: Mutex<HashMap<PathBuf, (SystemTime, u64, String)>>,
+}
+
+impl EtagCache {
+    /// Get the strong ETag for a file, only hashing the data if the file has
+    /// changed since it was last seen
+    pub fn get_or_compute(&self, path: &Path, meta: &Metadata, data: &[u8]) -> String {
+        let mtime = meta.modified().unwrap_or(UNIX_EPOCH);
+        let len = meta.len();
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((cached_mtime, cached_len, etag)) = entries.get(path) {
+            if *cached_mtime == mtime && *cached_len == len {
+                return etag.clone();
+            }
+        }
+
+        let etag = strong_etag(data);
+        entries.insert(path.to_owned(), (mtime, len, etag.clone()));
+        etag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_etag_parse() {
+        let etag = ETag::parse("W/\"abc\"").unwrap();
+        assert!(etag.weak);
+        assert_eq!(etag.tag, "abc");
+        assert_eq!(etag.to_string(), "W/\"abc\"");
+
+        assert_eq!(ETag::parse(" \"abc\" ").unwrap().to_string(), "\"abc\"");
+        assert_eq!(ETag::parse("abc"), None);
+        assert_eq!(ETag::parse("\"a\"b\""), None);
+    }
+
+    #[test]
+    fn test_etag_comparison() {
+        let weak = ETag::parse("W/\"abc\"").unwrap();
+        let strong = ETag::parse("\"abc\"").unwrap();
+
+        assert!(strong.matches_list("W/\"abc\"", Comparison::Weak));
+        assert!(!strong.matches_list("W/\"abc\"", Comparison::Strong));
+        assert!(!weak.matches_list("\"abc\"", Comparison::Strong));
+        assert!(strong.matches_list("\"xyz\", \"abc\"", Comparison::Strong));
+        assert!(weak.matches_list("*", Comparison::Strong));
+        assert!(!strong.matches_list("\"xyz\"", Comparison::Weak));
+    }
+
+    #[test]
+    fn test_strong_etag() {
+        let dir = std::env::temp_dir().join(format!("etag-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("a.txt");
+        let b = dir.join("b.txt");
+        fs::write(&a, b"same contents").unwrap();
+        fs::write(&b, b"same contents").unwrap();
+
+        let cache = EtagCache::default();
+        let etag = |path: &Path| {
+            let data = fs::read(path).unwrap();
+            cache.get_or_compute(path, &fs::metadata(path).unwrap(), &data)
+        };
+
+        let etag_a = etag(&a);
+        assert_eq!(etag_a, etag(&b));
+        assert!(!etag_a.starts_with("W/"));
+
+        fs::write(&a, b"different contents").unwrap();
+        assert_ne!(etag_a, etag(&a));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_not_modified() {
+        let request = |if_none_match: &str| {
+            let input = format!("GET /a HTTP/1.1\r\nIf-None-Match: {if_none_match}\r\n\r\n");
+            Request::parser(input.as_bytes()).unwrap().1
+        };
+
+        let resp = check_not_modified(&request("\"x\", W/\"abc\""), "\"abc\"").unwrap();
+        assert_eq!(resp.status_line.status, Status::NotModified);
+        assert_eq!(resp.header("etag"), Some("\"abc\""));
+
+        assert!(check_not_modified(&request("\"x\""), "\"abc\"").is_none());
+        let (_, req) = Request::parser(b"GET /a HTTP/1.1\r\n\r\n").unwrap();
+        assert!(check_not_modified(&req, "\"abc\"").is_none());
+    }
+}
@@ -0,0 +1,713 @@
+//! HPACK, the header compression HTTP/2 uses, as RFC 7541 describes it
+
+use std::{collections::VecDeque, sync::OnceLock};
+
+/// Fields every header block can refer to by index, starting from 1
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// The Huffman code of each byte value, then of end-of-string, as `(code,
+/// length in bits)`. The code is canonical, so it can be decoded knowing just
+/// the lengths and the order of the symbols.
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+/// The symbol for end-of-string, which mustn't appear in a string
+const EOS: usize = 256;
+
+/// Size of the dynamic table the decoder starts with, and the most the
+/// encoder may set it to, which is the SETTINGS_HEADER_TABLE_SIZE default
+pub const TABLE_SIZE: usize = 4096;
+
+/// A header block that can't be decoded. That leaves the decoder out of step
+/// with the encoder, so the connection can't go on.
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+#[error("invalid header block")]
+pub struct InvalidHeaderBlock;
+
+/// Decodes the header blocks of one connection, which share a dynamic table
+#[derive(Debug)]
+pub struct Decoder {
+    /// Most recently added first
+    table: VecDeque<(String, String)>,
+    /// Size of the table's entries as RFC 7541 section 4.1 counts it
+    size: usize,
+    max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: TABLE_SIZE,
+        }
+    }
+}
+
+impl Decoder {
+    /// Decode a whole header block into its fields, in order
+    pub fn decode(
+        &mut self,
+        mut block: &[u8],
+    ) -> Result<Vec<(String, String)>, InvalidHeaderBlock> {
+        let mut fields = Vec::new();
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                let index = integer(&mut block, 7)?;
+                let (name, value) = self.entry(index)?;
+                fields.push((name.to_owned(), value.to_owned()));
+            } else if first & 0x40 != 0 {
+                let field = self.literal(&mut block, 6)?;
+                self.insert(field.clone());
+                fields.push(field);
+            } else if first & 0x20 != 0 {
+                let max_size = integer(&mut block, 5)?;
+                if max_size > TABLE_SIZE {
+                    return Err(InvalidHeaderBlock);
+                }
+                self.max_size = max_size;
+                self.evict(0);
+            } else {
+                // Without indexing, or never indexed, which is the same to
+                // a decoder
+                fields.push(self.literal(&mut block, 4)?);
+            }
+        }
+        Ok(fields)
+    }
+
+    /// The field at index into the static table followed by the dynamic one
+    fn entry(&self, index: usize) -> Result<(&str, &str), InvalidHeaderBlock> {
+        if index == 0 {
+            return Err(InvalidHeaderBlock);
+        }
+        if let Some(&field) = STATIC_TABLE.get(index - 1) {
+            return Ok(field);
+        }
+        let (name, value) = self
+            .table
+            .get(index - 1 - STATIC_TABLE.len())
+            .ok_or(InvalidHeaderBlock)?;
+        Ok((name, value))
+    }
+
+    /// A literal field, whose name is indexed unless the index is 0
+    fn literal(
+        &self,
+        block: &mut &[u8],
+        prefix: u8,
+    ) -> Result<(String, String), InvalidHeaderBlock> {
+        let name = match integer(block, prefix)? {
+            0 => string(block)?,
+            index => self.entry(index)?.0.to_owned(),
+        };
+        Ok((name, string(block)?))
+    }
+
+    fn insert(&mut self, field: (String, String)) {
+        let size = entry_size(&field);
+        self.evict(size);
+        // An entry too big for the table just empties it
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    /// Drop the oldest entries until there's room for one of size
+    fn evict(&mut self, size: usize) {
+        while self.size + size > self.max_size {
+            let Some(field) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= entry_size(&field);
+        }
+    }
+}
+
+fn entry_size((name, value): &(String, String)) -> usize {
+    name.len() + value.len() + 32
+}
+
+/// Encode fields as a header block. Nothing is added to the dynamic table, so
+/// there's no state to keep, but fields in the static table are referred to
+/// by index.
+pub fn encode<'a>(fields: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        if let Some(index) = STATIC_TABLE.iter().position(|&f| f == (name, value)) {
+            encode_integer(&mut block, 0x80, 7, index + 1);
+            continue;
+        }
+        // Literal without indexing
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(index) => encode_integer(&mut block, 0, 4, index + 1),
+            None => {
+                block.push(0);
+                encode_string(&mut block, name.as_bytes());
+            }
+        }
+        encode_string(&mut block, value.as_bytes());
+    }
+    block
+}
+
+/// Read an integer whose first byte holds it in the low prefix bits, as RFC
+/// 7541 section 5.1 describes
+fn integer(block: &mut &[u8], prefix: u8) -> Result<usize, InvalidHeaderBlock> {
+    let (&first, rest) = block.split_first().ok_or(InvalidHeaderBlock)?;
+    *block = rest;
+    let max = (1 << prefix) - 1;
+    let mut value = usize::from(first) & max;
+    if value < max {
+        return Ok(value);
+    }
+    for shift in (0..28).step_by(7) {
+        let (&byte, rest) = block.split_first().ok_or(InvalidHeaderBlock)?;
+        *block = rest;
+        value += usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    // Longer than any length or index could sensibly be
+    Err(InvalidHeaderBlock)
+}
+
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix: u8, value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    let mut value = value - max;
+    while value >= 0x80 {
+        block.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+/// Read a string literal, which may be Huffman coded
+fn string(block: &mut &[u8]) -> Result<String, InvalidHeaderBlock> {
+    let huffman = block.first().is_some_and(|b| b & 0x80 != 0);
+    let len = integer(block, 7)?;
+    if len > block.len() {
+        return Err(InvalidHeaderBlock);
+    }
+    let (data, rest) = block.split_at(len);
+    *block = rest;
+    let data = if huffman {
+        huffman_decode(data)?
+    } else {
+        data.to_vec()
+    };
+    String::from_utf8(data).map_err(|_| InvalidHeaderBlock)
+}
+
+/// Write a string literal as it is. Huffman coding would make most strings
+/// shorter, but responses are small next to their bodies.
+fn encode_string(block: &mut Vec<u8>, data: &[u8]) {
+    encode_integer(block, 0, 7, data.len());
+    block.extend_from_slice(data);
+}
+
+/// For each code length, the first code of that length, how many codes
+/// there are of it, and where their symbols start in the list of symbols
+/// ordered by code
+struct HuffmanTable {
+    lengths: [(u32, u32, usize); 31],
+    symbols: Vec<usize>,
+}
+
+fn huffman_table() -> &'static HuffmanTable {
+    static TABLE: OnceLock<HuffmanTable> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut symbols: Vec<usize> = (0..HUFFMAN_CODES.len()).collect();
+        symbols.sort_by_key(|&symbol| (HUFFMAN_CODES[symbol].1, symbol));
+        let mut lengths = [(0, 0, 0); 31];
+        for (i, &symbol) in symbols.iter().enumerate() {
+            let (code, len) = HUFFMAN_CODES[symbol];
+            let entry = &mut lengths[usize::from(len)];
+            if entry.1 == 0 {
+                *entry = (code, 0, i);
+            }
+            entry.1 += 1;
+        }
+        HuffmanTable { lengths, symbols }
+    })
+}
+
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, InvalidHeaderBlock> {
+    let table = huffman_table();
+    let mut decoded = Vec::with_capacity(data.len() * 8 / 5);
+    let mut code = 0u32;
+    let mut len = 0;
+    for byte in data {
+        for bit in (0..8).rev() {
+            code = code << 1 | u32::from(byte >> bit & 1);
+            len += 1;
+            let (first, count, start) = *table.lengths.get(len).ok_or(InvalidHeaderBlock)?;
+            if code >= first && code - first < count {
+                let symbol = table.symbols[start + (code - first) as usize];
+                if symbol == EOS {
+                    return Err(InvalidHeaderBlock);
+                }
+                decoded.push(symbol as u8);
+                code = 0;
+                len = 0;
+            }
+        }
+    }
+    // The last byte is padded with the start of end-of-string, which is all
+    // ones, but never a whole byte of it
+    if len > 7 || code != (1 << len) - 1 {
+        return Err(InvalidHeaderBlock);
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_integer() {
+        // RFC 7541 appendix C.1
+        let mut block = &[0x1f, 0x9a, 0x0a][..];
+        assert_eq!(integer(&mut block, 5), Ok(1337));
+        assert!(block.is_empty());
+
+        let mut encoded = Vec::new();
+        encode_integer(&mut encoded, 0, 5, 1337);
+        assert_eq!(encoded, [0x1f, 0x9a, 0x0a]);
+        encoded.clear();
+        encode_integer(&mut encoded, 0x80, 7, 10);
+        assert_eq!(encoded, [0x8a]);
+
+        let mut block = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff][..];
+        assert_eq!(integer(&mut block, 7), Err(InvalidHeaderBlock));
+    }
+
+    #[test]
+    fn test_decode_requests() {
+        // RFC 7541 appendix C.4, three requests on one connection
+        let mut decoder = Decoder::default();
+        let block = b"\x82\x86\x84\x41\x8c\xf1\xe3\xc2\xe5\xf2\x3a\x6b\xa0\xab\x90\xf4\xff";
+        assert_eq!(
+            decoder.decode(block).unwrap(),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+        assert_eq!(decoder.size, 57);
+
+        let block = b"\x82\x86\x84\xbe\x58\x86\xa8\xeb\x10\x64\x9c\xbf";
+        assert_eq!(
+            decoder.decode(block).unwrap(),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+        assert_eq!(decoder.size, 110);
+
+        let block = b"\x82\x87\x85\xbf\x40\x88\x25\xa8\x49\xe9\x5b\xa9\x7d\x7f\
+            \x89\x25\xa8\x49\xe9\x5b\xb8\xe8\xb4\xbf";
+        assert_eq!(
+            decoder.decode(block).unwrap(),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.size, 164);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut decoder = Decoder::default();
+        // Shrink the table to fit one entry, then add two
+        let mut block = vec![0x3f, 0x21];
+        for value in ["a", "b"] {
+            block.extend_from_slice(&[0x40, 0x01, b'x', 0x01]);
+            block.extend_from_slice(value.as_bytes());
+        }
+        block.push(0xbe);
+        let decoded = decoder.decode(&block).unwrap();
+        assert_eq!(decoded[2], (String::from("x"), String::from("b")));
+        assert_eq!(decoder.table.len(), 1);
+        assert_eq!(decoder.decode(&[0xbf]), Err(InvalidHeaderBlock));
+
+        // Bigger than allowed
+        assert_eq!(decoder.decode(&[0x3f, 0xe2, 0x1f]), Err(InvalidHeaderBlock));
+    }
+
+    #[test]
+    fn test_huffman() {
+        assert_eq!(
+            huffman_decode(b"\xf1\xe3\xc2\xe5\xf2\x3a\x6b\xa0\xab\x90\xf4\xff").unwrap(),
+            b"www.example.com"
+        );
+        // Every symbol, each padded out to a byte boundary
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate().take(EOS) {
+            let padding = (8 - len % 8) % 8;
+            let bits = u64::from(code) << padding | ((1 << padding) - 1);
+            let bytes = bits.to_be_bytes();
+            let encoded = &bytes[8 - usize::from((len + padding) / 8)..];
+            assert_eq!(huffman_decode(encoded).unwrap(), [symbol as u8]);
+        }
+
+        // Padding that isn't all ones, or is a whole byte
+        assert!(huffman_decode(b"\xf1\xe3\xc2\xe5\xf2\x3a\x6b\xa0\xab\x90\xf4\xfe").is_err());
+        assert!(huffman_decode(b"\xff").is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let block = encode([(":status", "200"), (":status", "201"), ("x-a", "b")]);
+        assert_eq!(block, b"\x88\x08\x03201\x00\x03x-a\x01b");
+        let mut decoder = Decoder::default();
+        assert_eq!(
+            decoder.decode(&block).unwrap(),
+            fields(&[(":status", "200"), (":status", "201"), ("x-a", "b")])
+        );
+        assert!(decoder.table.is_empty());
+    }
+}
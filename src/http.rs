@@ -1,5 +1,6 @@
-use std::{collections::HashMap, fmt, io, str};
+use std::{borrow::Cow, collections::HashMap, fmt, io, str, str::Utf8Error, time::SystemTime};
 
+use bytes::Bytes;
 use nom::{
     self,
     branch::alt,
@@ -11,9 +12,16 @@ use nom::{
     IResult,
 };
 
-use crate::ser::Serialize;
+use tokio::sync::mpsc;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+use crate::{
+    body::{BodyFrames, BodyStream},
+    compress::Encoding,
+    date::http_date,
+    ser::Serialize,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Method {
     Get,
     Head,
@@ -27,6 +35,19 @@ pub enum Method {
 }
 
 impl Method {
+    /// Every method, in the order they're listed in an Allow header
+    pub const ALL: [Self; 9] = [
+        Self::Get,
+        Self::Head,
+        Self::Post,
+        Self::Put,
+        Self::Delete,
+        Self::Connect,
+        Self::Options,
+        Self::Trace,
+        Self::Patch,
+    ];
+
     fn parser(input: &[u8]) -> IResult<&[u8], Self> {
         alt((
             value(Self::Get, tag("GET")),
@@ -40,9 +61,41 @@ impl Method {
             value(Self::Patch, tag("PATCH")),
         ))(input)
     }
+
+    /// The method's name as it appears in a request line
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Delete => "DELETE",
+            Self::Connect => "CONNECT",
+            Self::Options => "OPTIONS",
+            Self::Trace => "TRACE",
+            Self::Patch => "PATCH",
+        }
+    }
+
+    /// The method with the given name, as it appears in a request line
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|method| method.as_str() == name)
+    }
+
+    /// Whether the method is read-only, so the request can't change anything
+    /// on the server
+    pub fn is_safe(&self) -> bool {
+        matches!(self, Self::Get | Self::Head | Self::Options | Self::Trace)
+    }
+
+    /// Whether repeating the request has the same effect as sending it once,
+    /// which makes it fine to retry
+    pub fn is_idempotent(&self) -> bool {
+        self.is_safe() || matches!(self, Self::Put | Self::Delete)
+    }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Version {
     pub major: u8,
     pub minor: u8,
@@ -59,6 +112,11 @@ impl Version {
 
         Ok((remain, Self { major, minor }))
     }
+
+    /// Whether this is the given version or a later one
+    pub fn at_least(&self, major: u8, minor: u8) -> bool {
+        *self >= Self { major, minor }
+    }
 }
 
 impl Default for Version {
@@ -73,10 +131,104 @@ impl fmt::Display for Version {
     }
 }
 
+/// The four forms of request target from RFC 9112 section 3.2
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Target {
+    /// `/path?query`, the usual form
+    Origin { path: String, query: Option<String> },
+    /// `http://host/path?query`, as sent to proxies
+    Absolute {
+        scheme: String,
+        authority: String,
+        path: String,
+        query: Option<String>,
+    },
+    /// `host:port`, only used by CONNECT
+    Authority(String),
+    /// `*`, only used by OPTIONS to ask about the whole server
+    Asterisk,
+}
+
+impl Target {
+    pub fn parse(target: &str) -> Self {
+        if target == "*" {
+            return Self::Asterisk;
+        }
+
+        if !target.starts_with('/') {
+            let Some((scheme, rest)) = target.split_once("://") else {
+                return Self::Authority(target.to_owned());
+            };
+            let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+            let (authority, path_and_query) = rest.split_at(authority_end);
+            let (path, query) = split_query(path_and_query);
+            return Self::Absolute {
+                scheme: scheme.to_ascii_lowercase(),
+                // Userinfo isn't part of the host
+                authority: authority
+                    .rsplit_once('@')
+                    .map_or(authority, |(_, a)| a)
+                    .to_owned(),
+                path,
+                query,
+            };
+        }
+
+        let (path, query) = split_query(target);
+        Self::Origin { path, query }
+    }
+
+    /// The path to route on. An absolute-form target with no path has a path
+    /// of `/`, and the other forms have none.
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Origin { path, .. } | Self::Absolute { path, .. } if path.is_empty() => "/",
+            Self::Origin { path, .. } | Self::Absolute { path, .. } => path,
+            Self::Authority(_) => "",
+            Self::Asterisk => "*",
+        }
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        match self {
+            Self::Origin { query, .. } | Self::Absolute { query, .. } => query.as_deref(),
+            Self::Authority(_) | Self::Asterisk => None,
+        }
+    }
+
+    /// The path followed by the query, if there is one
+    pub fn path_and_query(&self) -> String {
+        match self.query() {
+            Some(query) => format!("{}?{query}", self.path()),
+            None => self.path().to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Origin { .. } => write!(f, "{}", self.path_and_query()),
+            Self::Absolute {
+                scheme, authority, ..
+            } => write!(f, "{scheme}://{authority}{}", self.path_and_query()),
+            Self::Authority(authority) => write!(f, "{authority}"),
+            Self::Asterisk => write!(f, "*"),
+        }
+    }
+}
+
+fn split_query(path_and_query: &str) -> (String, Option<String>) {
+    match path_and_query.split_once('?') {
+        Some((path, query)) => (path.to_owned(), Some(query.to_owned())),
+        None => (path_and_query.to_owned(), None),
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct RequestLine {
     pub method: Method,
-    pub path: String,
+    pub target: Target,
     pub version: Version,
 }
 
@@ -95,49 +247,287 @@ impl RequestLine {
             remain,
             Self {
                 method,
-                path: String::from_utf8(path).unwrap(),
+                target: Target::parse(&String::from_utf8_lossy(&path)),
                 version,
             },
         ))
     }
+
+    /// The path of the request target, without any query
+    pub fn path(&self) -> &str {
+        self.target.path()
+    }
 }
 
+/// Request headers keyed by lowercased name
+pub type RequestHeaders = HashMap<String, String>;
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct Request {
     pub req_line: RequestLine,
-    pub headers: HashMap<String, String>,
+    pub headers: RequestHeaders,
     pub body: Option<Vec<u8>>,
+    /// Fields sent after a chunked body
+    pub trailers: RequestHeaders,
+    /// The body when it's handed over as it arrives rather than read up front,
+    /// as a large upload is. Any trailers aren't available then.
+    pub streamed_body: Option<BodyStream>,
 }
 
 impl Request {
+    /// Parse a whole request. The body is `None` when the input stops before
+    /// the blank line ending the head, and `Some` (possibly empty) once that
+    /// line has been seen. A Content-Length bounds the body, leaving any bytes
+    /// after it unconsumed.
     pub fn parser(input: &[u8]) -> IResult<&[u8], Self> {
-        let (remain, (req_line, headers, body)) = tuple((
-            RequestLine::parser,
-            many0(pair(
-                terminated(take_while1(is_header_key), tag(": ")),
-                terminated(take_until1("\r\n"), tag("\r\n")),
-            )),
-            opt(preceded(tag("\r\n"), rest)),
-        ))(input)?;
+        let (remain, ((req_line, headers), body)) =
+            pair(head_fields, opt(preceded(tag("\r\n"), rest)))(input)?;
 
-        let headers_owned = headers
-            .into_iter()
-            .map(|(k, v)| {
-                (
-                    str::from_utf8(k).unwrap().to_lowercase(),
-                    str::from_utf8(v).unwrap().to_owned(),
-                )
-            })
-            .collect();
+        let mut req = Self {
+            req_line,
+            headers,
+            body: None,
+            trailers: RequestHeaders::new(),
+            streamed_body: None,
+        };
+        let Some(body) = body else {
+            return Ok((remain, req));
+        };
+        if req.is_chunked() {
+            return match decode_chunked(body) {
+                Ok(Chunked::Complete(body_data, trailers, len)) => {
+                    req.set_chunked_body(body_data, trailers);
+                    Ok((&body[len..], req))
+                }
+                Ok(Chunked::NeedMore) => Err(nom::Err::Incomplete(nom::Needed::Unknown)),
+                Err(_) => Err(nom::Err::Error(nom::error::Error::new(
+                    body,
+                    nom::error::ErrorKind::Verify,
+                ))),
+            };
+        }
+        let len = req
+            .get_content_length()
+            .map_or(body.len(), |len| len.min(body.len()));
+        let (body, remain) = body.split_at(len);
+        req.body = Some(body.to_vec());
+        Ok((remain, req))
+    }
 
-        Ok((
-            remain,
-            Self {
-                req_line,
-                headers: headers_owned,
-                body: body.map(|b| b.to_vec()),
-            },
-        ))
+    /// Parse a request which may not have been fully received yet. Rather
+    /// than failing on truncated input, this reports that more is needed.
+    pub fn parse_streaming(
+        input: &[u8],
+        line_endings: LineEndings,
+    ) -> Result<Parsed, nom::Err<nom::error::Error<&[u8]>>> {
+        let Some(head_len) = head_len(input, line_endings) else {
+            return Ok(Parsed::NeedMore);
+        };
+
+        let head = &input[0..head_len];
+        let (req_line, headers, _) = match normalize_line_endings(head, line_endings) {
+            Cow::Borrowed(head) => parse_request_head(head)?.1,
+            // The error can't point into the rewritten head, so blame it all
+            Cow::Owned(normalized) => {
+                parse_request_head(&normalized)
+                    .map_err(|err| err.map(|e| nom::error::Error::new(head, e.code)))?
+                    .1
+            }
+        };
+        let mut req = Self {
+            req_line,
+            headers,
+            body: None,
+            trailers: RequestHeaders::new(),
+            streamed_body: None,
+        };
+
+        if req.is_chunked() {
+            let body = &input[head_len..];
+            return match decode_chunked(body) {
+                Ok(Chunked::Complete(body, trailers, len)) => {
+                    req.set_chunked_body(body, trailers);
+                    Ok(Parsed::Complete(Box::new(req), head_len + len))
+                }
+                Ok(Chunked::NeedMore) => Ok(Parsed::NeedMore),
+                Err(_) => Err(nom::Err::Error(nom::error::Error::new(
+                    body,
+                    nom::error::ErrorKind::Verify,
+                ))),
+            };
+        }
+
+        let body_end = head_len.saturating_add(req.get_content_length().unwrap_or(0));
+        let Some(body) = input.get(head_len..body_end) else {
+            return Ok(Parsed::NeedMore);
+        };
+        req.body = Some(body.to_vec());
+
+        Ok(Parsed::Complete(Box::new(req), body_end))
+    }
+
+    /// The request method
+    pub fn method(&self) -> Method {
+        self.req_line.method
+    }
+
+    /// The request method's name as it appears in the request line
+    pub fn method_str(&self) -> &'static str {
+        self.req_line.method.as_str()
+    }
+
+    /// Read from a blocking reader until it has given a whole request, and
+    /// parse it. Fails if the request is bigger than max bytes or the reader
+    /// ends partway through. Anything read past the end of the request is
+    /// dropped.
+    pub fn parse_from_reader<R: io::Read>(reader: &mut R, max: usize) -> Result<Self, ReadError> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            match Self::parse_streaming(&buf, LineEndings::Strict) {
+                Ok(Parsed::Complete(req, _)) => return Ok(*req),
+                Ok(Parsed::NeedMore) => (),
+                Err(_) => return Err(ReadError::Invalid),
+            }
+            if buf.len() >= max {
+                return Err(ReadError::TooLarge(max));
+            }
+
+            let bytes_read = match reader.read(&mut chunk) {
+                Ok(0) => return Err(ReadError::Closed),
+                Ok(bytes_read) => bytes_read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ReadError::Io(e)),
+            };
+            buf.extend_from_slice(&chunk[0..bytes_read]);
+        }
+    }
+
+    /// Look up a header value, ignoring the case of the name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    /// The host the request is for, including any port. An absolute-form
+    /// target like `http://example.com/a` takes precedence over the Host
+    /// header.
+    pub fn host(&self) -> Option<&str> {
+        match &self.req_line.target {
+            Target::Absolute { authority, .. } => Some(authority.as_str()),
+            _ => self.header("host"),
+        }
+        .filter(|host| !host.is_empty())
+    }
+
+    /// The host the request is for, without any port
+    pub fn host_only(&self) -> Option<&str> {
+        let host = self.host()?;
+        let without_port = host
+            .rsplit_once(':')
+            .filter(|(_, port)| port.bytes().all(|b| b.is_ascii_digit()))
+            .map_or(host, |(host, _)| host);
+        Some(without_port)
+    }
+
+    /// Whether the body is sent with chunked transfer coding, which must be
+    /// the last coding applied
+    pub fn is_chunked(&self) -> bool {
+        self.header("transfer-encoding").is_some_and(|codings| {
+            codings
+                .rsplit(',')
+                .next()
+                .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"))
+        })
+    }
+
+    /// Set the body decoded from chunked transfer coding. As RFC 9112
+    /// section 7.1.3 describes, it then has a Content-Length in place of the
+    /// chunked coding.
+    pub fn set_chunked_body(&mut self, body: Vec<u8>, trailers: RequestHeaders) {
+        self.headers.remove("transfer-encoding");
+        self.headers
+            .insert(String::from("content-length"), body.len().to_string());
+        self.body = Some(body);
+        self.trailers = trailers;
+    }
+
+    /// Whether the client is waiting for a 100 Continue before sending the
+    /// body, from `Expect: 100-continue`
+    pub fn expect_continue(&self) -> bool {
+        self.header("expect")
+            .is_some_and(|v| v.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    /// Whether the client will accept trailer fields, from `TE: trailers`
+    pub fn accepts_trailers(&self) -> bool {
+        self.header("te").is_some_and(|te| {
+            te.split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case("trailers"))
+        })
+    }
+
+    /// The non-empty, percent-decoded segments of the path. For example,
+    /// `/files/a/b.txt` gives `["files", "a", "b.txt"]`. A decoded segment
+    /// can hold `/` or be `..`, so these aren't safe to join onto a path.
+    pub fn path_segments(&self) -> Vec<Cow<'_, str>> {
+        self.req_line
+            .path()
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(percent_decode)
+            .collect()
+    }
+
+    /// The codings from the Accept-Encoding header along with their q-values,
+    /// best first. Codings are lowercased.
+    pub fn accept_encodings(&self) -> Vec<(String, f32)> {
+        self.headers
+            .get("accept-encoding")
+            .map_or_else(Vec::new, |v| parse_quality_list(v))
+    }
+
+    /// The media types from the Accept header along with their q-values, best
+    /// first. Among equal q-values, more specific types come before wildcards.
+    /// With no Accept header, anything is acceptable.
+    pub fn accept_types(&self) -> Vec<(String, f32)> {
+        let Some(accept) = self.headers.get("accept") else {
+            return vec![(String::from("*/*"), 1.0)];
+        };
+
+        let mut types = parse_quality_list(accept);
+        let specificity = |t: &str| match t {
+            "*/*" => 0,
+            t if t.ends_with("/*") => 1,
+            _ => 2,
+        };
+        types.sort_by(|a, b| {
+            b.1.total_cmp(&a.1)
+                .then_with(|| specificity(&b.0).cmp(&specificity(&a.0)))
+        });
+        types
+    }
+
+    /// The body as UTF-8 text, or None if there is no body
+    pub fn body_str(&self) -> Option<Result<&str, Utf8Error>> {
+        self.body.as_deref().map(str::from_utf8)
+    }
+
+    /// Length of the body that was actually received, which may not match
+    /// the Content-Length header of a malformed request
+    /// The body a frame at a time, for processing a large upload without
+    /// holding all of it in memory. A body that was read up front comes as a
+    /// single frame. Waiting for frames blocks, as handlers are free to do.
+    pub fn body_stream(&self) -> BodyFrames<'_> {
+        match &self.streamed_body {
+            Some(stream) => BodyFrames::streamed(stream),
+            None => BodyFrames::buffered(self.body.as_deref().unwrap_or_default()),
+        }
+    }
+
+    pub fn body_len(&self) -> usize {
+        self.body.as_ref().map_or(0, Vec::len)
     }
 
     pub fn get_content_length(&self) -> Option<usize> {
@@ -147,6 +537,339 @@ impl Request {
     }
 }
 
+/// Parse the request line and headers, up to and including the blank line
+/// after them. Also gives the offset where the body begins.
+pub fn parse_request_head(input: &[u8]) -> IResult<&[u8], (RequestLine, RequestHeaders, usize)> {
+    let (remain, (req_line, headers)) = terminated(head_fields, tag("\r\n"))(input)?;
+    let body_start = input.len() - remain.len();
+    Ok((remain, (req_line, headers, body_start)))
+}
+
+/// The request line and headers, without the blank line ending them
+fn head_fields(input: &[u8]) -> IResult<&[u8], (RequestLine, RequestHeaders)> {
+    let (remain, (req_line, headers)) = pair(
+        RequestLine::parser,
+        many0(pair(
+            terminated(take_while1(is_header_key), tag(": ")),
+            terminated(take_until1("\r\n"), tag("\r\n")),
+        )),
+    )(input)?;
+
+    let headers = headers
+        .into_iter()
+        .map(|(k, v)| {
+            (
+                str::from_utf8(k).unwrap().to_lowercase(),
+                String::from_utf8_lossy(v).into_owned(),
+            )
+        })
+        .collect();
+    Ok((remain, (req_line, headers)))
+}
+
+/// Which line endings are accepted in a request head
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LineEndings {
+    /// Only CRLF, as the spec requires. Disagreeing with a proxy about where
+    /// lines end is a way to smuggle requests past it.
+    #[default]
+    Strict,
+    /// Bare LF as well as CRLF, for clients which don't send the CR
+    Lenient,
+}
+
+/// Length of the request line and headers, including the blank line after
+/// them, if the input holds all of them
+pub fn head_len(input: &[u8], line_endings: LineEndings) -> Option<usize> {
+    match line_endings {
+        LineEndings::Strict => input
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|pos| pos + 4),
+        LineEndings::Lenient => {
+            // The head ends at the first empty line, with or without its CR
+            let mut line_start = 0;
+            for (pos, _) in input.iter().enumerate().filter(|(_, &c)| c == b'\n') {
+                if matches!(&input[line_start..pos], b"" | b"\r") {
+                    return Some(pos + 1);
+                }
+                line_start = pos + 1;
+            }
+            None
+        }
+    }
+}
+
+/// Turn any bare LF line endings in a request head into CRLF, if they're
+/// accepted, so that it can be parsed as usual
+pub fn normalize_line_endings(head: &[u8], line_endings: LineEndings) -> Cow<'_, [u8]> {
+    let has_bare_lf =
+        head.first() == Some(&b'\n') || head.windows(2).any(|w| w[0] != b'\r' && w[1] == b'\n');
+    if line_endings == LineEndings::Strict || !has_bare_lf {
+        return Cow::Borrowed(head);
+    }
+
+    let mut normalized = Vec::with_capacity(head.len() + 16);
+    let mut prev = None;
+    for &c in head {
+        if c == b'\n' && prev != Some(b'\r') {
+            normalized.push(b'\r');
+        }
+        normalized.push(c);
+        prev = Some(c);
+    }
+    Cow::Owned(normalized)
+}
+
+/// Result of decoding a body sent with chunked transfer coding
+#[derive(Debug, Eq, PartialEq)]
+pub enum Chunked {
+    /// The whole body, any trailer fields sent after it, and the number of
+    /// input bytes the encoded body used
+    Complete(Vec<u8>, RequestHeaders, usize),
+    /// The input ends partway through the body
+    NeedMore,
+}
+
+/// The input isn't valid chunked transfer coding
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+#[error("invalid chunked body")]
+pub struct InvalidChunk;
+
+/// Decode a body sent with chunked transfer coding, from the start of input.
+/// Chunk extensions are ignored, and trailer field names are lowercased.
+pub fn decode_chunked(input: &[u8]) -> Result<Chunked, InvalidChunk> {
+    ChunkedDecoder::default().decode(input)
+}
+
+/// Decodes a chunked body as it arrives, picking up where it left off each
+/// time more is available
+#[derive(Debug, Default)]
+pub struct ChunkedDecoder {
+    body: Vec<u8>,
+    trailers: RequestHeaders,
+    /// Where decoding carries on from
+    pos: usize,
+    /// Data still to come of the chunk pos is partway through, if it is
+    chunk_left: Option<usize>,
+    /// Whether the last chunk has been seen, so only trailers are left
+    last_chunk: bool,
+}
+
+impl ChunkedDecoder {
+    /// Decode as much of input as possible. Each call must pass the same
+    /// input as the last one, with any more that has arrived on the end.
+    pub fn decode(&mut self, input: &[u8]) -> Result<Chunked, InvalidChunk> {
+        let next_line = |pos: usize| {
+            let len = input.get(pos..)?.windows(2).position(|w| w == b"\r\n")?;
+            Some((&input[pos..pos + len], pos + len + 2))
+        };
+
+        while !self.last_chunk {
+            // Take as much of a chunk's data as has arrived, so that a long
+            // chunk needn't be held in input until all of it is there
+            if let Some(left) = self.chunk_left {
+                let data = input.get(self.pos..).unwrap_or_default();
+                let data = &data[0..data.len().min(left)];
+                self.body.extend_from_slice(data);
+                self.pos += data.len();
+                self.chunk_left = Some(left - data.len());
+                let Some(end) = input
+                    .get(self.pos..self.pos + 2)
+                    .filter(|_| data.len() == left)
+                else {
+                    return Ok(Chunked::NeedMore);
+                };
+                if end != b"\r\n" {
+                    return Err(InvalidChunk);
+                }
+                self.pos += 2;
+                self.chunk_left = None;
+                continue;
+            }
+
+            let Some((line, data_start)) = next_line(self.pos) else {
+                return Ok(Chunked::NeedMore);
+            };
+            let size = line.split(|&c| c == b';').next().unwrap_or_default();
+            let size = str::from_utf8(size).map_err(|_| InvalidChunk)?.trim();
+            let size = usize::from_str_radix(size, 16).map_err(|_| InvalidChunk)?;
+            self.pos = data_start;
+            if size == 0 {
+                self.last_chunk = true;
+                break;
+            }
+            self.chunk_left = Some(size);
+        }
+
+        // Trailer fields, up to an empty line
+        loop {
+            let Some((line, next)) = next_line(self.pos) else {
+                return Ok(Chunked::NeedMore);
+            };
+            self.pos = next;
+            if line.is_empty() {
+                let body = std::mem::take(&mut self.body);
+                let trailers = std::mem::take(&mut self.trailers);
+                return Ok(Chunked::Complete(body, trailers, self.pos));
+            }
+            let line = str::from_utf8(line).map_err(|_| InvalidChunk)?;
+            let (name, value) = line.split_once(':').ok_or(InvalidChunk)?;
+            self.trailers
+                .insert(name.trim().to_lowercase(), value.trim().to_owned());
+        }
+    }
+
+    /// Take the body decoded so far and drop the input it was decoded from,
+    /// so that a long body can be passed on as it arrives. The next call to
+    /// decode must pass input starting where the dropped input ended.
+    pub fn take_decoded(&mut self, input: &mut Vec<u8>) -> Vec<u8> {
+        input.drain(0..self.pos);
+        self.pos = 0;
+        std::mem::take(&mut self.body)
+    }
+}
+
+/// Failure to read a request with Request::parse_from_reader
+#[derive(Debug, thiserror::Error)]
+pub enum ReadError {
+    #[error("invalid request")]
+    Invalid,
+    #[error("request is larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("input ended partway through the request")]
+    Closed,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Result of parsing input that may hold only part of a request
+#[derive(Debug, Eq, PartialEq)]
+pub enum Parsed {
+    /// A whole request, along with the number of input bytes it used
+    Complete(Box<Request>, usize),
+    /// The input ends partway through the request
+    NeedMore,
+}
+
+/// Parse a comma separated list of values with optional q-value parameters,
+/// such as `gzip, deflate;q=0.5`, sorted best first. Values are lowercased
+/// and parameters other than q are dropped.
+fn parse_quality_list(list: &str) -> Vec<(String, f32)> {
+    let mut values: Vec<(String, f32)> = list
+        .split(',')
+        .filter_map(|item| {
+            let (value, params) = split_header_params(item);
+            if value.is_empty() {
+                return None;
+            }
+            let q = params
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case("q"))
+                .find_map(|(_, q)| q.parse().ok())
+                .unwrap_or(1.0);
+            Some((value.to_lowercase(), q))
+        })
+        .collect();
+    values.sort_by(|a, b| b.1.total_cmp(&a.1));
+    values
+}
+
+/// Parse raw request bytes and describe the result, one field per line, or
+/// where parsing failed. Everything after the head counts as the body. For
+/// checking what the server makes of a request without sending it.
+pub fn describe_request(input: &[u8]) -> String {
+    // Parse the head strictly, so that a malformed header is reported rather
+    // than taken as the start of the body
+    let (body, (req_line, headers, _)) = match parse_request_head(input) {
+        Ok(parsed) => parsed,
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+            let offset = input.len() - e.input.len();
+            return format!("invalid request at byte {offset} ({:?})\n", e.code);
+        }
+        Err(nom::Err::Incomplete(_)) => return String::from("incomplete request\n"),
+    };
+
+    let mut output = format!(
+        "method: {}\ntarget: {}\nversion: {}\nheaders:\n",
+        req_line.method.as_str(),
+        req_line.target,
+        req_line.version
+    );
+    let mut headers: Vec<_> = headers.iter().collect();
+    headers.sort();
+    for (name, value) in headers {
+        output += &format!("  {name}: {value}\n");
+    }
+    output += &format!("body: {} bytes\n", body.len());
+    output
+}
+
+/// Split a header value such as `text/plain; charset=utf-8` into the main
+/// value and its parameters. Quotes around parameter values are removed, and
+/// semicolons inside them don't split. Parameters without a value are
+/// dropped.
+pub fn split_header_params(value: &str) -> (&str, Vec<(&str, &str)>) {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    parts.push(&value[start..]);
+
+    let main = parts[0].trim();
+    let params = parts[1..]
+        .iter()
+        .filter_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            Some((name.trim(), value))
+        })
+        .collect();
+    (main, params)
+}
+
+/// Decode `%XX` escapes. Invalid escapes are left as they are.
+pub fn percent_decode(s: &str) -> Cow<'_, str> {
+    if !s.contains('%') {
+        return Cow::Borrowed(s);
+    }
+
+    let bytes = s.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(b) => {
+                output.push(b);
+                i += 3;
+            }
+            None => {
+                output.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    Cow::Owned(String::from_utf8_lossy(&output).into_owned())
+}
+
 fn is_whitespace(c: u8) -> bool {
     c == b' ' || c == b'\t' || c == b'\r' || c == b'\n'
 }
@@ -157,34 +880,98 @@ fn is_header_key(c: u8) -> bool {
 
 #[derive(Debug, Default, Eq, PartialEq)]
 pub enum Status {
+    SwitchingProtocols,
     Ok,
     Created,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    NotModified,
     BadRequest,
+    Forbidden,
     NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    PreconditionFailed,
+    PayloadTooLarge,
+    RangeNotSatisfiable,
+    ExpectationFailed,
+    ImATeapot,
+    RequestHeaderFieldsTooLarge,
     #[default]
     Internal,
+    NotImplemented,
+    ServiceUnavailable,
+    InsufficientStorage,
+    /// Any other status, with the reason phrase to send for it
+    Custom {
+        code: u32,
+        text: String,
+    },
 }
 
 impl Status {
     pub fn code(&self) -> u32 {
         match self {
+            Self::SwitchingProtocols => 101,
             Self::Ok => 200,
             Self::Created => 201,
+            Self::NoContent => 204,
+            Self::PartialContent => 206,
+            Self::MovedPermanently => 301,
+            Self::NotModified => 304,
             Self::BadRequest => 400,
+            Self::Forbidden => 403,
             Self::NotFound => 404,
+            Self::MethodNotAllowed => 405,
+            Self::RequestTimeout => 408,
+            Self::PreconditionFailed => 412,
+            Self::PayloadTooLarge => 413,
+            Self::RangeNotSatisfiable => 416,
+            Self::ExpectationFailed => 417,
+            Self::ImATeapot => 418,
+            Self::RequestHeaderFieldsTooLarge => 431,
             Self::Internal => 500,
+            Self::NotImplemented => 501,
+            Self::ServiceUnavailable => 503,
+            Self::InsufficientStorage => 507,
+            Self::Custom { code, .. } => *code,
         }
     }
 
-    pub fn text(&self) -> &'static str {
+    pub fn text(&self) -> &str {
         match self {
+            Self::SwitchingProtocols => "Switching Protocols",
             Self::Ok => "OK",
             Self::Created => "Created",
+            Self::NoContent => "No Content",
+            Self::PartialContent => "Partial Content",
+            Self::MovedPermanently => "Moved Permanently",
+            Self::NotModified => "Not Modified",
             Self::BadRequest => "Bad Request",
+            Self::Forbidden => "Forbidden",
             Self::NotFound => "NOT FOUND",
+            Self::MethodNotAllowed => "Method Not Allowed",
+            Self::RequestTimeout => "Request Timeout",
+            Self::PreconditionFailed => "Precondition Failed",
+            Self::PayloadTooLarge => "Payload Too Large",
+            Self::RangeNotSatisfiable => "Range Not Satisfiable",
+            Self::ExpectationFailed => "Expectation Failed",
+            Self::ImATeapot => "I'm a teapot",
+            Self::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
             Self::Internal => "Internal Server Error",
+            Self::NotImplemented => "Not Implemented",
+            Self::ServiceUnavailable => "Service Unavailable",
+            Self::InsufficientStorage => "Insufficient Storage",
+            Self::Custom { text, .. } => text,
         }
     }
+
+    /// Whether a response with this status may have a body. Informational
+    /// responses, 204 and 304 never do.
+    pub fn allows_body(&self) -> bool {
+        !matches!(self.code(), 100..=199 | 204 | 304)
+    }
 }
 
 impl fmt::Display for Status {
@@ -197,19 +984,122 @@ impl fmt::Display for Status {
 pub struct StatusLine {
     pub version: Version,
     pub status: Status,
+    /// Send only the code, as the reason phrase is optional
+    pub omit_reason: bool,
 }
 
 impl fmt::Display for StatusLine {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}\r\n", self.version, self.status)
+        if self.omit_reason {
+            write!(f, "{} {}\r\n", self.version, self.status.code())
+        } else {
+            write!(f, "{} {}\r\n", self.version, self.status)
+        }
+    }
+}
+
+/// Header fields kept in the order they were added, allowing a name to
+/// appear more than once. Names are stored lowercase.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the first value for a name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    /// Get every value for a name, in the order they were added
+    pub fn get_all<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> {
+        let name = name.to_lowercase();
+        self.0
+            .iter()
+            .filter(move |(k, _)| *k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Set a header, replacing any values it already has
+    pub fn insert<K: ToString, V: ToString>(&mut self, name: K, value: V) {
+        let name = sanitize_header(name.to_string()).to_lowercase();
+        let value = sanitize_header(value.to_string());
+        match self.0.iter().position(|(k, _)| *k == name) {
+            Some(pos) => {
+                self.0[pos].1 = value;
+
+                // Drop any later duplicates
+                let mut rest = self.0.split_off(pos + 1);
+                rest.retain(|(k, _)| *k != name);
+                self.0.append(&mut rest);
+            }
+            None => self.0.push((name, value)),
+        }
+    }
+
+    /// Add a header, keeping any values it already has
+    pub fn append<K: ToString, V: ToString>(&mut self, name: K, value: V) {
+        let name = sanitize_header(name.to_string()).to_lowercase();
+        self.0.push((name, sanitize_header(value.to_string())));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        let name = name.to_lowercase();
+        self.0.retain(|(k, _)| *k != name);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 }
 
+/// Remove CR, LF and NUL from a header name or value. A value taken from the
+/// request could otherwise end the header early and add headers of its own,
+/// or even a whole extra response. Nothing is logged, since a client could
+/// send such values to flood the log.
+fn sanitize_header(s: String) -> String {
+    // Most values have none, so don't copy those
+    if !s.contains(['\r', '\n', '\0']) {
+        return s;
+    }
+    s.replace(['\r', '\n', '\0'], "")
+}
+
+/// Order in which a response's headers are written out
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum HeaderOrder {
+    /// Alphabetical by name
+    #[default]
+    Sorted,
+    /// The order the headers were added
+    Insertion,
+}
+
 #[derive(Default)]
 pub struct Response {
     pub status_line: StatusLine,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
     pub body: Option<Vec<u8>>,
+    pub header_order: HeaderOrder,
+    /// Fields sent after the body. Only possible with chunked encoding.
+    pub trailers: Headers,
+    /// Chunks of a body that's sent as they're produced rather than all at
+    /// once, ending when the sender is dropped
+    pub streamed_body: Option<mpsc::Receiver<Bytes>>,
 }
 
 impl Response {
@@ -218,59 +1108,364 @@ impl Response {
             status_line: StatusLine {
                 version: Version { major: 1, minor: 1 },
                 status,
+                omit_reason: false,
             },
-            headers: HashMap::new(),
+            headers: Headers::new(),
             body: None,
+            header_order: HeaderOrder::default(),
+            trailers: Headers::new(),
+            streamed_body: None,
         }
     }
 
+    /// An error response with the status line as a plain text body, such as
+    /// `404 NOT FOUND`
+    pub fn error(status: Status) -> Self {
+        let message = status.to_string();
+        Self::error_with_message(status, message)
+    }
+
+    /// An error response with a plain text body explaining what went wrong
+    pub fn error_with_message<S: ToString>(status: Status, message: S) -> Self {
+        Self::new(status).with_body(message.to_string().as_bytes(), "text/plain")
+    }
+
     pub fn with_header<K: ToString, V: ToString>(mut self, k: K, v: V) -> Self {
-        self.headers
-            .insert(k.to_string().to_lowercase(), v.to_string().to_lowercase());
+        self.headers.insert(k, v);
         self
     }
 
-    pub fn with_body<S: ToString>(mut self, body: &[u8], content_type: S) -> Self {
-        let body_len = body.len();
-        self.body = Some(body.to_owned());
-        self.with_header("Content-Type", content_type.to_string())
-            .with_header("Content-Length", body_len.to_string())
+    /// Add a header value without replacing any existing ones, for headers
+    /// such as Set-Cookie and Via which may be repeated
+    pub fn append_header<K: ToString, V: ToString>(mut self, k: K, v: V) -> Self {
+        self.headers.append(k, v);
+        self
     }
-}
 
-impl Serialize for Response {
-    fn serialize<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
-        write!(writer, "{}", self.status_line)?;
+    pub fn with_header_order(mut self, order: HeaderOrder) -> Self {
+        self.header_order = order;
+        self
+    }
 
-        // Sort so tests are easier to write
-        let mut sorted_headers: Vec<_> = self.headers.iter().collect();
-        sorted_headers.sort();
-        for (k, v) in sorted_headers {
-            write!(writer, "{}: {}\r\n", k, v)?;
-        }
-        write!(writer, "\r\n")?;
+    /// Get the first value of a header
+    pub fn header(&self, k: &str) -> Option<&str> {
+        self.headers.get(k)
+    }
 
-        if let Some(b) = self.body.as_ref() {
-            writer.write_all(b)?;
-        }
+    /// Add a field to send after the body, such as a checksum of it
+    pub fn with_trailer<K: ToString, V: ToString>(mut self, k: K, v: V) -> Self {
+        self.trailers.insert(k, v);
+        self
+    }
 
-        Ok(())
+    /// Whether the body is sent with chunked transfer coding
+    pub fn is_chunked(&self) -> bool {
+        self.header("transfer-encoding")
+            .is_some_and(|v| v.eq_ignore_ascii_case("chunked"))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Send the body in chunks as they arrive from chunks, so that the
+    /// response can start before all of it is known
+    pub fn with_streamed_body(mut self, chunks: mpsc::Receiver<Bytes>) -> Self {
+        self.body = None;
+        self.streamed_body = Some(chunks);
+        self.headers.remove("content-length");
+        self.with_header("Transfer-Encoding", "chunked")
+    }
 
-    #[test]
-    fn test_version_parser() {
-        let input = b"HTTP/1.1";
+    /// Answer in an older version of HTTP. Chunked encoding arrived with
+    /// HTTP/1.1, so a streamed body is sent as is and ends when the connection
+    /// closes.
+    pub fn with_version(mut self, version: Version) -> Self {
+        self.status_line.version = version;
+        if !version.at_least(1, 1) && self.streamed_body.is_some() {
+            self.headers.remove("transfer-encoding");
+        }
+        self
+    }
+
+    /// Send any trailers if the client accepts them, which means switching to
+    /// chunked encoding. Otherwise they are dropped.
+    pub fn with_trailers_if(mut self, accepted: bool) -> Self {
+        if self.trailers.is_empty() {
+            return self;
+        }
+        if !accepted || (self.body.is_none() && self.streamed_body.is_none()) {
+            self.trailers = Headers::new();
+            return self;
+        }
+
+        let names: Vec<_> = self.trailers.iter().map(|(k, _)| k).collect();
+        let names = names.join(", ");
+        self.headers.remove("content-length");
+        self.with_header("Transfer-Encoding", "chunked")
+            .with_header("Trailer", names)
+    }
+
+    pub fn with_body<S: ToString>(mut self, body: &[u8], content_type: S) -> Self {
+        let body_len = body.len();
+        self.body = Some(body.to_owned());
+        self.with_header("Content-Type", content_type.to_string())
+            .with_header("Content-Length", body_len.to_string())
+    }
+
+    /// Fill in the headers every response needs, correcting any that a
+    /// handler got wrong. This should be the last step before sending.
+    pub fn finalize(mut self, keep_alive: bool) -> Self {
+        if !self.status_line.status.allows_body() {
+            self.body = None;
+            self.headers.remove("content-length");
+        }
+        if self.is_chunked() {
+            self.headers.remove("content-length");
+        } else if let Some(body_len) = self.body.as_ref().map(Vec::len) {
+            self = self.with_header("Content-Length", body_len);
+        } else if keep_alive
+            && (200..300).contains(&self.status_line.status.code())
+            && self.status_line.status.allows_body()
+            && !self.headers.contains("content-length")
+        {
+            // Otherwise a client could wait for a body until the connection
+            // closes, which it won't
+            self = self.with_header("Content-Length", 0);
+        }
+        if !self.headers.contains("date") {
+            self = self.with_header("Date", http_date(SystemTime::now()));
+        }
+        if !keep_alive {
+            self = self.with_header("Connection", "close");
+        } else if !self.status_line.version.at_least(1, 1) {
+            // HTTP/1.0 closes unless told otherwise
+            self = self.with_header("Connection", "keep-alive");
+        }
+        self
+    }
+
+    /// The Content-Length header, if it's set and disagrees with the body
+    pub fn wrong_content_length(&self) -> Option<&str> {
+        let body_len = self.body.as_ref()?.len();
+        self.header("content-length")
+            .filter(|len| len.parse() != Ok(body_len))
+    }
+
+    /// Say which bytes of a total length the body holds, with the inclusive
+    /// range `(start, end)`, or that none of them could be sent if it's None
+    pub fn with_content_range(self, range: Option<(usize, usize)>, total: usize) -> Self {
+        let content_range = match range {
+            Some((start, end)) => format!("bytes {start}-{end}/{total}"),
+            None => format!("bytes */{total}"),
+        };
+        self.with_header("Content-Range", content_range)
+    }
+
+    pub fn with_etag(self, etag: &str) -> Self {
+        self.with_header("ETag", etag)
+    }
+
+    /// Mark the response as not to be stored by any cache
+    pub fn no_cache(self) -> Self {
+        self.with_header("Cache-Control", "no-store, no-cache, must-revalidate")
+            .with_header("Pragma", "no-cache")
+    }
+
+    /// Set Last-Modified to the given time
+    pub fn with_last_modified(self, time: SystemTime) -> Self {
+        self.with_header("Last-Modified", http_date(time))
+    }
+
+    /// Compress the body with the best of the offered codings that the client
+    /// accepts, if any
+    pub fn with_compression(mut self, accepted: &[(String, f32)], offered: &[Encoding]) -> Self {
+        // Compressing part of a body would make Content-Range meaningless
+        if self.body.as_ref().is_none_or(Vec::is_empty)
+            || self.headers.contains("content-encoding")
+            || self.headers.contains("content-range")
+        {
+            return self;
+        }
+
+        self = self.append_header("Vary", "Accept-Encoding");
+        let (Some(encoding), Some(body)) = (Encoding::negotiate(accepted, offered), &self.body)
+        else {
+            return self;
+        };
+
+        // Small or already compressed bodies can grow, so send those as they are
+        let compressed = encoding.encode(body);
+        let compressed_len = compressed.len();
+        if compressed_len >= body.len() {
+            return self;
+        }
+        self.body = Some(compressed);
+        self.with_header("Content-Encoding", encoding.name())
+            .with_header("Content-Length", compressed_len)
+    }
+
+    /// Ask the client to save the body as a file rather than display it
+    pub fn with_attachment(self, filename: &str) -> Self {
+        // Quoted filename for old clients, with non-ASCII characters replaced
+        let fallback: String = filename
+            .chars()
+            .map(|c| match c {
+                '"' | '\\' => format!("\\{c}"),
+                c if c.is_ascii() && !c.is_ascii_control() => c.to_string(),
+                _ => "_".to_owned(),
+            })
+            .collect();
+
+        let mut value = format!("attachment; filename=\"{fallback}\"");
+        if !filename.is_ascii() || filename.contains(['"', '\\']) {
+            // RFC 5987 extended value which carries the exact name
+            value.push_str("; filename*=UTF-8''");
+            for b in filename.bytes() {
+                if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                    value.push(b as char);
+                } else {
+                    value.push_str(&format!("%{b:02X}"));
+                }
+            }
+        }
+
+        self.with_header("Content-Disposition", value)
+    }
+}
+
+impl Response {
+    /// End a chunked body, with the trailers
+    pub fn serialize_last_chunk<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(writer, "0\r\n")?;
+        for (k, v) in self.trailers.iter() {
+            write!(writer, "{}: {}\r\n", k, v)?;
+        }
+        write!(writer, "\r\n")
+    }
+
+    /// Write the status line and headers, up to and including the blank line
+    /// before the body
+    pub fn serialize_head<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(writer, "{}", self.status_line)?;
+
+        // Sort by default so tests are easier to write. The sort is stable so
+        // repeated headers keep their relative order.
+        let mut headers: Vec<_> = self.headers.iter().collect();
+        if self.header_order == HeaderOrder::Sorted {
+            headers.sort_by_key(|(k, _)| *k);
+        }
+        for (k, v) in headers {
+            write!(writer, "{}: {}\r\n", k, v)?;
+        }
+        write!(writer, "\r\n")
+    }
+}
+
+/// An error response for a failed file operation, with the kind of error as
+/// the body
+impl From<io::Error> for Response {
+    fn from(e: io::Error) -> Self {
+        let status = match e.kind() {
+            io::ErrorKind::NotFound | io::ErrorKind::IsADirectory => Status::NotFound,
+            io::ErrorKind::PermissionDenied => Status::Forbidden,
+            _ => Status::Internal,
+        };
+        Self::error_with_message(status, e.kind())
+    }
+}
+
+impl Serialize for Response {
+    fn serialize<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.serialize_head(writer)?;
+
+        let Some(body) = self
+            .body
+            .as_ref()
+            .filter(|_| self.status_line.status.allows_body())
+        else {
+            return Ok(());
+        };
+
+        if !self.is_chunked() {
+            return writer.write_all(body);
+        }
+
+        // The whole body goes in one chunk, then the last chunk and trailers
+        serialize_chunk(writer, body)?;
+        self.serialize_last_chunk(writer)
+    }
+}
+
+/// Write data as one chunk of a chunked body. Nothing is written for empty
+/// data, since an empty chunk would end the body.
+pub fn serialize_chunk<W: io::Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    write!(writer, "{:x}\r\n", data.len())?;
+    writer.write_all(data)?;
+    write!(writer, "\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_method() {
+        let (_, req) = Request::parser(b"DELETE /files/a.txt HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.method(), Method::Delete);
+        assert_eq!(req.method_str(), "DELETE");
+
+        for name in [
+            "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+        ] {
+            let input = format!("{name} / HTTP/1.1\r\n\r\n");
+            let (_, req) = Request::parser(input.as_bytes()).unwrap();
+            assert_eq!(req.method_str(), name);
+        }
+    }
+
+    #[test]
+    fn test_method_classification() {
+        let classes = [
+            (Method::Get, true, true),
+            (Method::Head, true, true),
+            (Method::Options, true, true),
+            (Method::Trace, true, true),
+            (Method::Put, false, true),
+            (Method::Delete, false, true),
+            (Method::Post, false, false),
+            (Method::Patch, false, false),
+            (Method::Connect, false, false),
+        ];
+        for (method, safe, idempotent) in classes {
+            assert_eq!(method.is_safe(), safe, "{method:?}");
+            assert_eq!(method.is_idempotent(), idempotent, "{method:?}");
+        }
+    }
+
+    #[test]
+    fn test_version_parser() {
+        let input = b"HTTP/1.1";
 
         let (remain, ver) = Version::parser(input).unwrap();
         assert!(remain.is_empty());
         assert_eq!(ver, Version { major: 1, minor: 1 });
     }
 
+    #[test]
+    fn test_version_ordering() {
+        let v1_0 = Version { major: 1, minor: 0 };
+        let v1_1 = Version { major: 1, minor: 1 };
+        let v2_0 = Version { major: 2, minor: 0 };
+        assert!(v1_0 < v1_1);
+        assert!(v1_1 < v2_0);
+        assert_eq!(v1_1.max(v1_0), v1_1);
+
+        assert!(v1_1.at_least(1, 1));
+        assert!(v1_1.at_least(1, 0));
+        assert!(!v1_0.at_least(1, 1));
+        assert!(v2_0.at_least(1, 9));
+    }
+
     #[test]
     fn test_version_to_string() {
         let ver = Version { major: 1, minor: 1 };
@@ -287,10 +1482,51 @@ mod tests {
             req_line,
             RequestLine {
                 method: Method::Get,
-                path: String::from("/index.html"),
+                target: Target::Origin {
+                    path: String::from("/index.html"),
+                    query: None,
+                },
                 version: Version { major: 1, minor: 1 }
             }
         );
+
+        let (_, req_line) = RequestLine::parser(b"OPTIONS * HTTP/1.1\r\n").unwrap();
+        assert_eq!(req_line.method, Method::Options);
+        assert_eq!(req_line.target, Target::Asterisk);
+        assert_eq!(req_line.path(), "*");
+    }
+
+    #[test]
+    fn test_target_parse() {
+        let target = Target::parse("/files/a.txt?download=1");
+        assert_eq!(
+            target,
+            Target::Origin {
+                path: String::from("/files/a.txt"),
+                query: Some(String::from("download=1")),
+            }
+        );
+        assert_eq!(target.path(), "/files/a.txt");
+        assert_eq!(target.to_string(), "/files/a.txt?download=1");
+
+        let target = Target::parse("HTTP://user@example.com:8080?x");
+        assert_eq!(
+            target,
+            Target::Absolute {
+                scheme: String::from("http"),
+                authority: String::from("example.com:8080"),
+                path: String::new(),
+                query: Some(String::from("x")),
+            }
+        );
+        assert_eq!(target.path(), "/");
+        assert_eq!(target.path_and_query(), "/?x");
+
+        let target = Target::parse("example.com:443");
+        assert_eq!(target, Target::Authority(String::from("example.com:443")));
+        assert_eq!(target.path(), "");
+
+        assert_eq!(Target::parse("*"), Target::Asterisk);
     }
 
     #[test]
@@ -307,7 +1543,10 @@ mod tests {
             Request {
                 req_line: RequestLine {
                     method: Method::Get,
-                    path: String::from("/index.html"),
+                    target: Target::Origin {
+                        path: String::from("/index.html"),
+                        query: None,
+                    },
                     version: Version { major: 1, minor: 1 },
                 },
                 headers: [
@@ -317,18 +1556,522 @@ mod tests {
                 .into_iter()
                 .collect(),
                 body: None,
+                trailers: RequestHeaders::new(),
+                streamed_body: None,
             }
         );
     }
 
+    #[test]
+    fn test_request_parser_body() {
+        let (remain, req) =
+            Request::parser(b"POST / HTTP/1.1\r\nContent-Length: 0\r\n\r\n").unwrap();
+        assert!(remain.is_empty());
+        assert_eq!(req.body, Some(vec![]));
+
+        let (remain, req) =
+            Request::parser(b"POST / HTTP/1.1\r\nContent-Length: 0\r\n\r\nGET / HTTP/1.1\r\n\r\n")
+                .unwrap();
+        assert_eq!(remain, b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(req.body, Some(vec![]));
+
+        let (remain, req) =
+            Request::parser(b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcdef").unwrap();
+        assert_eq!(remain, b"def");
+        assert_eq!(req.body, Some(b"abc".to_vec()));
+
+        let (_, req) = Request::parser(b"POST / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.body, Some(vec![]));
+
+        let (remain, req) = Request::parser(b"GET / HTTP/1.1\r\nHost: a\r\n").unwrap();
+        assert!(remain.is_empty());
+        assert_eq!(req.body, None);
+    }
+
+    #[test]
+    fn test_request_body_len() {
+        let (_, req) =
+            Request::parser(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+        assert_eq!(req.body_len(), 5);
+        assert_eq!(req.get_content_length(), Some(5));
+
+        let (_, req) =
+            Request::parser(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello").unwrap();
+        assert_eq!(req.body_len(), 5);
+        assert_eq!(req.get_content_length(), Some(10));
+
+        let (_, req) = Request::parser(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n").unwrap();
+        assert_eq!(req.body_len(), 0);
+        assert_eq!(req.get_content_length(), Some(5));
+    }
+
+    #[test]
+    fn test_parse_request_head() {
+        let input = b"POST /files/a.txt HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let (remain, (req_line, headers, body_start)) = parse_request_head(input).unwrap();
+        assert_eq!(body_start, 49);
+        assert_eq!(&input[body_start..], b"hello");
+        assert_eq!(remain, b"hello");
+        assert_eq!(req_line.method, Method::Post);
+        assert_eq!(headers.get("content-length").map(String::as_str), Some("5"));
+
+        let (_, (_, headers, body_start)) = parse_request_head(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(body_start, 18);
+        assert!(headers.is_empty());
+
+        // The head isn't over until the blank line
+        assert!(parse_request_head(b"GET / HTTP/1.1\r\nHost: a\r\n").is_err());
+    }
+
+    #[test]
+    fn test_request_parse_streaming() {
+        let input = b"\
+            POST /files/a.txt HTTP/1.1\r\n\
+            Content-Length: 5\r\n\
+            \r\n\
+            hello\
+        ";
+
+        // Split partway through the headers and partway through the body
+        for split in [20, input.len() - 2] {
+            let parsed = Request::parse_streaming(&input[0..split], LineEndings::Strict).unwrap();
+            assert_eq!(parsed, Parsed::NeedMore);
+        }
+
+        let Parsed::Complete(req, used) =
+            Request::parse_streaming(input, LineEndings::Strict).unwrap()
+        else {
+            panic!("expected complete request");
+        };
+        assert_eq!(used, input.len());
+        assert_eq!(req.req_line.method, Method::Post);
+        assert_eq!(req.body.as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn test_decode_chunked() {
+        let input = b"5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nX-Checksum: abc\r\n\r\nGET";
+        assert_eq!(
+            decode_chunked(input).unwrap(),
+            Chunked::Complete(
+                b"hello world".to_vec(),
+                [(String::from("x-checksum"), String::from("abc"))]
+                    .into_iter()
+                    .collect(),
+                input.len() - 3
+            )
+        );
+
+        // Partway through a size line, the data, and the trailers
+        for split in [1, 9, 32, input.len() - 5] {
+            assert_eq!(decode_chunked(&input[0..split]).unwrap(), Chunked::NeedMore);
+        }
+
+        assert_eq!(decode_chunked(b"zz\r\n"), Err(InvalidChunk));
+        assert_eq!(decode_chunked(b"3\r\nabcd\r\n"), Err(InvalidChunk));
+        assert_eq!(decode_chunked(b"0\r\nno colon\r\n\r\n"), Err(InvalidChunk));
+        assert_eq!(decode_chunked(b"ffffffffffffffffff\r\n"), Err(InvalidChunk));
+    }
+
+    #[test]
+    fn test_chunked_decoder_resumes() {
+        let input = b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let mut decoder = ChunkedDecoder::default();
+        for end in 0..input.len() {
+            assert_eq!(decoder.decode(&input[0..end]).unwrap(), Chunked::NeedMore);
+        }
+        let Chunked::Complete(body, trailers, used) = decoder.decode(input).unwrap() else {
+            panic!("expected complete body");
+        };
+        assert_eq!(body, b"hello world");
+        assert!(trailers.is_empty());
+        assert_eq!(used, input.len());
+    }
+
+    #[test]
+    fn test_chunked_decoder_take_decoded() {
+        let mut input = b"5\r\nhello\r\n6\r\n wo".to_vec();
+        let mut decoder = ChunkedDecoder::default();
+        assert_eq!(decoder.decode(&input).unwrap(), Chunked::NeedMore);
+        assert_eq!(decoder.take_decoded(&mut input), b"hello wo");
+        assert!(input.is_empty());
+
+        input.extend_from_slice(b"rld\r\n0\r\n\r\nGET");
+        let Chunked::Complete(body, _, used) = decoder.decode(&input).unwrap() else {
+            panic!("expected complete body");
+        };
+        assert_eq!(body, b"rld");
+        assert_eq!(&input[used..], b"GET");
+    }
+
+    #[test]
+    fn test_request_chunked_body() {
+        let input = b"POST /files/a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            3\r\nabc\r\n0\r\nX-Checksum: 1\r\n\r\n";
+        let (remain, req) = Request::parser(input).unwrap();
+        assert!(remain.is_empty());
+        assert_eq!(req.body.as_deref(), Some(&b"abc"[..]));
+        assert_eq!(req.get_content_length(), Some(3));
+        assert_eq!(req.header("transfer-encoding"), None);
+        assert_eq!(
+            req.trailers.get("x-checksum").map(String::as_str),
+            Some("1")
+        );
+
+        let Parsed::Complete(req, used) =
+            Request::parse_streaming(input, LineEndings::Strict).unwrap()
+        else {
+            panic!("expected complete request");
+        };
+        assert_eq!(used, input.len());
+        assert_eq!(req.body.as_deref(), Some(&b"abc"[..]));
+
+        let truncated = &input[0..input.len() - 2];
+        assert_eq!(
+            Request::parse_streaming(truncated, LineEndings::Strict).unwrap(),
+            Parsed::NeedMore
+        );
+        assert!(Request::parser(truncated).is_err());
+    }
+
+    #[test]
+    fn test_request_parse_streaming_invalid() {
+        assert!(
+            Request::parse_streaming(b"BREW /pot HTTP/1.1\r\n\r\n", LineEndings::Strict).is_err()
+        );
+    }
+
+    #[test]
+    fn test_request_parse_streaming_line_endings() {
+        let input = b"POST /files/a.txt HTTP/1.1\nHost: example.com\r\nContent-Length: 5\n\nhello";
+
+        // Without the CRs the head never ends, or the request line is invalid
+        assert_eq!(
+            Request::parse_streaming(input, LineEndings::Strict).unwrap(),
+            Parsed::NeedMore
+        );
+        assert!(
+            Request::parse_streaming(b"GET / HTTP/1.1\n\r\n\r\n", LineEndings::Strict).is_err()
+        );
+
+        let Parsed::Complete(req, used) =
+            Request::parse_streaming(input, LineEndings::Lenient).unwrap()
+        else {
+            panic!("expected complete request");
+        };
+        assert_eq!(used, input.len());
+        assert_eq!(req.req_line.path(), "/files/a.txt");
+        assert_eq!(req.header("host"), Some("example.com"));
+        assert_eq!(req.body.as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn test_request_parse_from_reader() {
+        /// Gives at most a few bytes per read, like a slow connection
+        struct SmallReads<R>(R);
+
+        impl<R: io::Read> io::Read for SmallReads<R> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = buf.len().min(3);
+                self.0.read(&mut buf[0..len])
+            }
+        }
+
+        let input =
+            b"POST /files/a.txt HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\n\r\n";
+        let mut reader = SmallReads(io::Cursor::new(&input[..]));
+        let req = Request::parse_from_reader(&mut reader, 1024).unwrap();
+        assert_eq!(req.method(), Method::Post);
+        assert_eq!(req.req_line.path(), "/files/a.txt");
+        assert_eq!(req.body.as_deref(), Some(&b"hello"[..]));
+
+        let mut reader = SmallReads(io::Cursor::new(&input[0..40]));
+        assert!(matches!(
+            Request::parse_from_reader(&mut reader, 1024),
+            Err(ReadError::Closed)
+        ));
+
+        let mut reader = SmallReads(io::Cursor::new(&input[..]));
+        assert!(matches!(
+            Request::parse_from_reader(&mut reader, 16),
+            Err(ReadError::TooLarge(16))
+        ));
+
+        let mut reader = io::Cursor::new(&b"BREW /pot HTTP/1.1\r\n\r\n"[..]);
+        assert!(matches!(
+            Request::parse_from_reader(&mut reader, 1024),
+            Err(ReadError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn test_request_path_segments() {
+        let req = |path: &str| {
+            let input = format!("GET {path} HTTP/1.1\r\n\r\n");
+            Request::parser(input.as_bytes()).unwrap().1
+        };
+
+        assert!(req("/").path_segments().is_empty());
+        assert_eq!(
+            req("/files/a/b.txt").path_segments(),
+            ["files", "a", "b.txt"]
+        );
+        assert_eq!(req("/files/a/").path_segments(), ["files", "a"]);
+        assert_eq!(
+            req("/files/hello%20world%2Ftxt?x=%20").path_segments(),
+            ["files", "hello world/txt"]
+        );
+    }
+
+    #[test]
+    fn test_request_accept_encodings() {
+        let input = b"GET / HTTP/1.1\r\nAccept-Encoding: deflate;q=0.5, GZIP, br;q=0.8\r\n\r\n";
+        let (_, req) = Request::parser(input).unwrap();
+        assert_eq!(
+            req.accept_encodings(),
+            [
+                (String::from("gzip"), 1.0),
+                (String::from("br"), 0.8),
+                (String::from("deflate"), 0.5)
+            ]
+        );
+
+        let (_, req) = Request::parser(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(req.accept_encodings().is_empty());
+    }
+
+    #[test]
+    fn test_request_accept_types() {
+        let input = b"\
+            GET / HTTP/1.1\r\n\
+            Accept: */*;q=0.1, text/*, application/json;q=0.9, text/html;level=1\r\n\
+            \r\n\
+        ";
+        let (_, req) = Request::parser(input).unwrap();
+        assert_eq!(
+            req.accept_types(),
+            [
+                (String::from("text/html"), 1.0),
+                (String::from("text/*"), 1.0),
+                (String::from("application/json"), 0.9),
+                (String::from("*/*"), 0.1),
+            ]
+        );
+
+        let (_, req) = Request::parser(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.accept_types(), [(String::from("*/*"), 1.0)]);
+    }
+
+    #[test]
+    fn test_request_host() {
+        let (_, req) =
+            Request::parser(b"GET / HTTP/1.1\r\nHost: example.com:8080\r\n\r\n").unwrap();
+        assert_eq!(req.host(), Some("example.com:8080"));
+        assert_eq!(req.host_only(), Some("example.com"));
+
+        let input = b"GET http://other.example/a?b HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let (_, req) = Request::parser(input).unwrap();
+        assert_eq!(req.host(), Some("other.example"));
+
+        let (_, req) = Request::parser(b"GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n").unwrap();
+        assert_eq!(req.host_only(), Some("[::1]"));
+
+        let (_, req) = Request::parser(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.host(), None);
+        assert_eq!(req.host_only(), None);
+    }
+
+    #[test]
+    fn test_request_expect_continue() {
+        let (_, req) = Request::parser(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\n\r\n").unwrap();
+        assert!(req.expect_continue());
+
+        let (_, req) = Request::parser(b"POST / HTTP/1.1\r\nExpect: 100-Continue\r\n\r\n").unwrap();
+        assert!(req.expect_continue());
+
+        let (_, req) = Request::parser(b"POST / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(!req.expect_continue());
+    }
+
+    #[test]
+    fn test_request_body_str() {
+        let (_, req) = Request::parser(b"POST / HTTP/1.1\r\n\r\nh\xc3\xa9llo").unwrap();
+        assert_eq!(req.body_str(), Some(Ok("h\u{e9}llo")));
+
+        let (_, req) = Request::parser(b"POST / HTTP/1.1\r\n\r\n\xff\xfe").unwrap();
+        assert!(req.body_str().unwrap().is_err());
+
+        let (_, req) = Request::parser(b"GET / HTTP/1.1\r\n").unwrap();
+        assert_eq!(req.body_str(), None);
+    }
+
+    #[test]
+    fn test_response_with_compression() {
+        let accepted = [(String::from("br"), 1.0), (String::from("gzip"), 0.9)];
+        let resp = Response::new(Status::Ok)
+            .with_body(b"abcabcabcabcabcabcabcabcabcabcabcabc", "text/plain")
+            .with_compression(&accepted, &Encoding::PREFERENCE);
+        assert_eq!(resp.header("content-encoding"), Some("br"));
+        assert_eq!(
+            resp.body,
+            Some(crate::compress::brotli(
+                b"abcabcabcabcabcabcabcabcabcabcabcabc"
+            ))
+        );
+
+        // Without Brotli on offer, the client's next choice
+        let resp = Response::new(Status::Ok)
+            .with_body(b"abcabcabcabcabcabcabcabcabcabcabcabc", "text/plain")
+            .with_compression(&accepted, &[Encoding::Gzip, Encoding::Deflate]);
+        assert_eq!(resp.header("content-encoding"), Some("gzip"));
+        assert_eq!(resp.header("vary"), Some("Accept-Encoding"));
+        let body_len = resp.body.as_ref().unwrap().len();
+        assert_eq!(
+            resp.header("content-length"),
+            Some(body_len.to_string().as_str())
+        );
+
+        let resp = Response::new(Status::Ok)
+            .with_body(b"abc", "text/plain")
+            .with_compression(&[], &Encoding::PREFERENCE);
+        assert_eq!(resp.header("content-encoding"), None);
+        assert_eq!(resp.body.as_deref(), Some(&b"abc"[..]));
+    }
+
+    #[test]
+    fn test_response_with_compression_larger() {
+        // Pseudo-random bytes, which gzip can't shrink
+        let mut state = 1u32;
+        let body: Vec<u8> = (0..256)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+
+        let accepted = [(String::from("gzip"), 1.0)];
+        let resp = Response::new(Status::Ok)
+            .with_body(&body, "application/octet-stream")
+            .with_compression(&accepted, &Encoding::PREFERENCE);
+        assert_eq!(resp.header("content-encoding"), None);
+        assert_eq!(resp.header("content-length"), Some("256"));
+        assert_eq!(resp.body, Some(body));
+    }
+
+    #[test]
+    fn test_describe_request() {
+        let input = include_bytes!("../tests/fixtures/post_request.txt");
+        assert_eq!(
+            describe_request(input),
+            "method: POST\n\
+             target: /files/a.txt\n\
+             version: HTTP/1.1\n\
+             headers:\n  \
+               content-length: 5\n  \
+               host: localhost:4221\n  \
+               user-agent: curl/8.0\n\
+             body: 5 bytes\n"
+        );
+
+        assert_eq!(
+            describe_request(b"GET / HTTP/1.1\r\nBad Header\r\n\r\n"),
+            "invalid request at byte 16 (Tag)\n"
+        );
+        assert_eq!(
+            describe_request(b"BREW /pot HTTP/1.1\r\n\r\n"),
+            "invalid request at byte 0 (Tag)\n"
+        );
+    }
+
+    #[test]
+    fn test_split_header_params() {
+        assert_eq!(
+            split_header_params("text/plain; charset=utf-8"),
+            ("text/plain", vec![("charset", "utf-8")])
+        );
+        assert_eq!(
+            split_header_params(r#"form-data; name="f"; filename="a.txt""#),
+            ("form-data", vec![("name", "f"), ("filename", "a.txt")])
+        );
+        assert_eq!(split_header_params("gzip"), ("gzip", vec![]));
+        assert_eq!(
+            split_header_params(r#"attachment; filename="a;b.txt""#),
+            ("attachment", vec![("filename", "a;b.txt")])
+        );
+    }
+
+    #[test]
+    fn test_response_from_io_error() {
+        let resp = Response::from(io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(resp.status_line.status, Status::NotFound);
+        assert_eq!(resp.body.unwrap(), b"entity not found");
+
+        let resp = Response::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert_eq!(resp.status_line.status, Status::Forbidden);
+        assert_eq!(resp.body.unwrap(), b"permission denied");
+
+        let resp = Response::from(io::Error::other("disk on fire"));
+        assert_eq!(resp.status_line.status, Status::Internal);
+        assert_eq!(resp.header("content-type"), Some("text/plain"));
+        assert_eq!(resp.body.unwrap(), b"other error");
+    }
+
+    #[test]
+    fn test_serialize_chunks() {
+        let (_, receiver) = mpsc::channel(1);
+        let resp = Response::new(Status::Ok)
+            .with_body(b"abc", "text/plain")
+            .with_streamed_body(receiver)
+            .with_trailer("X-Checksum", "1")
+            .with_trailers_if(true)
+            .finalize(true);
+        assert_eq!(resp.body, None);
+        assert_eq!(resp.header("content-length"), None);
+        assert!(resp.is_chunked());
+        assert_eq!(resp.header("trailer"), Some("x-checksum"));
+
+        let mut output = Vec::new();
+        serialize_chunk(&mut output, b"hello").unwrap();
+        serialize_chunk(&mut output, b"").unwrap();
+        resp.serialize_last_chunk(&mut output).unwrap();
+        assert_eq!(output, b"5\r\nhello\r\n0\r\nx-checksum: 1\r\n\r\n");
+    }
+
+    #[test]
+    fn test_custom_status() {
+        let status = Status::Custom {
+            code: 451,
+            text: String::from("Unavailable For Legal Reasons"),
+        };
+        assert_eq!(status.code(), 451);
+        assert_eq!(status.text(), "Unavailable For Legal Reasons");
+        assert!(status.allows_body());
+
+        let resp = Response::new(status)
+            .with_header("Date", "Thu, 01 Jan 1970 00:00:00 GMT")
+            .finalize(true);
+        assert_eq!(
+            String::from_utf8(resp.to_bytes()).unwrap(),
+            "HTTP/1.1 451 Unavailable For Legal Reasons\r\n\
+             date: Thu, 01 Jan 1970 00:00:00 GMT\r\n\r\n"
+        );
+    }
+
     #[test]
     fn test_status_line_to_string() {
         let status_line = StatusLine {
             version: Version { major: 1, minor: 1 },
             status: Status::Ok,
+            omit_reason: false,
         };
         assert_eq!(status_line.to_string(), "HTTP/1.1 200 OK\r\n");
 
+        let status_line = StatusLine {
+            omit_reason: true,
+            ..status_line
+        };
+        assert_eq!(status_line.to_string(), "HTTP/1.1 200\r\n");
+
         let status_line = StatusLine::default();
         assert_eq!(
             status_line.to_string(),
@@ -347,4 +2090,263 @@ mod tests {
             b"HTTP/1.1 200 OK\r\ncontent-length: 3\r\ncontent-type: text/plain\r\n\r\nabc"
         )
     }
+
+    #[test]
+    fn test_response_finalize() {
+        let mut resp = Response::new(Status::Ok)
+            .with_body(b"abc", "text/plain")
+            .with_header("Content-Length", 10)
+            .finalize(true);
+        assert_eq!(resp.header("content-length"), Some("3"));
+        assert_eq!(resp.header("connection"), None);
+        assert!(resp.header("date").unwrap().ends_with(" GMT"));
+
+        resp = resp.with_header("Date", "today").finalize(false);
+        assert_eq!(resp.header("date"), Some("today"));
+        assert_eq!(resp.header("connection"), Some("close"));
+
+        let resp = Response::new(Status::Ok).finalize(true);
+        assert_eq!(resp.header("content-length"), Some("0"));
+        let resp = Response::new(Status::Ok).finalize(false);
+        assert_eq!(resp.header("content-length"), None);
+        let resp = Response::new(Status::NoContent).finalize(true);
+        assert_eq!(resp.header("content-length"), None);
+    }
+
+    #[test]
+    fn test_status_allows_body() {
+        assert!(!Status::NoContent.allows_body());
+        assert!(!Status::NotModified.allows_body());
+        assert!(!Status::SwitchingProtocols.allows_body());
+        assert!(Status::Ok.allows_body());
+        assert!(Status::NotFound.allows_body());
+
+        let resp = Response::new(Status::NoContent).with_body(b"abc", "text/plain");
+        assert_eq!(
+            resp.to_bytes(),
+            b"HTTP/1.1 204 No Content\r\ncontent-length: 3\r\ncontent-type: text/plain\r\n\r\n"
+        );
+        let resp = resp.finalize(true);
+        assert_eq!(resp.body, None);
+        assert_eq!(resp.header("content-length"), None);
+    }
+
+    #[test]
+    fn test_response_error() {
+        let resp = Response::error(Status::NotFound);
+        assert_eq!(resp.status_line.status, Status::NotFound);
+        assert_eq!(resp.header("content-type"), Some("text/plain"));
+        assert_eq!(resp.body.as_deref(), Some(&b"404 NOT FOUND"[..]));
+
+        let resp = Response::error_with_message(Status::BadRequest, "Content-Length required");
+        assert_eq!(resp.status_line.status, Status::BadRequest);
+        assert_eq!(resp.body.as_deref(), Some(&b"Content-Length required"[..]));
+    }
+
+    #[test]
+    fn test_finalize_corrects_content_length() {
+        let resp = Response::new(Status::Ok)
+            .with_body(b"hello", "text/plain")
+            .with_header("Content-Length", 3);
+        assert_eq!(resp.wrong_content_length(), Some("3"));
+
+        let resp = resp.finalize(true);
+        assert_eq!(resp.header("content-length"), Some("5"));
+        assert_eq!(resp.wrong_content_length(), None);
+
+        // A bodiless HEAD response keeps the length the body would have had
+        let mut resp = Response::new(Status::Ok).with_body(b"hello", "text/plain");
+        resp.body = None;
+        assert_eq!(resp.wrong_content_length(), None);
+    }
+
+    #[test]
+    fn test_with_content_range() {
+        let resp = Response::new(Status::PartialContent).with_content_range(Some((0, 99)), 1000);
+        assert_eq!(resp.header("content-range"), Some("bytes 0-99/1000"));
+
+        // An open-ended range runs to the last byte
+        let resp = Response::new(Status::PartialContent).with_content_range(Some((500, 999)), 1000);
+        assert_eq!(resp.header("content-range"), Some("bytes 500-999/1000"));
+
+        let resp = Response::new(Status::RangeNotSatisfiable).with_content_range(None, 1000);
+        assert_eq!(resp.header("content-range"), Some("bytes */1000"));
+    }
+
+    #[test]
+    fn test_serialize_batch() {
+        let date = "Thu, 01 Jan 1970 00:00:00 GMT";
+        let responses = [
+            Response::new(Status::Ok)
+                .with_body(b"abc", "text/plain")
+                .with_header("Date", date)
+                .finalize(true),
+            Response::new(Status::NotFound)
+                .with_header("Date", date)
+                .finalize(false),
+        ];
+        assert_eq!(
+            String::from_utf8(responses.to_bytes()).unwrap(),
+            "HTTP/1.1 200 OK\r\ncontent-length: 3\r\ncontent-type: text/plain\r\n\
+             date: Thu, 01 Jan 1970 00:00:00 GMT\r\n\r\nabc\
+             HTTP/1.1 404 NOT FOUND\r\nconnection: close\r\n\
+             date: Thu, 01 Jan 1970 00:00:00 GMT\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_header_injection() {
+        let resp = Response::new(Status::Ok)
+            .with_header("X-Echo", "a\r\nX-Evil: 1")
+            .append_header("X-Evil\r\nX-Other", "b\0")
+            .with_trailer("X-Trailer", "c\nX-Evil: 1")
+            .with_header("Date", "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(resp.header("x-echo"), Some("aX-Evil: 1"));
+        assert_eq!(resp.header("x-evilx-other"), Some("b"));
+        assert_eq!(resp.trailers.get("x-trailer"), Some("cX-Evil: 1"));
+
+        let output = String::from_utf8(resp.to_bytes()).unwrap();
+        assert!(!output.contains("\r\nX-Evil"));
+        assert!(!output.contains('\0'));
+    }
+
+    #[test]
+    fn test_no_cache() {
+        let resp = Response::new(Status::Ok)
+            .with_header("Cache-Control", "max-age=60")
+            .no_cache();
+        assert_eq!(
+            resp.header("cache-control"),
+            Some("no-store, no-cache, must-revalidate")
+        );
+        assert_eq!(resp.header("pragma"), Some("no-cache"));
+    }
+
+    #[test]
+    fn test_with_last_modified() {
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(784111777);
+        let resp = Response::new(Status::Ok).with_last_modified(time);
+        assert_eq!(
+            resp.header("last-modified"),
+            Some("Sun, 06 Nov 1994 08:49:37 GMT")
+        );
+    }
+
+    #[test]
+    fn test_response_trailers() {
+        let resp = || {
+            Response::new(Status::Ok)
+                .with_body(b"abc", "text/plain")
+                .with_trailer("X-Checksum", "123")
+        };
+
+        let chunked = resp()
+            .with_trailers_if(true)
+            .with_header("Date", "today")
+            .finalize(true);
+        assert_eq!(
+            chunked.to_bytes(),
+            b"HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ndate: today\r\n\
+              trailer: x-checksum\r\ntransfer-encoding: chunked\r\n\r\n\
+              3\r\nabc\r\n0\r\nx-checksum: 123\r\n\r\n"
+        );
+
+        let plain = resp().with_trailers_if(false);
+        assert!(plain.trailers.is_empty());
+        assert_eq!(plain.header("transfer-encoding"), None);
+        assert_eq!(plain.header("content-length"), Some("3"));
+    }
+
+    #[test]
+    fn test_with_attachment() {
+        let resp = Response::new(Status::Ok).with_attachment("a.txt");
+        assert_eq!(
+            resp.header("content-disposition").unwrap(),
+            "attachment; filename=\"a.txt\""
+        );
+
+        let resp = Response::new(Status::Ok).with_attachment("say \"hi\".txt");
+        assert_eq!(
+            resp.header("content-disposition").unwrap(),
+            "attachment; filename=\"say \\\"hi\\\".txt\"; filename*=UTF-8''say%20%22hi%22.txt"
+        );
+
+        let resp = Response::new(Status::Ok).with_attachment("résumé.pdf");
+        assert_eq!(
+            resp.header("content-disposition").unwrap(),
+            "attachment; filename=\"r_sum_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf"
+        );
+    }
+
+    #[test]
+    fn test_append_header() {
+        let resp = Response::new(Status::Ok)
+            .append_header("Via", "1.1 alpha")
+            .append_header("Via", "1.1 beta");
+        assert_eq!(
+            resp.to_bytes(),
+            b"HTTP/1.1 200 OK\r\nvia: 1.1 alpha\r\nvia: 1.1 beta\r\n\r\n"
+        );
+
+        // with_header still replaces everything
+        let resp = resp.with_header("Via", "1.1 gamma");
+        assert_eq!(
+            resp.to_bytes(),
+            b"HTTP/1.1 200 OK\r\nvia: 1.1 gamma\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_headers_insert_replaces() {
+        let mut headers = Headers::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("Content-Type", "text/plain");
+        headers.append("Set-Cookie", "b=2");
+        assert_eq!(
+            headers.get_all("set-cookie").collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+
+        headers.insert("Set-Cookie", "c=3");
+        assert_eq!(headers.get_all("set-cookie").collect::<Vec<_>>(), ["c=3"]);
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [("set-cookie", "c=3"), ("content-type", "text/plain")]
+        );
+
+        headers.remove("SET-COOKIE");
+        assert!(!headers.contains("set-cookie"));
+        assert_eq!(headers.len(), 1);
+    }
+
+    #[test]
+    fn test_response_duplicate_headers() {
+        let resp = Response::new(Status::Ok)
+            .with_body(b"abc", "text/plain")
+            .append_header("Set-Cookie", "b=2")
+            .append_header("Set-Cookie", "a=1");
+        assert_eq!(
+            resp.to_bytes(),
+            b"HTTP/1.1 200 OK\r\ncontent-length: 3\r\ncontent-type: text/plain\r\n\
+              set-cookie: b=2\r\nset-cookie: a=1\r\n\r\nabc"
+        );
+    }
+
+    #[test]
+    fn test_response_header_order() {
+        let resp = Response::new(Status::Ok)
+            .with_header("Server", "test")
+            .with_header("Date", "today")
+            .with_header("Allow", "GET");
+        assert_eq!(
+            resp.to_bytes(),
+            b"HTTP/1.1 200 OK\r\nallow: GET\r\ndate: today\r\nserver: test\r\n\r\n"
+        );
+
+        let resp = resp.with_header_order(HeaderOrder::Insertion);
+        assert_eq!(
+            resp.to_bytes(),
+            b"HTTP/1.1 200 OK\r\nserver: test\r\ndate: today\r\nallow: GET\r\n\r\n"
+        );
+    }
 }
@@ -0,0 +1,1250 @@
+//! HTTP/2, as RFC 9113 describes it. Each stream's request is turned into an
+//! http::Request and its answer comes back as an http::Response, so requests
+//! are routed the same whichever version they arrive in. A client gets here
+//! by agreeing h2 over ALPN in the TLS handshake, or by starting a plaintext
+//! connection with the preface, assuming the server speaks it.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    task::{AbortHandle, JoinSet},
+    time::{sleep, timeout},
+};
+
+use crate::{
+    config::{Config, LogLevel},
+    hpack, http,
+};
+
+/// What every HTTP/2 connection starts with
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// How HTTP/2 over TLS is named in ALPN
+pub const ALPN_ID: &[u8] = b"h2";
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_PRIORITY: u8 = 0x2;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_PUSH_PROMISE: u8 = 0x5;
+const FRAME_PING: u8 = 0x6;
+const FRAME_GOAWAY: u8 = 0x7;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+/// Length of the header every frame starts with
+const FRAME_HEADER_LEN: usize = 9;
+/// Largest frame payload either side may send, until the other allows more
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+/// Flow control window each stream, and the connection, starts with
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+/// Streams a client may have open at once
+const MAX_STREAMS: u32 = 100;
+/// Response data waiting on flow control beyond which no more is taken from
+/// streamed bodies
+const MAX_QUEUED: usize = 1 << 20;
+
+/// Whether the connection starts with the HTTP/2 preface, from a client that
+/// assumes the server speaks it
+pub fn is_preface(buf: &[u8]) -> bool {
+    buf.starts_with(PREFACE)
+}
+
+/// A failure which ends the whole connection, as the client is told with a
+/// GOAWAY
+#[derive(Debug, thiserror::Error)]
+#[error("{reason}")]
+struct ConnectionError {
+    code: u32,
+    reason: &'static str,
+}
+
+fn error(code: u32, reason: &'static str) -> ConnectionError {
+    ConnectionError { code, reason }
+}
+
+/// Serve an HTTP/2 connection until either side ends it, answering each
+/// request with respond. buf holds whatever has already been read of the
+/// connection, which may include the preface.
+pub async fn serve_conn<S, F, Fut>(
+    stream: S,
+    buf: Vec<u8>,
+    config: &Config,
+    shutting_down: &AtomicBool,
+    respond: F,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(http::Request) -> Fut,
+    Fut: Future<Output = http::Response> + Send + 'static,
+{
+    let (data_tx, data_rx) = mpsc::channel(16);
+    let mut conn = Connection {
+        stream,
+        config,
+        respond,
+        decoder: hpack::Decoder::default(),
+        streams: HashMap::new(),
+        last_stream_id: 0,
+        window: DEFAULT_WINDOW,
+        initial_window: DEFAULT_WINDOW,
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        continuation: None,
+        out: Vec::new(),
+        tasks: JoinSet::new(),
+        data_tx,
+        data_rx,
+        queued: 0,
+        going_away: false,
+    };
+    conn.run(buf, shutting_down).await
+}
+
+/// A stream the client has opened, from its request until the response has
+/// been sent
+#[derive(Debug)]
+struct Stream {
+    fields: Vec<(String, String)>,
+    body: Vec<u8>,
+    trailers: Vec<(String, String)>,
+    /// Whether the client has sent all of the request
+    received: bool,
+    /// Whether the request has been passed on, or answered without that
+    answered: bool,
+    /// How much response data the client will currently take
+    window: i64,
+    /// Response data waiting for window
+    pending: VecDeque<Bytes>,
+    /// Whether all of the response is in pending, so the stream ends once
+    /// it's been sent
+    complete: bool,
+    /// Fields to send after the response body
+    response_trailers: Vec<(String, String)>,
+    /// Task passing on a streamed response body
+    forwarder: Option<AbortHandle>,
+}
+
+struct Connection<'a, S, F> {
+    stream: S,
+    config: &'a Config,
+    respond: F,
+    decoder: hpack::Decoder,
+    streams: HashMap<u32, Stream>,
+    /// Highest numbered stream the client has opened
+    last_stream_id: u32,
+    /// How much response data the client will currently take, across streams
+    window: i64,
+    /// The client's window for new streams
+    initial_window: i64,
+    /// Largest frame payload the client takes
+    max_frame_size: usize,
+    /// A header block still to be finished by CONTINUATION frames: its
+    /// stream, the block so far and whether the stream ends with it
+    continuation: Option<(u32, Vec<u8>, bool)>,
+    /// Frames waiting to be written
+    out: Vec<u8>,
+    /// Handlers, which give a stream and its response, and tasks passing on
+    /// streamed bodies, which give None
+    tasks: JoinSet<Option<(u32, http::Response)>>,
+    /// Chunks of streamed bodies, with None once one ends
+    data_tx: mpsc::Sender<(u32, Option<Bytes>)>,
+    data_rx: mpsc::Receiver<(u32, Option<Bytes>)>,
+    /// Bytes in all the streams' pending data
+    queued: usize,
+    /// Set once either side has sent a GOAWAY, after which no new streams
+    /// are taken and the connection closes when the open ones are done
+    going_away: bool,
+}
+
+impl<S, F, Fut> Connection<'_, S, F>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(http::Request) -> Fut,
+    Fut: Future<Output = http::Response> + Send + 'static,
+{
+    async fn run(&mut self, mut buf: Vec<u8>, shutting_down: &AtomicBool) -> anyhow::Result<()> {
+        while buf.len() < PREFACE.len() && PREFACE.starts_with(&buf) {
+            if !self.read(&mut buf).await? {
+                return Ok(());
+            }
+        }
+        if !is_preface(&buf) {
+            self.log(format_args!("  HTTP/2 - fail, no preface"));
+            return Ok(());
+        }
+        buf.drain(0..PREFACE.len());
+
+        let mut settings = Vec::new();
+        settings.extend_from_slice(&SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes());
+        settings.extend_from_slice(&MAX_STREAMS.to_be_bytes());
+        frame(&mut self.out, FRAME_SETTINGS, 0, 0, &settings);
+
+        loop {
+            if let Err(e) = self.handle_frames(&mut buf) {
+                self.log(format_args!("  HTTP/2 - fail, {e}"));
+                self.go_away(e.code);
+                self.flush().await?;
+                return Ok(());
+            }
+            self.send_data();
+            if shutting_down.load(Ordering::Relaxed) && !self.going_away {
+                self.go_away(NO_ERROR);
+            }
+            self.flush().await?;
+            if self.going_away && self.streams.is_empty() {
+                return Ok(());
+            }
+
+            let mut chunk = [0u8; 16 * 1024];
+            tokio::select! {
+                read = self.stream.read(&mut chunk) => {
+                    let len = read?;
+                    if len == 0 {
+                        return Ok(());
+                    }
+                    buf.extend_from_slice(&chunk[0..len]);
+                }
+                Some(joined) = self.tasks.join_next() => {
+                    match joined {
+                        Ok(Some((id, response))) => self.start_response(id, response),
+                        Ok(None) => (),
+                        Err(e) if e.is_cancelled() => (),
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Some((id, data)) = self.data_rx.recv(), if self.queued < MAX_QUEUED => {
+                    self.queue_data(id, data);
+                }
+                () = sleep(self.config.keep_alive_timeout), if self.streams.is_empty() => {
+                    self.go_away(NO_ERROR);
+                    self.flush().await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Read more of the connection into buf. Returns false if it's closed or
+    /// idles out first.
+    async fn read(&mut self, buf: &mut Vec<u8>) -> anyhow::Result<bool> {
+        let mut chunk = [0u8; 1024];
+        match timeout(self.config.keep_alive_timeout, self.stream.read(&mut chunk)).await {
+            Ok(Ok(0)) | Err(_) => Ok(false),
+            Ok(Ok(len)) => {
+                buf.extend_from_slice(&chunk[0..len]);
+                Ok(true)
+            }
+            Ok(Err(e)) => Err(e.into()),
+        }
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        if self.out.is_empty() {
+            return Ok(());
+        }
+        let write = async {
+            self.stream.write_all(&self.out).await?;
+            self.stream.flush().await
+        };
+        match timeout(self.config.write_timeout, write).await {
+            Ok(result) => result?,
+            Err(_) => anyhow::bail!("timed out writing HTTP/2 frames"),
+        }
+        self.out.clear();
+        Ok(())
+    }
+
+    fn log(&self, args: std::fmt::Arguments) {
+        self.config.log(LogLevel::Info, args);
+    }
+
+    /// Handle every whole frame in buf, then remove them from it
+    fn handle_frames(&mut self, buf: &mut Vec<u8>) -> Result<(), ConnectionError> {
+        let mut used = 0;
+        while let Some(header) = buf.get(used..used + FRAME_HEADER_LEN) {
+            let len =
+                usize::from(header[0]) << 16 | usize::from(header[1]) << 8 | usize::from(header[2]);
+            let (kind, flags) = (header[3], header[4]);
+            let id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+            if len > DEFAULT_MAX_FRAME_SIZE {
+                return Err(error(FRAME_SIZE_ERROR, "frame too large"));
+            }
+            let Some(payload) = buf.get(used + FRAME_HEADER_LEN..used + FRAME_HEADER_LEN + len)
+            else {
+                break;
+            };
+            let payload = payload.to_vec();
+            used += FRAME_HEADER_LEN + len;
+            self.handle_frame(kind, flags, id, &payload)?;
+        }
+        buf.drain(0..used);
+        Ok(())
+    }
+
+    fn handle_frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        id: u32,
+        payload: &[u8],
+    ) -> Result<(), ConnectionError> {
+        // Nothing may come between a header block's frames
+        if let Some((continued, ..)) = self.continuation {
+            if kind != FRAME_CONTINUATION || id != continued {
+                return Err(error(PROTOCOL_ERROR, "header block interrupted"));
+            }
+        }
+
+        match kind {
+            FRAME_DATA => self.on_data(flags, id, payload),
+            FRAME_HEADERS => self.on_headers(flags, id, payload),
+            FRAME_CONTINUATION => self.on_continuation(flags, id, payload),
+            FRAME_PRIORITY if id == 0 => Err(error(PROTOCOL_ERROR, "PRIORITY on stream 0")),
+            FRAME_PRIORITY if payload.len() != 5 => {
+                self.reset(id, FRAME_SIZE_ERROR);
+                Ok(())
+            }
+            FRAME_RST_STREAM => {
+                if payload.len() != 4 {
+                    return Err(error(FRAME_SIZE_ERROR, "RST_STREAM of the wrong size"));
+                }
+                if id == 0 || id > self.last_stream_id {
+                    return Err(error(PROTOCOL_ERROR, "RST_STREAM on an idle stream"));
+                }
+                self.remove(id);
+                Ok(())
+            }
+            FRAME_SETTINGS => self.on_settings(flags, id, payload),
+            FRAME_PUSH_PROMISE => Err(error(PROTOCOL_ERROR, "PUSH_PROMISE from a client")),
+            FRAME_PING => {
+                if payload.len() != 8 {
+                    return Err(error(FRAME_SIZE_ERROR, "PING of the wrong size"));
+                }
+                if id != 0 {
+                    return Err(error(PROTOCOL_ERROR, "PING on a stream"));
+                }
+                if flags & FLAG_ACK == 0 {
+                    frame(&mut self.out, FRAME_PING, FLAG_ACK, 0, payload);
+                }
+                Ok(())
+            }
+            FRAME_GOAWAY => {
+                self.going_away = true;
+                Ok(())
+            }
+            FRAME_WINDOW_UPDATE => self.on_window_update(id, payload),
+            // Priorities are only advice, and unknown frames are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn on_data(&mut self, flags: u8, id: u32, payload: &[u8]) -> Result<(), ConnectionError> {
+        if id == 0 {
+            return Err(error(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        // Padding counts against the window too. The data is taken as soon as
+        // it arrives, so the window is given back straight away.
+        if !payload.is_empty() {
+            window_update(&mut self.out, 0, payload.len());
+        }
+        let data = unpad(flags, payload)?;
+        let end_stream = flags & FLAG_END_STREAM != 0;
+
+        let Some(stream) = self.streams.get_mut(&id) else {
+            if id > self.last_stream_id {
+                return Err(error(PROTOCOL_ERROR, "DATA on an idle stream"));
+            }
+            // A stream that's been reset may still have data on the way
+            return Ok(());
+        };
+        if stream.received {
+            self.reset(id, STREAM_CLOSED);
+            return Ok(());
+        }
+        stream.received = end_stream;
+        if stream.answered {
+            // Answered early, so the rest of the body isn't wanted
+            self.finish_if_sent(id);
+            return Ok(());
+        }
+        if stream.body.len() + data.len() > self.config.max_body_size {
+            self.log(format_args!(
+                "  Request - fail, body longer than {} bytes",
+                self.config.max_body_size
+            ));
+            self.answer_early(id, http::Status::PayloadTooLarge);
+            return Ok(());
+        }
+        stream.body.extend_from_slice(data);
+        if end_stream {
+            self.dispatch(id);
+        } else if !payload.is_empty() {
+            window_update(&mut self.out, id, payload.len());
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, flags: u8, id: u32, payload: &[u8]) -> Result<(), ConnectionError> {
+        if id.is_multiple_of(2) {
+            return Err(error(PROTOCOL_ERROR, "HEADERS on a server stream"));
+        }
+        let mut block = unpad(flags, payload)?;
+        if flags & FLAG_PRIORITY != 0 {
+            block = block
+                .get(5..)
+                .ok_or(error(FRAME_SIZE_ERROR, "HEADERS too short"))?;
+        }
+        let end_stream = flags & FLAG_END_STREAM != 0;
+        if flags & FLAG_END_HEADERS != 0 {
+            self.on_header_block(id, block, end_stream)
+        } else {
+            self.continuation = Some((id, block.to_vec(), end_stream));
+            Ok(())
+        }
+    }
+
+    fn on_continuation(
+        &mut self,
+        flags: u8,
+        id: u32,
+        payload: &[u8],
+    ) -> Result<(), ConnectionError> {
+        let Some((_, mut block, end_stream)) = self.continuation.take() else {
+            return Err(error(PROTOCOL_ERROR, "CONTINUATION without HEADERS"));
+        };
+        block.extend_from_slice(payload);
+        if block.len() > self.config.max_head_size {
+            return Err(error(ENHANCE_YOUR_CALM, "header block too large"));
+        }
+        if flags & FLAG_END_HEADERS != 0 {
+            self.on_header_block(id, &block, end_stream)
+        } else {
+            self.continuation = Some((id, block, end_stream));
+            Ok(())
+        }
+    }
+
+    fn on_header_block(
+        &mut self,
+        id: u32,
+        block: &[u8],
+        end_stream: bool,
+    ) -> Result<(), ConnectionError> {
+        // Decode even a block that's going to be ignored, to keep the
+        // decoder in step with the client's encoder
+        let fields = self
+            .decoder
+            .decode(block)
+            .map_err(|_| error(COMPRESSION_ERROR, "invalid header block"))?;
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            // Only trailers may follow the request's headers, and they end it
+            if stream.received || !end_stream {
+                self.reset(id, PROTOCOL_ERROR);
+                return Ok(());
+            }
+            stream.trailers = fields;
+            stream.received = true;
+            if !stream.answered {
+                self.dispatch(id);
+            } else {
+                self.finish_if_sent(id);
+            }
+            return Ok(());
+        }
+        // The stream has already been reset, or finished
+        if id <= self.last_stream_id {
+            return Ok(());
+        }
+
+        self.last_stream_id = id;
+        if self.going_away || self.streams.len() >= MAX_STREAMS as usize {
+            frame(
+                &mut self.out,
+                FRAME_RST_STREAM,
+                0,
+                id,
+                &REFUSED_STREAM.to_be_bytes(),
+            );
+            return Ok(());
+        }
+        let head_size: usize = fields
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum();
+        self.streams.insert(
+            id,
+            Stream {
+                fields,
+                body: Vec::new(),
+                trailers: Vec::new(),
+                received: end_stream,
+                answered: false,
+                window: self.initial_window,
+                pending: VecDeque::new(),
+                complete: false,
+                response_trailers: Vec::new(),
+                forwarder: None,
+            },
+        );
+        if head_size > self.config.max_head_size {
+            self.log(format_args!(
+                "  Request - fail, request head longer than {} bytes",
+                self.config.max_head_size
+            ));
+            self.answer_early(id, http::Status::RequestHeaderFieldsTooLarge);
+        } else if end_stream {
+            self.dispatch(id);
+        }
+        Ok(())
+    }
+
+    fn on_settings(&mut self, flags: u8, id: u32, payload: &[u8]) -> Result<(), ConnectionError> {
+        if id != 0 {
+            return Err(error(PROTOCOL_ERROR, "SETTINGS on a stream"));
+        }
+        if flags & FLAG_ACK != 0 {
+            if !payload.is_empty() {
+                return Err(error(FRAME_SIZE_ERROR, "SETTINGS ack with a payload"));
+            }
+            return Ok(());
+        }
+        if !payload.len().is_multiple_of(6) {
+            return Err(error(FRAME_SIZE_ERROR, "SETTINGS of the wrong size"));
+        }
+
+        for setting in payload.chunks(6) {
+            let name = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match name {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(error(PROTOCOL_ERROR, "invalid SETTINGS_ENABLE_PUSH"));
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = i64::from(value);
+                    if value > MAX_WINDOW {
+                        return Err(error(FLOW_CONTROL_ERROR, "initial window too large"));
+                    }
+                    // Windows already open move by the change
+                    for stream in self.streams.values_mut() {
+                        stream.window += value - self.initial_window;
+                        if stream.window > MAX_WINDOW {
+                            return Err(error(FLOW_CONTROL_ERROR, "window too large"));
+                        }
+                    }
+                    self.initial_window = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(DEFAULT_MAX_FRAME_SIZE..1 << 24).contains(&(value as usize)) {
+                        return Err(error(PROTOCOL_ERROR, "invalid SETTINGS_MAX_FRAME_SIZE"));
+                    }
+                    self.max_frame_size = value as usize;
+                }
+                // Responses don't use the dynamic table, so its size doesn't
+                // matter, and the rest are only advice
+                _ => (),
+            }
+        }
+        frame(&mut self.out, FRAME_SETTINGS, FLAG_ACK, 0, &[]);
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, id: u32, payload: &[u8]) -> Result<(), ConnectionError> {
+        let increment: [u8; 4] = payload
+            .try_into()
+            .map_err(|_| error(FRAME_SIZE_ERROR, "WINDOW_UPDATE of the wrong size"))?;
+        let increment = i64::from(u32::from_be_bytes(increment) & 0x7fff_ffff);
+        if id == 0 {
+            if increment == 0 {
+                return Err(error(PROTOCOL_ERROR, "empty WINDOW_UPDATE"));
+            }
+            self.window += increment;
+            if self.window > MAX_WINDOW {
+                return Err(error(FLOW_CONTROL_ERROR, "window too large"));
+            }
+        } else if let Some(stream) = self.streams.get_mut(&id) {
+            stream.window += increment;
+            if increment == 0 {
+                self.reset(id, PROTOCOL_ERROR);
+            } else if stream.window > MAX_WINDOW {
+                self.reset(id, FLOW_CONTROL_ERROR);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pass on a stream's whole request to be answered
+    fn dispatch(&mut self, id: u32) {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return;
+        };
+        stream.answered = true;
+        let fields = std::mem::take(&mut stream.fields);
+        let body = std::mem::take(&mut stream.body);
+        let trailers = std::mem::take(&mut stream.trailers);
+        let Some(req) = request(fields, body, trailers) else {
+            self.log(format_args!("  Request - fail, malformed HTTP/2 request"));
+            self.reset(id, PROTOCOL_ERROR);
+            return;
+        };
+        let response = (self.respond)(req);
+        self.tasks.spawn(async move { Some((id, response.await)) });
+    }
+
+    /// Answer a request without passing it on, before the client has
+    /// necessarily sent all of it
+    fn answer_early(&mut self, id: u32, status: http::Status) {
+        if let Some(stream) = self.streams.get_mut(&id) {
+            stream.answered = true;
+            stream.body = Vec::new();
+        }
+        self.start_response(id, http::Response::error(status).finalize(true));
+    }
+
+    /// Send a response's headers, and queue its body to be sent as the
+    /// client's windows allow
+    fn start_response(&mut self, id: u32, response: http::Response) {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            // Reset while it was being handled
+            return;
+        };
+        let code = response.status_line.status.code().to_string();
+        let fields: Vec<(&str, &str)> = [(":status", code.as_str())]
+            .into_iter()
+            .chain(
+                response
+                    .headers
+                    .iter()
+                    .filter(|(name, _)| !is_connection_specific(name)),
+            )
+            .collect();
+        let body = response.body.filter(|body| !body.is_empty());
+        stream.response_trailers = response
+            .trailers
+            .iter()
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect();
+        let end_stream = body.is_none()
+            && response.streamed_body.is_none()
+            && stream.response_trailers.is_empty();
+        headers(&mut self.out, id, &fields, end_stream, self.max_frame_size);
+        if end_stream {
+            stream.complete = true;
+            stream.response_trailers.clear();
+            self.finish(id);
+            return;
+        }
+
+        if let Some(body) = body {
+            self.queued += body.len();
+            stream.pending.push_back(Bytes::from(body));
+        }
+        match response.streamed_body {
+            Some(mut chunks) => {
+                let data = self.data_tx.clone();
+                stream.forwarder = Some(self.tasks.spawn(async move {
+                    while let Some(chunk) = chunks.recv().await {
+                        if data.send((id, Some(chunk))).await.is_err() {
+                            return None;
+                        }
+                    }
+                    let _ = data.send((id, None)).await;
+                    None
+                }));
+            }
+            None => stream.complete = true,
+        }
+    }
+
+    /// Queue a chunk of a streamed body, or end the body if there's no chunk
+    fn queue_data(&mut self, id: u32, data: Option<Bytes>) {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return;
+        };
+        match data {
+            Some(data) if !data.is_empty() => {
+                self.queued += data.len();
+                stream.pending.push_back(data);
+            }
+            Some(_) => (),
+            None => stream.complete = true,
+        }
+    }
+
+    /// Send as much pending response data as the windows allow, ending the
+    /// streams that have sent everything
+    fn send_data(&mut self) {
+        let mut ids: Vec<u32> = self.streams.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let Some(stream) = self.streams.get_mut(&id) else {
+                continue;
+            };
+            while self.window > 0 && stream.window > 0 {
+                let Some(chunk) = stream.pending.front_mut() else {
+                    break;
+                };
+                let len = (chunk.len() as i64)
+                    .min(self.window)
+                    .min(stream.window)
+                    .min(self.max_frame_size as i64) as usize;
+                let data = chunk.split_to(len);
+                if chunk.is_empty() {
+                    stream.pending.pop_front();
+                }
+                self.window -= len as i64;
+                stream.window -= len as i64;
+                self.queued -= len;
+                let last = stream.pending.is_empty()
+                    && stream.complete
+                    && stream.response_trailers.is_empty();
+                let flags = if last { FLAG_END_STREAM } else { 0 };
+                frame(&mut self.out, FRAME_DATA, flags, id, &data);
+                if last {
+                    self.finish(id);
+                    break;
+                }
+            }
+
+            let Some(stream) = self.streams.get_mut(&id) else {
+                continue;
+            };
+            if stream.complete && stream.pending.is_empty() {
+                // Trailers end the stream, or else an empty DATA frame does
+                let trailers = std::mem::take(&mut stream.response_trailers);
+                if trailers.is_empty() {
+                    frame(&mut self.out, FRAME_DATA, FLAG_END_STREAM, id, &[]);
+                } else {
+                    let fields: Vec<(&str, &str)> = trailers
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.as_str()))
+                        .collect();
+                    headers(&mut self.out, id, &fields, true, self.max_frame_size);
+                }
+                self.finish(id);
+            }
+        }
+    }
+
+    /// Forget a stream whose response has been sent in full. If the client
+    /// is still sending the request, it's told to stop.
+    fn finish(&mut self, id: u32) {
+        let Some(stream) = self.streams.remove(&id) else {
+            return;
+        };
+        if !stream.received {
+            frame(
+                &mut self.out,
+                FRAME_RST_STREAM,
+                0,
+                id,
+                &NO_ERROR.to_be_bytes(),
+            );
+        }
+    }
+
+    /// Finish a stream that was answered early, if the whole response has
+    /// already been sent
+    fn finish_if_sent(&mut self, id: u32) {
+        let sent = self
+            .streams
+            .get(&id)
+            .is_some_and(|stream| stream.complete && stream.pending.is_empty());
+        if sent {
+            self.finish(id);
+        }
+    }
+
+    /// End a stream because of an error with it
+    fn reset(&mut self, id: u32, code: u32) {
+        frame(&mut self.out, FRAME_RST_STREAM, 0, id, &code.to_be_bytes());
+        self.remove(id);
+    }
+
+    fn remove(&mut self, id: u32) {
+        if let Some(stream) = self.streams.remove(&id) {
+            self.queued -= stream.pending.iter().map(Bytes::len).sum::<usize>();
+            if let Some(forwarder) = stream.forwarder {
+                forwarder.abort();
+            }
+        }
+    }
+
+    /// Tell the client no more streams will be taken, and why
+    fn go_away(&mut self, code: u32) {
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        frame(&mut self.out, FRAME_GOAWAY, 0, 0, &payload);
+        self.going_away = true;
+    }
+}
+
+/// Build the request a stream's fields and body make up, or None if they're
+/// malformed
+fn request(
+    fields: Vec<(String, String)>,
+    body: Vec<u8>,
+    trailers: Vec<(String, String)>,
+) -> Option<http::Request> {
+    let (mut method, mut path, mut authority) = (None, None, None);
+    let mut headers = http::RequestHeaders::new();
+    for (name, value) in fields {
+        // Pseudo-headers come before any others
+        if name.starts_with(':') && !headers.is_empty() {
+            return None;
+        }
+        match name.as_str() {
+            ":method" => method = Some(value),
+            ":path" => path = Some(value),
+            ":authority" => authority = Some(value),
+            ":scheme" => (),
+            _ if name.starts_with(':') || name.contains(|c: char| c.is_ascii_uppercase()) => {
+                return None;
+            }
+            _ if is_connection_specific(&name) => return None,
+            "te" if value != "trailers" => return None,
+            // Cookies may be split into separate fields to compress better
+            "cookie" => {
+                let cookie = headers.entry(name).or_default();
+                if !cookie.is_empty() {
+                    cookie.push_str("; ");
+                }
+                cookie.push_str(&value);
+            }
+            _ => {
+                headers.insert(name, value);
+            }
+        }
+    }
+
+    let method = http::Method::from_name(&method?)?;
+    let path = path.filter(|path| !path.is_empty())?;
+    if let Some(authority) = authority {
+        headers.entry(String::from("host")).or_insert(authority);
+    }
+    match headers.get("content-length") {
+        Some(len) if len.parse() != Ok(body.len()) => return None,
+        Some(_) => (),
+        None if !body.is_empty() || !method.is_safe() => {
+            headers.insert(String::from("content-length"), body.len().to_string());
+        }
+        None => (),
+    }
+
+    Some(http::Request {
+        req_line: http::RequestLine {
+            method,
+            target: http::Target::parse(&path),
+            version: http::Version { major: 2, minor: 0 },
+        },
+        headers,
+        body: Some(body),
+        trailers: trailers.into_iter().collect(),
+        streamed_body: None,
+    })
+}
+
+/// Whether a field is about the HTTP/1.1 connection, which HTTP/2 does
+/// without
+fn is_connection_specific(name: &str) -> bool {
+    matches!(
+        name,
+        "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade"
+    )
+}
+
+/// The data in a frame which may be padded
+fn unpad(flags: u8, payload: &[u8]) -> Result<&[u8], ConnectionError> {
+    if flags & FLAG_PADDED == 0 {
+        return Ok(payload);
+    }
+    let (&pad_len, rest) = payload
+        .split_first()
+        .ok_or(error(FRAME_SIZE_ERROR, "padded frame too short"))?;
+    rest.len()
+        .checked_sub(usize::from(pad_len))
+        .map(|len| &rest[0..len])
+        .ok_or(error(PROTOCOL_ERROR, "padding longer than the frame"))
+}
+
+/// Append a frame
+fn frame(out: &mut Vec<u8>, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.push(flags);
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// Append a header block, split into CONTINUATION frames if it's too large
+/// for one
+fn headers(
+    out: &mut Vec<u8>,
+    id: u32,
+    fields: &[(&str, &str)],
+    end_stream: bool,
+    max_frame_size: usize,
+) {
+    let block = hpack::encode(fields.iter().copied());
+    let mut fragments = block.chunks(max_frame_size).peekable();
+    let mut kind = FRAME_HEADERS;
+    let mut flags = if end_stream { FLAG_END_STREAM } else { 0 };
+    // Even an empty block needs a frame
+    let first: &[u8] = &[];
+    let mut fragment = fragments.next().unwrap_or(first);
+    loop {
+        let last = fragments.peek().is_none();
+        if last {
+            flags |= FLAG_END_HEADERS;
+        }
+        frame(out, kind, flags, id, fragment);
+        let Some(next) = fragments.next() else {
+            break;
+        };
+        fragment = next;
+        kind = FRAME_CONTINUATION;
+        flags = 0;
+    }
+}
+
+/// Append a WINDOW_UPDATE giving back len bytes of window
+fn window_update(out: &mut Vec<u8>, id: u32, len: usize) {
+    frame(out, FRAME_WINDOW_UPDATE, 0, id, &(len as u32).to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    /// A frame as it was written: type, flags, stream and payload
+    type Frame = (u8, u8, u32, Vec<u8>);
+
+    fn parse_frames(mut out: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while out.len() >= FRAME_HEADER_LEN {
+            let len = usize::from(out[0]) << 16 | usize::from(out[1]) << 8 | usize::from(out[2]);
+            let id = u32::from_be_bytes([out[5], out[6], out[7], out[8]]);
+            frames.push((out[3], out[4], id, out[9..9 + len].to_vec()));
+            out = &out[9 + len..];
+        }
+        frames
+    }
+
+    /// Answer with the path and body of the request
+    async fn echo(req: http::Request) -> http::Response {
+        let mut body = req.req_line.target.to_string().into_bytes();
+        body.extend_from_slice(req.body.as_deref().unwrap_or_default());
+        http::Response::new(http::Status::Ok).with_body(&body, "text/plain")
+    }
+
+    /// Serve a connection with echo, sending input once the server's
+    /// preface has been read, and return the frames the server sends
+    async fn run(config: Config, input: &[u8]) -> Vec<Frame> {
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        let task = tokio::spawn(async move {
+            let shutting_down = AtomicBool::new(false);
+            serve_conn(server, Vec::new(), &config, &shutting_down, echo).await
+        });
+        client.write_all(PREFACE).await.unwrap();
+        client.write_all(input).await.unwrap();
+        let output = read_until_quiet(&mut client).await;
+        drop(client);
+        task.await.unwrap().unwrap();
+        parse_frames(&output)
+    }
+
+    async fn read_until_quiet(client: &mut DuplexStream) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut chunk = [0u8; 4096];
+        while let Ok(Ok(len @ 1..)) =
+            timeout(Duration::from_millis(200), client.read(&mut chunk)).await
+        {
+            output.extend_from_slice(&chunk[0..len]);
+        }
+        output
+    }
+
+    fn get(id: u32, path: &str) -> Vec<u8> {
+        let block = hpack::encode([(":method", "GET"), (":scheme", "http"), (":path", path)]);
+        let mut out = Vec::new();
+        frame(
+            &mut out,
+            FRAME_HEADERS,
+            FLAG_END_HEADERS | FLAG_END_STREAM,
+            id,
+            &block,
+        );
+        out
+    }
+
+    fn fields(payload: &[u8]) -> Vec<(String, String)> {
+        hpack::Decoder::default().decode(payload).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_settings_and_ping() {
+        let mut input = Vec::new();
+        frame(&mut input, FRAME_SETTINGS, 0, 0, &[]);
+        frame(&mut input, FRAME_PING, 0, 0, b"12345678");
+        let frames = run(Config::default(), &input).await;
+        assert_eq!(frames[0], (FRAME_SETTINGS, 0, 0, vec![0, 3, 0, 0, 0, 100]));
+        assert_eq!(frames[1], (FRAME_SETTINGS, FLAG_ACK, 0, vec![]));
+        assert_eq!(frames[2], (FRAME_PING, FLAG_ACK, 0, b"12345678".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let mut input = get(1, "/a");
+        let block = hpack::encode([(":method", "POST"), (":scheme", "http"), (":path", "/b")]);
+        frame(&mut input, FRAME_HEADERS, FLAG_END_HEADERS, 3, &block);
+        frame(&mut input, FRAME_DATA, 0, 3, b"hello ");
+        frame(&mut input, FRAME_DATA, FLAG_END_STREAM, 3, b"world");
+        let frames = run(Config::default(), &input).await;
+
+        let responses: Vec<_> = frames.iter().filter(|f| f.0 == FRAME_HEADERS).collect();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].1, FLAG_END_HEADERS);
+        assert_eq!(
+            fields(&responses[0].3),
+            [
+                (String::from(":status"), String::from("200")),
+                (String::from("content-type"), String::from("text/plain")),
+                (String::from("content-length"), String::from("2")),
+            ]
+        );
+        let data = |id| {
+            frames
+                .iter()
+                .filter(|f| f.0 == FRAME_DATA && f.2 == id)
+                .map(|f| (f.1, f.3.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(data(1), [(FLAG_END_STREAM, b"/a".to_vec())]);
+        assert_eq!(data(3), [(FLAG_END_STREAM, b"/bhello world".to_vec())]);
+
+        // The request body's window is given back
+        let updates: Vec<_> = frames
+            .iter()
+            .filter(|f| f.0 == FRAME_WINDOW_UPDATE)
+            .collect();
+        assert_eq!(updates.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_request_fields() {
+        let fields = |fields: &[(&str, &str)]| {
+            fields
+                .iter()
+                .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+                .collect::<Vec<_>>()
+        };
+        let req = request(
+            fields(&[
+                (":method", "PUT"),
+                (":path", "/files/a?b=c"),
+                (":authority", "example.com"),
+                ("cookie", "a=1"),
+                ("cookie", "b=2"),
+            ]),
+            b"abc".to_vec(),
+            fields(&[("x-checksum", "1")]),
+        )
+        .unwrap();
+        assert_eq!(req.method(), http::Method::Put);
+        assert_eq!(req.req_line.path(), "/files/a");
+        assert_eq!(req.header("host"), Some("example.com"));
+        assert_eq!(req.header("cookie"), Some("a=1; b=2"));
+        assert_eq!(req.get_content_length(), Some(3));
+        assert_eq!(
+            req.trailers.get("x-checksum").map(String::as_str),
+            Some("1")
+        );
+
+        let get = [(":method", "GET"), (":path", "/")];
+        assert!(request(fields(&get), Vec::new(), Vec::new()).is_some());
+        assert!(request(fields(&get[0..1]), Vec::new(), Vec::new()).is_none());
+        for extra in [("Upper", "a"), ("connection", "close"), ("te", "gzip")] {
+            let req = fields(&[get[0], get[1], extra]);
+            assert!(request(req, Vec::new(), Vec::new()).is_none());
+        }
+        let late_pseudo = fields(&[get[0], ("accept", "*/*"), get[1]]);
+        assert!(request(late_pseudo, Vec::new(), Vec::new()).is_none());
+        let wrong_len = fields(&[get[0], get[1], ("content-length", "2")]);
+        assert!(request(wrong_len, b"abc".to_vec(), Vec::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_continuation() {
+        let block = hpack::encode([(":method", "GET"), (":scheme", "http"), (":path", "/abc")]);
+        let mut input = Vec::new();
+        frame(&mut input, FRAME_HEADERS, FLAG_END_STREAM, 1, &block[0..2]);
+        frame(&mut input, FRAME_CONTINUATION, 0, 1, &block[2..4]);
+        frame(
+            &mut input,
+            FRAME_CONTINUATION,
+            FLAG_END_HEADERS,
+            1,
+            &block[4..],
+        );
+        let frames = run(Config::default(), &input).await;
+        let data = frames.iter().find(|f| f.0 == FRAME_DATA).unwrap();
+        assert_eq!(data.3, b"/abc");
+
+        // Anything else partway through a header block breaks the connection
+        let mut input = Vec::new();
+        frame(&mut input, FRAME_HEADERS, FLAG_END_STREAM, 1, &block[0..2]);
+        frame(&mut input, FRAME_PING, 0, 0, b"12345678");
+        let frames = run(Config::default(), &input).await;
+        let goaway = frames.last().unwrap();
+        assert_eq!(goaway.0, FRAME_GOAWAY);
+        assert_eq!(goaway.3[4..], PROTOCOL_ERROR.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_header_block() {
+        let mut input = Vec::new();
+        frame(&mut input, FRAME_HEADERS, FLAG_END_HEADERS, 1, &[0x80]);
+        let frames = run(Config::default(), &input).await;
+        let goaway = frames.last().unwrap();
+        assert_eq!(goaway.0, FRAME_GOAWAY);
+        assert_eq!(goaway.3, [0, 0, 0, 0, 0, 0, 0, COMPRESSION_ERROR as u8]);
+    }
+
+    #[tokio::test]
+    async fn test_flow_control() {
+        let body = vec![b'x'; 100];
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        let task = tokio::spawn(async move {
+            let shutting_down = AtomicBool::new(false);
+            let respond = move |_| {
+                let body = body.clone();
+                async move { http::Response::new(http::Status::Ok).with_body(&body, "text/plain") }
+            };
+            serve_conn(
+                server,
+                PREFACE.to_vec(),
+                &Config::default(),
+                &shutting_down,
+                respond,
+            )
+            .await
+        });
+
+        // Only 30 bytes of window for the stream
+        let mut input = Vec::new();
+        frame(&mut input, FRAME_SETTINGS, 0, 0, &[0, 4, 0, 0, 0, 30]);
+        input.extend_from_slice(&get(1, "/"));
+        client.write_all(&input).await.unwrap();
+        let frames = parse_frames(&read_until_quiet(&mut client).await);
+        let data: Vec<_> = frames.iter().filter(|f| f.0 == FRAME_DATA).collect();
+        assert_eq!(data.len(), 1);
+        assert_eq!((data[0].1, data[0].3.len()), (0, 30));
+
+        let mut input = Vec::new();
+        window_update(&mut input, 1, 100);
+        client.write_all(&input).await.unwrap();
+        let frames = parse_frames(&read_until_quiet(&mut client).await);
+        assert_eq!(frames.len(), 1);
+        assert_eq!((frames[0].1, frames[0].3.len()), (FLAG_END_STREAM, 70));
+
+        drop(client);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_body_too_large() {
+        let config = Config {
+            max_body_size: 4,
+            ..Default::default()
+        };
+        let block = hpack::encode([(":method", "POST"), (":scheme", "http"), (":path", "/")]);
+        let mut input = Vec::new();
+        frame(&mut input, FRAME_HEADERS, FLAG_END_HEADERS, 1, &block);
+        frame(&mut input, FRAME_DATA, 0, 1, b"hello");
+        let frames = run(config, &input).await;
+        let response = frames.iter().find(|f| f.0 == FRAME_HEADERS).unwrap();
+        assert_eq!(fields(&response.3)[0].1, "413");
+        // The client is told to stop sending the body
+        let reset = frames.last().unwrap();
+        assert_eq!((reset.0, reset.2), (FRAME_RST_STREAM, 1));
+        assert_eq!(reset.3, NO_ERROR.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_shutting_down() {
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        let task = tokio::spawn(async move {
+            let shutting_down = AtomicBool::new(true);
+            serve_conn(server, Vec::new(), &Config::default(), &shutting_down, echo).await
+        });
+        client.write_all(PREFACE).await.unwrap();
+        let frames = parse_frames(&read_until_quiet(&mut client).await);
+        assert_eq!(frames[1], (FRAME_GOAWAY, 0, 0, vec![0; 8]));
+        task.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_is_preface() {
+        assert!(is_preface(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04"));
+        assert!(!is_preface(b"PRI * HTTP/2.0\r\n"));
+        assert!(!is_preface(b"GET / HTTP/1.1\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_headers_split() {
+        let mut out = Vec::new();
+        let value = "x".repeat(40_000);
+        headers(
+            &mut out,
+            1,
+            &[(":status", "200"), ("x-big", &value)],
+            true,
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        let frames = parse_frames(&out);
+        let kinds: Vec<_> = frames.iter().map(|f| (f.0, f.1)).collect();
+        assert_eq!(
+            kinds,
+            [
+                (FRAME_HEADERS, FLAG_END_STREAM),
+                (FRAME_CONTINUATION, 0),
+                (FRAME_CONTINUATION, FLAG_END_HEADERS),
+            ]
+        );
+        let block: Vec<u8> = frames.iter().flat_map(|f| f.3.clone()).collect();
+        assert_eq!(fields(&block)[1].1, value);
+    }
+}
@@ -1,2 +1,20 @@
+pub mod access_log;
+pub mod body;
+pub mod compress;
+pub mod config;
+pub mod date;
+pub mod etag;
+pub mod hpack;
 pub mod http;
+pub mod http2;
+pub mod metrics;
+pub mod pool;
+pub mod quota;
+pub mod router;
+pub mod semaphore;
 pub mod ser;
+pub mod server;
+pub mod sha1;
+pub mod sha256;
+pub mod sse;
+pub mod websocket;
@@ -0,0 +1,59 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counters for how well connections are being reused
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections: AtomicUsize,
+    requests: AtomicUsize,
+}
+
+impl Metrics {
+    /// Count a closed connection which served the given number of requests
+    pub fn record_connection(&self, requests: usize) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.requests.fetch_add(requests, Ordering::Relaxed);
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn requests_per_connection(&self) -> f64 {
+        match self.connections() {
+            0 => 0.0,
+            connections => self.requests() as f64 / connections as f64,
+        }
+    }
+
+    /// Plain text report with one `name value` pair per line
+    pub fn report(&self) -> String {
+        format!(
+            "connections {}\nrequests {}\nrequests_per_connection {:.2}\n",
+            self.connections(),
+            self.requests(),
+            self.requests_per_connection()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.requests_per_connection(), 0.0);
+
+        metrics.record_connection(3);
+        metrics.record_connection(0);
+        assert_eq!(
+            metrics.report(),
+            "connections 2\nrequests 3\nrequests_per_connection 1.50\n"
+        );
+    }
+}
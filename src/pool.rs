@@ -0,0 +1,118 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+/// Buffers bigger than this aren't kept, so one huge request doesn't pin its
+/// memory for the life of the server
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+/// Recycles connection read buffers to avoid allocating for every connection
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+            max_buffers,
+        }
+    }
+
+    /// Take an empty buffer from the pool, or a new one if the pool is empty.
+    /// It goes back to the pool when dropped.
+    pub fn get(&self) -> PooledBuffer<'_> {
+        let buf = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        PooledBuffer {
+            buf: Some(buf),
+            pool: self,
+        }
+    }
+
+    /// Number of buffers waiting to be reused
+    pub fn available(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        buf.clear();
+
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buf);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+pub struct PooledBuffer<'a> {
+    // Only None while being dropped
+    buf: Option<Vec<u8>>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        self.buf.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.buf.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.put(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse() {
+        let pool = BufferPool::new(1);
+        {
+            let mut buf = pool.get();
+            buf.extend_from_slice(b"abc");
+            assert_eq!(pool.available(), 0);
+        }
+        assert_eq!(pool.available(), 1);
+
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 3);
+
+        // Only one buffer is kept
+        let other = pool.get();
+        drop(buf);
+        drop(other);
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[test]
+    fn test_large_buffers_dropped() {
+        let pool = BufferPool::new(1);
+        pool.get().resize(MAX_POOLED_CAPACITY + 1, 0);
+        assert_eq!(pool.available(), 0);
+    }
+}
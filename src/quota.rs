@@ -0,0 +1,81 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Total size of the files under each served directory, scanned the first
+/// time it's needed and then kept up to date as files are written and deleted
+#[derive(Debug, Default)]
+pub struct DirSizes {
+    sizes: Mutex<HashMap<PathBuf, u64>>,
+}
+
+impl DirSizes {
+    /// The total size of the files under dir
+    pub fn get(&self, dir: &Path) -> io::Result<u64> {
+        if let Some(size) = self.sizes.lock().unwrap().get(dir) {
+            return Ok(*size);
+        }
+
+        // Scan without holding the lock, since it could take a while
+        let size = scan(dir)?;
+        Ok(*self
+            .sizes
+            .lock()
+            .unwrap()
+            .entry(dir.to_owned())
+            .or_insert(size))
+    }
+
+    /// Record that a file under dir has changed from old_len to new_len bytes.
+    /// A directory which hasn't been scanned yet is left to be scanned later.
+    pub fn update(&self, dir: &Path, old_len: u64, new_len: u64) {
+        if let Some(size) = self.sizes.lock().unwrap().get_mut(dir) {
+            *size = (*size + new_len).saturating_sub(old_len);
+        }
+    }
+}
+
+/// Add up the sizes of all files under dir, including in subdirectories
+fn scan(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            size += scan(&entry.path())?;
+        } else {
+            size += meta.len();
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dir_sizes() {
+        let dir = std::env::temp_dir().join(format!("quota-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        fs::write(dir.join("sub").join("b.txt"), "abc").unwrap();
+
+        let sizes = DirSizes::default();
+        assert_eq!(sizes.get(&dir).unwrap(), 8);
+
+        // Later changes come from updates rather than rescanning
+        fs::write(dir.join("c.txt"), "1234").unwrap();
+        assert_eq!(sizes.get(&dir).unwrap(), 8);
+        sizes.update(&dir, 0, 4);
+        assert_eq!(sizes.get(&dir).unwrap(), 12);
+        sizes.update(&dir, 5, 0);
+        assert_eq!(sizes.get(&dir).unwrap(), 7);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,322 @@
+use std::{fmt, str::FromStr};
+
+use crate::{
+    http::{Method, Request, Response},
+    server::RequestContext,
+};
+
+/// Handles a request, with access to the server's config and shared state
+/// through the context
+pub type Handler = Box<dyn Fn(&RequestContext, &Params) -> Response + Send + Sync>;
+
+/// Values taken from a request path by the `{name}` parts of a route. They're
+/// as they appear in the path, so still percent-encoded.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Params {
+    values: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The value parsed as a T, or None if it's missing or doesn't parse
+    pub fn parse<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name)?.parse().ok()
+    }
+
+    fn push(&mut self, name: &str, value: &str) {
+        self.values.push((name.to_owned(), value.to_owned()));
+    }
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    /// `{name}`, matching one non-empty segment
+    Param(String),
+    /// `{*name}`, matching the rest of the path, slashes and all
+    Rest(String),
+}
+
+/// A route's path, split into segments when the route is registered so that
+/// matching doesn't need to parse it again
+#[derive(Debug)]
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(path: &str) -> Self {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let segments = path
+            .split('/')
+            .map(
+                |s| match s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    Some(name) => match name.strip_prefix('*') {
+                        Some(name) => Segment::Rest(name.to_owned()),
+                        None => Segment::Param(name.to_owned()),
+                    },
+                    None => Segment::Literal(s.to_owned()),
+                },
+            )
+            .collect();
+        Self { segments }
+    }
+
+    /// The parameters from path, if it matches
+    fn matches(&self, path: &str) -> Option<Params> {
+        let mut params = Params::default();
+        let mut remain = path.strip_prefix('/')?;
+        for (i, segment) in self.segments.iter().enumerate() {
+            if let Segment::Rest(name) = segment {
+                params.push(name, remain);
+                return Some(params);
+            }
+
+            let (part, after) = match remain.split_once('/') {
+                Some((part, after)) => (part, Some(after)),
+                None => (remain, None),
+            };
+            match segment {
+                Segment::Literal(literal) if literal == part => (),
+                Segment::Param(name) if !part.is_empty() => params.push(name, part),
+                _ => return None,
+            }
+
+            let is_last = i + 1 == self.segments.len();
+            match after {
+                Some(after) if !is_last => remain = after,
+                None if is_last => return Some(params),
+                _ => return None,
+            }
+        }
+        None
+    }
+}
+
+struct Route<H> {
+    method: Method,
+    pattern: Pattern,
+    handler: H,
+}
+
+/// Handlers matched on method and path, where the path can hold `{name}`
+/// parameters and end with a `{*name}` one. The first route to match wins,
+/// and a HEAD request is handled by a GET route if there's no HEAD one.
+pub struct Router<H = Handler> {
+    routes: Vec<Route<H>>,
+}
+
+impl<H> Router<H> {
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Register a handler of any type, for routers that aren't given requests
+    /// directly
+    pub fn add<P: ToString>(mut self, method: Method, path: P, handler: H) -> Self {
+        self.routes.push(Route {
+            method,
+            pattern: Pattern::parse(&path.to_string()),
+            handler,
+        });
+        self
+    }
+
+    /// The handler for a request method and path, and the path's parameters
+    pub fn find(&self, method: Method, path: &str) -> Option<(&H, Params)> {
+        let exact = self.find_exact(method, path);
+        match method {
+            Method::Head => exact.or_else(|| self.find_exact(Method::Get, path)),
+            _ => exact,
+        }
+    }
+
+    fn find_exact(&self, method: Method, path: &str) -> Option<(&H, Params)> {
+        self.routes
+            .iter()
+            .filter(|r| r.method == method)
+            .find_map(|r| Some((&r.handler, r.pattern.matches(path)?)))
+    }
+
+    /// Whether a handler is registered for the request
+    pub fn matches(&self, req: &Request) -> bool {
+        self.find(req.method(), req.req_line.path()).is_some()
+    }
+
+    /// The methods with a route for path, in the order they were registered.
+    /// HEAD follows GET since GET routes handle it too.
+    pub fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let mut methods = Vec::new();
+        for route in self
+            .routes
+            .iter()
+            .filter(|r| r.pattern.matches(path).is_some())
+        {
+            let implied = (route.method == Method::Get).then_some(Method::Head);
+            for method in [Some(route.method), implied].into_iter().flatten() {
+                if !methods.contains(&method) {
+                    methods.push(method);
+                }
+            }
+        }
+        methods
+    }
+
+    /// Whether any route takes the method, whatever its path
+    pub fn handles_method(&self, method: Method) -> bool {
+        let method = if method == Method::Head {
+            Method::Get
+        } else {
+            method
+        };
+        self.routes.iter().any(|r| r.method == method)
+    }
+}
+
+impl Router {
+    pub fn route<P, F>(self, method: Method, path: P, handler: F) -> Self
+    where
+        P: ToString,
+        F: Fn(&RequestContext, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.add(method, path, Box::new(handler))
+    }
+
+    /// Run the matching handler, if any is registered for the request
+    pub fn handle(&self, ctx: &RequestContext) -> Option<Response> {
+        self.find(ctx.req.method(), ctx.req.req_line.path())
+            .map(|(handler, params)| handler(ctx, &params))
+    }
+}
+
+impl<H> Default for Router<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> fmt::Debug for Router<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.routes.iter().map(|r| (&r.method, &r.pattern)))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        http::Status,
+        server::{AppState, State},
+    };
+
+    #[test]
+    fn test_custom_route() {
+        let router = Router::new().route(Method::Get, "/custom", |_, _| {
+            Response::new(Status::ImATeapot).with_header("X-Custom", "short and stout")
+        });
+        let config = Config::default();
+        let state = State::new(AppState::default());
+        let handle = |req: &Request| router.handle(&RequestContext::new(req, &config, &state));
+
+        let (_, req) = Request::parser(b"GET /custom HTTP/1.1\r\n\r\n").unwrap();
+        let resp = handle(&req).unwrap();
+        assert_eq!(resp.status_line.status, Status::ImATeapot);
+        assert_eq!(resp.header("x-custom"), Some("short and stout"));
+        assert!(router.matches(&req));
+
+        let (_, req) = Request::parser(b"HEAD /custom HTTP/1.1\r\n\r\n").unwrap();
+        assert!(router.matches(&req));
+
+        let (_, req) = Request::parser(b"POST /custom HTTP/1.1\r\n\r\n").unwrap();
+        assert!(handle(&req).is_none());
+        assert!(!router.matches(&req));
+
+        let (_, req) = Request::parser(b"GET /other HTTP/1.1\r\n\r\n").unwrap();
+        assert!(handle(&req).is_none());
+    }
+
+    #[test]
+    fn test_handler_context() {
+        // A handler can reach the config and shared state
+        let router = Router::new().route(Method::Get, "/hits/{n}", |ctx, params| {
+            let hits = ctx.state.hits.load(std::sync::atomic::Ordering::Relaxed);
+            let body = format!(
+                "{} {hits} {}",
+                params.get("n").unwrap(),
+                ctx.config.read_only
+            );
+            Response::new(Status::Ok).with_body(body.as_bytes(), "text/plain")
+        });
+        let config = Config {
+            read_only: true,
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+        state.hits.store(7, std::sync::atomic::Ordering::Relaxed);
+
+        let (_, req) = Request::parser(b"GET /hits/3 HTTP/1.1\r\n\r\n").unwrap();
+        let resp = router
+            .handle(&RequestContext::new(&req, &config, &state))
+            .unwrap();
+        assert_eq!(resp.body.as_deref(), Some(&b"3 7 true"[..]));
+    }
+
+    #[test]
+    fn test_path_params() {
+        let router = Router::new()
+            .add(Method::Get, "/", 0)
+            .add(Method::Get, "/users/{id}/posts/{post}", 1)
+            .add(Method::Get, "/files/{*name}", 2);
+
+        let (route, params) = router.find(Method::Get, "/").unwrap();
+        assert_eq!((*route, params), (0, Params::default()));
+
+        let (route, params) = router.find(Method::Get, "/users/42/posts/hello").unwrap();
+        assert_eq!(*route, 1);
+        assert_eq!(params.parse::<u32>("id"), Some(42));
+        assert_eq!(params.get("post"), Some("hello"));
+        assert_eq!(params.parse::<u32>("post"), None);
+        assert_eq!(params.get("other"), None);
+
+        let (route, params) = router.find(Method::Get, "/files/a/b%20c.txt").unwrap();
+        assert_eq!(*route, 2);
+        assert_eq!(params.get("name"), Some("a/b%20c.txt"));
+        let (_, params) = router.find(Method::Get, "/files/").unwrap();
+        assert_eq!(params.get("name"), Some(""));
+
+        assert!(router.find(Method::Get, "/users//posts/hello").is_none());
+        assert!(router.find(Method::Get, "/users/42/posts/hello/").is_none());
+        assert!(router.find(Method::Get, "/users/42").is_none());
+        assert!(router.find(Method::Get, "/files").is_none());
+        assert!(router.find(Method::Post, "/").is_none());
+    }
+
+    #[test]
+    fn test_allowed_methods() {
+        let router = Router::new()
+            .add(Method::Get, "/items/{id}", ())
+            .add(Method::Delete, "/items/{id}", ())
+            .add(Method::Put, "/items/{*rest}", ())
+            .add(Method::Post, "/items", ());
+
+        assert_eq!(
+            router.allowed_methods("/items/1"),
+            [Method::Get, Method::Head, Method::Delete, Method::Put]
+        );
+        assert_eq!(router.allowed_methods("/items"), [Method::Post]);
+        assert!(router.allowed_methods("/other").is_empty());
+
+        assert!(router.handles_method(Method::Head));
+        assert!(router.handles_method(Method::Post));
+        assert!(!router.handles_method(Method::Patch));
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::{Condvar, Mutex};
+
+/// Limits how many callers can hold a permit at once. Callers block until
+/// one is free rather than failing.
+#[derive(Debug)]
+pub struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait for a permit. It's given back when dropped.
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        Permit { semaphore: self }
+    }
+
+    /// Number of permits not currently held
+    pub fn available(&self) -> usize {
+        *self.available.lock().unwrap()
+    }
+}
+
+impl Default for Semaphore {
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_one_at_a_time() {
+        let semaphore = Semaphore::new(1);
+        let active = AtomicUsize::new(0);
+        let most_active = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let _permit = semaphore.acquire();
+                    let now_active = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_active.fetch_max(now_active, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    active.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        assert_eq!(most_active.load(Ordering::SeqCst), 1);
+        assert_eq!(semaphore.available(), 1);
+    }
+}
@@ -3,9 +3,28 @@ use std::io;
 pub trait Serialize {
     fn serialize<W: io::Write>(&self, writer: &mut W) -> io::Result<()>;
 
-    fn to_bytes(&self) -> Vec<u8> {
+    /// Serialize into a new buffer, reporting any error from the underlying
+    /// data (such as a body read from a file)
+    fn try_to_bytes(&self) -> io::Result<Vec<u8>> {
         let mut output = Vec::new();
-        self.serialize(&mut output).unwrap();
-        output
+        self.serialize(&mut output)?;
+        Ok(output)
+    }
+
+    /// Serialize into a new buffer. Panics if serialization fails, so this is
+    /// only for values which can't fail; use try_to_bytes otherwise.
+    fn to_bytes(&self) -> Vec<u8> {
+        self.try_to_bytes().unwrap()
+    }
+}
+
+/// Items are serialized one after another, such as the responses to several
+/// pipelined requests being written together
+impl<T: Serialize> Serialize for [T] {
+    fn serialize<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        for item in self {
+            item.serialize(writer)?;
+        }
+        Ok(())
     }
 }
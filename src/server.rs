@@ -0,0 +1,4300 @@
+use std::{
+    cmp, fmt, fs,
+    future::Future,
+    io::{self, IoSlice, Read, Write},
+    net::SocketAddr,
+    ops::{self, Deref},
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc},
+    task::JoinSet,
+    time::{sleep, timeout, timeout_at, Instant},
+};
+
+use crate::{
+    access_log::AccessLog,
+    body::{self, BodySender},
+    compress::{self, DecompressError},
+    config::{Config, LogLevel},
+    date::{http_date, parse_http_date},
+    etag, http, http2,
+    metrics::Metrics,
+    pool::{BufferPool, PooledBuffer},
+    quota::DirSizes,
+    router::{Params, Router},
+    semaphore::Semaphore,
+    ser::Serialize,
+    sha1, sha256, sse, websocket,
+};
+
+/// Log a line through the config's logger, if its level is logged
+macro_rules! log_at {
+    ($config:expr, $level:expr, $($arg:tt)*) => {
+        $config.log($level, format_args!($($arg)*))
+    };
+}
+
+/// Log a line about a request, unless the log level is below info as with
+/// --quiet
+macro_rules! log_info {
+    ($config:expr, $($arg:tt)*) => {
+        log_at!($config, LogLevel::Info, $($arg)*)
+    };
+}
+
+/// Shared state handed to every request handler. Cloning is cheap since the
+/// inner value lives behind an `Arc`.
+#[derive(Debug, Default)]
+pub struct State<T>(Arc<T>);
+
+impl<T> State<T> {
+    pub fn new(inner: T) -> Self {
+        Self(Arc::new(inner))
+    }
+}
+
+// Implemented manually so that T doesn't need to be Clone
+impl<T> Clone for State<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T> Deref for State<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AppState {
+    pub hits: AtomicUsize,
+    pub etag_cache: etag::EtagCache,
+    pub router: Router,
+    /// HTML used for the body of error responses
+    pub error_template: Option<String>,
+    pub buffer_pool: BufferPool,
+    pub metrics: Metrics,
+    /// Set while draining for shutdown, when new requests are turned away
+    pub shutting_down: AtomicBool,
+    /// Bounds the number of files open at once
+    pub file_permits: Semaphore,
+    /// Sizes of the served directories, for enforcing max_dir_size
+    pub dir_sizes: DirSizes,
+    /// Takes over connections which start with a TLS handshake
+    pub tls: Option<Arc<dyn TlsAcceptor>>,
+    /// Where a line is written for every request, if anywhere
+    pub access_log: Option<AccessLog>,
+    /// Source of the events streamed from /events, which is only served when
+    /// this is set
+    pub events: Option<broadcast::Sender<String>>,
+}
+
+impl AppState {
+    /// Send an event to every client streaming /events
+    pub fn publish_event(&self, data: String) {
+        if let Some(events) = &self.events {
+            // It's fine for nobody to be listening
+            let _ = events.send(data);
+        }
+    }
+}
+
+/// Everything a handler needs to answer a request: the request itself, who
+/// sent it and the server it arrived at
+#[derive(Clone, Copy, Debug)]
+pub struct RequestContext<'a> {
+    pub req: &'a http::Request,
+    /// The client's address, when the connection has one
+    pub peer_addr: Option<SocketAddr>,
+    pub config: &'a Config,
+    pub state: &'a State<AppState>,
+}
+
+impl<'a> RequestContext<'a> {
+    pub fn new(req: &'a http::Request, config: &'a Config, state: &'a State<AppState>) -> Self {
+        Self {
+            req,
+            peer_addr: None,
+            config,
+            state,
+        }
+    }
+
+    pub fn with_peer_addr(mut self, peer_addr: Option<SocketAddr>) -> Self {
+        self.peer_addr = peer_addr;
+        self
+    }
+}
+
+/// Serves connections which turned out to be TLS rather than plaintext HTTP,
+/// typically by completing the handshake, offering ALPN_PROTOCOLS in it, and
+/// then calling handle_tls_conn with the protocol the client picked.
+pub trait TlsAcceptor: fmt::Debug + Send + Sync {
+    fn serve(
+        &self,
+        stream: TcpStream,
+        config: Arc<Config>,
+        state: State<AppState>,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+}
+
+/// The protocols to offer over ALPN, most preferred first
+pub const ALPN_PROTOCOLS: [&[u8]; 2] = [http2::ALPN_ID, b"http/1.1"];
+
+/// Whether the first bytes of a connection are a TLS handshake record, as
+/// opposed to the start of an HTTP request line
+pub fn is_tls_handshake(first_bytes: &[u8]) -> bool {
+    first_bytes.first() == Some(&0x16)
+}
+
+/// Accept connections until shutdown completes, serving each one on its own
+/// task. Then drain: new connections are turned away with a 503 while the
+/// open ones finish, for up to request_timeout before they're dropped.
+pub async fn serve<F>(
+    listener: TcpListener,
+    config: Arc<Config>,
+    state: State<AppState>,
+    shutdown: F,
+) where
+    F: Future<Output = ()>,
+{
+    let mut conns = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => spawn_conn(&mut conns, accepted, &config, &state),
+            // Reap finished connections so the set doesn't keep growing
+            Some(_) = conns.join_next() => (),
+            () = &mut shutdown => break,
+        }
+    }
+
+    log_info!(config, "Shutting down, {} connections open", conns.len());
+    state.shutting_down.store(true, Ordering::Relaxed);
+    let drained = sleep(config.request_timeout);
+    tokio::pin!(drained);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => spawn_conn(&mut conns, accepted, &config, &state),
+            joined = conns.join_next() => {
+                if joined.is_none() {
+                    break;
+                }
+            }
+            () = &mut drained => {
+                log_info!(config, "Dropping {} connections still open", conns.len());
+                break;
+            }
+        }
+    }
+}
+
+fn spawn_conn(
+    conns: &mut JoinSet<()>,
+    accepted: io::Result<(TcpStream, SocketAddr)>,
+    config: &Arc<Config>,
+    state: &State<AppState>,
+) {
+    match accepted {
+        Ok((stream, _)) => {
+            log_at!(config, LogLevel::Debug, "Accepted new connection");
+            let c = Arc::clone(config); // Clone before move
+            let s = state.clone();
+            conns.spawn(async move {
+                match dispatch_conn(stream, Arc::clone(&c), s).await {
+                    Ok(_) => log_at!(c, LogLevel::Debug, "Connection handled successfully"),
+                    Err(e) => log_at!(c, LogLevel::Error, "Error handling connection: {e}"),
+                }
+            });
+        }
+        Err(e) => log_at!(
+            config,
+            LogLevel::Error,
+            "Failed to accept new connection: {e}"
+        ),
+    }
+}
+
+/// Accept connections forever, answering every request on them with a
+/// redirect to the same URL over HTTPS
+pub async fn serve_redirects(listener: TcpListener, config: Arc<Config>) {
+    loop {
+        match listener.accept().await {
+            Ok((mut stream, _)) => {
+                let c = Arc::clone(&config); // Clone before move
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let result = match read_request(&mut stream, &mut buf, &c).await {
+                        Ok(Some(req)) => {
+                            let resp = redirect_to_https(&req, &c).finalize(false);
+                            write_response(&mut stream, &resp).await.map_err(Into::into)
+                        }
+                        Ok(None) => Ok(()),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        log_at!(c, LogLevel::Error, "Error redirecting connection: {e}");
+                    }
+                });
+            }
+            Err(e) => log_at!(
+                config,
+                LogLevel::Error,
+                "Failed to accept new connection: {e}"
+            ),
+        }
+    }
+}
+
+/// A permanent redirect to the https URL for the request. The port is dropped
+/// from the host since the HTTPS one will be different.
+fn redirect_to_https(req: &http::Request, config: &Config) -> http::Response {
+    let Some(host) = req.host_only() else {
+        log_info!(config, "  Redirect - fail, no host");
+        return http::Response::new(http::Status::BadRequest);
+    };
+
+    let location = format!("https://{host}{}", req.req_line.target.path_and_query());
+    log_info!(config, "  Redirect - {location}");
+    http::Response::new(http::Status::MovedPermanently).with_header("Location", location)
+}
+
+/// Peek at the start of a connection to decide whether it's TLS, then serve
+/// it accordingly. TLS connections are treated as plaintext if no acceptor is
+/// configured.
+async fn dispatch_conn(
+    stream: TcpStream,
+    config: Arc<Config>,
+    state: State<AppState>,
+) -> anyhow::Result<()> {
+    let mut first_bytes = [0u8; 1];
+    let peeked = match timeout(config.keep_alive_timeout, stream.peek(&mut first_bytes)).await {
+        Ok(result) => result?,
+        Err(_) => return Ok(()),
+    };
+
+    match &state.tls {
+        Some(tls) if is_tls_handshake(&first_bytes[0..peeked]) => {
+            let tls = Arc::clone(tls);
+            tls.serve(stream, config, state).await
+        }
+        _ => {
+            let peer_addr = stream.peer_addr().ok();
+            handle_conn(stream, peer_addr, config, state).await
+        }
+    }
+}
+
+/// Serve requests on a connection until the client closes it, asks for it
+/// to be closed, goes idle, or reaches the per-connection request limit.
+pub async fn handle_conn<S>(
+    stream: S,
+    peer_addr: Option<SocketAddr>,
+    config: Arc<Config>,
+    state: State<AppState>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if state.shutting_down.load(Ordering::Relaxed) {
+        return refuse_conn(stream, &config).await;
+    }
+
+    let mut conn = ConnectionState::new(stream, peer_addr, &config, &state);
+    let mut result = Ok(());
+    while !conn.is_closing() && result.is_ok() {
+        result = conn.step().await;
+    }
+    state.metrics.record_connection(conn.served);
+    result
+}
+
+/// Serve a connection once its TLS handshake is done, in whichever protocol
+/// was agreed over ALPN. Without ALPN that's HTTP/1.1.
+pub async fn handle_tls_conn<S>(
+    stream: S,
+    alpn: Option<&[u8]>,
+    peer_addr: Option<SocketAddr>,
+    config: Arc<Config>,
+    state: State<AppState>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if alpn != Some(http2::ALPN_ID) {
+        return handle_conn(stream, peer_addr, config, state).await;
+    }
+
+    let served = AtomicUsize::new(0);
+    let result = serve_h2_conn(stream, Vec::new(), peer_addr, &config, &state, &served).await;
+    state.metrics.record_connection(served.into_inner());
+    result
+}
+
+/// Serve an HTTP/2 connection, counting its requests in served. buf holds
+/// whatever has already been read of it.
+async fn serve_h2_conn<S>(
+    stream: S,
+    buf: Vec<u8>,
+    peer_addr: Option<SocketAddr>,
+    config: &Arc<Config>,
+    state: &State<AppState>,
+    served: &AtomicUsize,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    http2::serve_conn(stream, buf, config, &state.shutting_down, |req| {
+        served.fetch_add(1, Ordering::Relaxed);
+        serve_h2_request(req, peer_addr, Arc::clone(config), state.clone())
+    })
+    .await
+}
+
+/// Answer a request from an HTTP/2 stream the same way as one read from an
+/// HTTP/1.1 connection
+async fn serve_h2_request(
+    mut req: http::Request,
+    peer_addr: Option<SocketAddr>,
+    config: Arc<Config>,
+    state: State<AppState>,
+) -> http::Response {
+    let (req, response) = if let Err(status) = decode_body(&mut req, &config) {
+        (req, http::Response::new(status))
+    } else {
+        let c = Arc::clone(&config); // Clone before move
+        let s = state.clone();
+        let handled = tokio::task::spawn_blocking(move || {
+            let ctx = RequestContext::new(&req, &c, &s).with_peer_addr(peer_addr);
+            let response = handle_request(&ctx);
+            (req, response)
+        });
+        match timeout(config.request_timeout, handled).await {
+            Ok(Ok(handled)) => handled,
+            Ok(Err(e)) => {
+                log_at!(config, LogLevel::Error, "Error handling request: {e}");
+                return finish_response(http::Response::new(http::Status::Internal), &config, true);
+            }
+            Err(_) => {
+                log_info!(config, "  Request - fail, deadline exceeded");
+                let response = http::Response::new(http::Status::ServiceUnavailable);
+                return finish_response(response, &config, true);
+            }
+        }
+    };
+    if let Some(access_log) = &state.access_log {
+        access_log.record(peer_addr, &req, &response);
+    }
+
+    // The stream's body carries the events, leaving the connection free for
+    // other requests
+    let mut response = response;
+    if req.method() == http::Method::Get && sse::is_event_stream(&response) {
+        if let Some(events) = &state.events {
+            response = with_events(response, events.subscribe());
+        }
+    }
+    finish_response(response, &config, true)
+}
+
+/// Stream events as the body of an event stream response, until there are no
+/// more or the stream is closed
+fn with_events(resp: http::Response, mut events: broadcast::Receiver<String>) -> http::Response {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            let data = match events.recv().await {
+                Ok(data) => data,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            if tx.send(Bytes::from(sse::frame(&data))).await.is_err() {
+                return;
+            }
+        }
+    });
+    resp.with_streamed_body(rx)
+}
+
+/// Seconds a client turned away during shutdown is told to wait
+const SHUTDOWN_RETRY_AFTER: u64 = 5;
+
+/// Request bodies longer than this are handed to an upload as they arrive,
+/// rather than read first
+const STREAMED_BODY_SIZE: usize = 64 * 1024;
+
+/// Answer a connection's first request with a 503 telling the client to try
+/// again later, then close it
+async fn refuse_conn<S>(stream: S, config: &Config) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = stream;
+    let mut buf = Vec::new();
+    if read_request(&mut stream, &mut buf, config).await?.is_none() {
+        return Ok(());
+    }
+
+    log_info!(config, "  Request - refused, shutting down");
+    let response = http::Response::new(http::Status::ServiceUnavailable)
+        .with_header("Retry-After", SHUTDOWN_RETRY_AFTER);
+    let response = finish_response(response, config, false);
+    match timeout(config.write_timeout, write_response(&mut stream, &response)).await {
+        Ok(result) => result?,
+        Err(_) => log_info!(config, "  Response - fail, write timed out"),
+    }
+    Ok(())
+}
+
+/// Where a connection is in its request loop
+#[derive(Debug, Eq, PartialEq)]
+enum Phase {
+    /// Waiting for the next request to start arriving
+    ReadingRequest,
+    /// Reading the rest of a request and handling it, which must finish by
+    /// the deadline
+    Handling { deadline: Instant },
+    /// Writing the pending responses, after which the connection is closed
+    /// if close is set
+    Writing { deadline: Instant, close: bool },
+    /// Writing events to the client as they arrive, until there are no more
+    Streaming,
+    /// Serving HTTP/2, which the client started the connection with
+    Http2,
+    /// Finished with the connection
+    Closing,
+}
+
+/// The request loop for handle_conn, advanced one phase at a time by step
+struct ConnectionState<'a, S> {
+    stream: S,
+    peer_addr: Option<SocketAddr>,
+    config: &'a Arc<Config>,
+    state: &'a State<AppState>,
+    /// Holds bytes which have been read but not yet parsed, which may include
+    /// the start of pipelined requests
+    buf: PooledBuffer<'a>,
+    /// Responses waiting to be written together
+    pending: Vec<http::Response>,
+    /// Where events come from once the connection is streaming them
+    events: Option<broadcast::Receiver<String>>,
+    /// Requests handled so far. This is updated as they are handled so that
+    /// it's right even if the connection fails partway through.
+    served: usize,
+    phase: Phase,
+}
+
+impl<'a, S> ConnectionState<'a, S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(
+        stream: S,
+        peer_addr: Option<SocketAddr>,
+        config: &'a Arc<Config>,
+        state: &'a State<AppState>,
+    ) -> Self {
+        Self {
+            stream,
+            peer_addr,
+            config,
+            state,
+            buf: state.buffer_pool.get(),
+            pending: Vec::new(),
+            events: None,
+            served: 0,
+            phase: Phase::ReadingRequest,
+        }
+    }
+
+    fn is_closing(&self) -> bool {
+        self.phase == Phase::Closing
+    }
+
+    /// Do the work of the current phase and move on to the next one
+    async fn step(&mut self) -> anyhow::Result<()> {
+        self.phase = match self.phase {
+            Phase::ReadingRequest => self.read_request().await?,
+            Phase::Handling { deadline } => self.handle(deadline).await?,
+            Phase::Writing { deadline, close } => self.write(deadline, close).await?,
+            Phase::Streaming => self.stream_event().await?,
+            Phase::Http2 => self.serve_h2().await?,
+            Phase::Closing => Phase::Closing,
+        };
+        Ok(())
+    }
+
+    async fn read_request(&mut self) -> anyhow::Result<Phase> {
+        if self.buf.is_empty()
+            && !wait_for_request(&mut self.stream, &mut self.buf, self.config).await?
+        {
+            return Ok(Phase::Closing);
+        }
+
+        // A client may start a plaintext connection in HTTP/2, assuming it's
+        // spoken here. Wait for enough to tell whether that's what this is.
+        if self.served == 0 {
+            while self.buf.len() < http2::PREFACE.len() && http2::PREFACE.starts_with(&self.buf) {
+                if !wait_for_request(&mut self.stream, &mut self.buf, self.config).await? {
+                    return Ok(Phase::Closing);
+                }
+            }
+            if http2::is_preface(&self.buf) {
+                return Ok(Phase::Http2);
+            }
+        }
+
+        // The deadline starts once the request starts to arrive, so time spent
+        // idle between requests doesn't count against it
+        let deadline = Instant::now() + self.config.request_timeout;
+        Ok(Phase::Handling { deadline })
+    }
+
+    async fn handle(&mut self, deadline: Instant) -> anyhow::Result<Phase> {
+        let config = self.config;
+        let served_request = serve_request(
+            &mut self.stream,
+            &mut self.buf,
+            self.peer_addr,
+            config,
+            self.state,
+        );
+        let mut req_is_get = false;
+        let (response, close) = match timeout_at(deadline, served_request).await {
+            Ok(Err(e)) if e.is::<BodyTooSlow>() => {
+                log_info!(config, "  Request - fail, {e}");
+                (http::Response::error(http::Status::RequestTimeout), true)
+            }
+            Ok(Err(e)) if e.is::<http::InvalidChunk>() => {
+                log_info!(config, "  Request - fail, {e}");
+                (http::Response::error(http::Status::BadRequest), true)
+            }
+            Ok(Err(e)) if e.is::<TooLarge>() => {
+                log_info!(config, "  Request - fail, {e}");
+                let status = e.downcast_ref::<TooLarge>().map(TooLarge::status);
+                (http::Response::error(status.unwrap_or_default()), true)
+            }
+            Ok(result) => {
+                let Some((req, response)) = result? else {
+                    return Ok(Phase::Closing);
+                };
+                self.served += 1;
+                req_is_get = req.method() == http::Method::Get;
+                if let Some(access_log) = &self.state.access_log {
+                    access_log.record(self.peer_addr, &req, &response);
+                }
+
+                // After some errors the rest of the input can't be trusted to
+                // line up with a request boundary, so don't try to read any
+                // more. The same goes for a body that was turned away unread.
+                let close = wants_close(&req)
+                    || (req.body.is_none() && req.streamed_body.is_none())
+                    || self.state.shutting_down.load(Ordering::Relaxed)
+                    || (response.streamed_body.is_some() && !response.is_chunked())
+                    || self.served >= config.max_requests
+                    || config
+                        .close_on_status
+                        .contains(&response.status_line.status.code());
+                (response, close)
+            }
+            Err(_) => {
+                log_info!(config, "  Request - fail, deadline exceeded");
+                (http::Response::new(http::Status::ServiceUnavailable), true)
+            }
+        };
+
+        let mut deadline = deadline;
+        if let Some(delay) = config.response_delay.filter(|_| config.debug_routes) {
+            sleep(delay).await;
+            deadline += delay;
+        }
+
+        // An event stream's body lasts as long as the connection, so nothing
+        // else can be sent after it
+        if req_is_get && sse::is_event_stream(&response) {
+            if let Some(events) = &self.state.events {
+                self.events = Some(events.subscribe());
+                self.pending.push(finish_response(response, config, false));
+                return Ok(Phase::Writing {
+                    deadline,
+                    close: true,
+                });
+            }
+        }
+
+        // After a 101 the connection speaks another protocol, which isn't
+        // served here, so what follows mustn't be read as more requests. The
+        // response keeps its Connection: Upgrade rather than saying close.
+        if response.status_line.status == http::Status::SwitchingProtocols {
+            self.pending.push(finish_response(response, config, true));
+            return Ok(Phase::Writing {
+                deadline,
+                close: true,
+            });
+        }
+
+        let streamed = response.streamed_body.is_some();
+        self.pending.push(finish_response(response, config, !close));
+
+        // While the next pipelined request is already here, hold this response
+        // back so that they're written together. A streamed body has to go
+        // last, so isn't held back.
+        if !close && !streamed && request_buffered(&self.buf, config) {
+            return Ok(Phase::ReadingRequest);
+        }
+        Ok(Phase::Writing { deadline, close })
+    }
+
+    async fn write(&mut self, deadline: Instant, close: bool) -> anyhow::Result<Phase> {
+        // Only the last response can have a streamed body. Its head goes out
+        // with the others, then the body follows as it's produced.
+        let streamed_body = self.pending.last_mut().and_then(|resp| {
+            let streamed_body = resp.streamed_body.take();
+            streamed_body.filter(|_| resp.status_line.status.allows_body())
+        });
+
+        // Nothing more can be sent once a write has stalled, so give up on
+        // the connection
+        let deadline = deadline.min(Instant::now() + self.config.write_timeout);
+        match timeout_at(deadline, write_responses(&mut self.stream, &self.pending)).await {
+            Ok(result) => result?,
+            Err(_) => {
+                log_info!(self.config, "  Response - fail, write timed out");
+                return Ok(Phase::Closing);
+            }
+        }
+        if let (Some(chunks), Some(resp)) = (streamed_body, self.pending.last()) {
+            let write_timeout = self.config.write_timeout;
+            match write_streamed_body(&mut self.stream, chunks, resp, write_timeout).await {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    log_info!(self.config, "  Response - fail, write timed out");
+                    return Ok(Phase::Closing);
+                }
+                result => result?,
+            }
+        }
+        self.pending.clear();
+
+        Ok(if self.events.is_some() {
+            Phase::Streaming
+        } else if close {
+            Phase::Closing
+        } else {
+            Phase::ReadingRequest
+        })
+    }
+
+    async fn serve_h2(&mut self) -> anyhow::Result<Phase> {
+        let buf = std::mem::take(&mut *self.buf);
+        let served = AtomicUsize::new(0);
+        let result = serve_h2_conn(
+            &mut self.stream,
+            buf,
+            self.peer_addr,
+            self.config,
+            self.state,
+            &served,
+        )
+        .await;
+        self.served += served.into_inner();
+        result.map(|()| Phase::Closing)
+    }
+
+    async fn stream_event(&mut self) -> anyhow::Result<Phase> {
+        let Some(events) = &mut self.events else {
+            return Ok(Phase::Closing);
+        };
+        let data = match events.recv().await {
+            Ok(data) => data,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                log_info!(
+                    self.config,
+                    "  Events - skipped {skipped} for a slow client"
+                );
+                return Ok(Phase::Streaming);
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(Phase::Closing),
+        };
+
+        let frame = sse::frame(&data);
+        match timeout(
+            self.config.write_timeout,
+            self.stream.write_all(frame.as_bytes()),
+        )
+        .await
+        {
+            Ok(result) => result?,
+            Err(_) => {
+                log_info!(self.config, "  Events - fail, write timed out");
+                return Ok(Phase::Closing);
+            }
+        }
+        Ok(Phase::Streaming)
+    }
+}
+
+/// Whether buf holds the whole of the next request
+fn request_buffered(buf: &[u8], config: &Config) -> bool {
+    matches!(
+        http::Request::parse_streaming(buf, line_endings(config)),
+        Ok(http::Parsed::Complete(..))
+    )
+}
+
+/// Wait for the first bytes of the next request. Returns false if the
+/// connection is closed or idles out first.
+async fn wait_for_request<S>(stream: &mut S, buf: &mut Vec<u8>, config: &Config) -> io::Result<bool>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut chunk = [0u8; 1024];
+    match timeout(config.keep_alive_timeout, stream.read(&mut chunk)).await {
+        Ok(result) => {
+            let bytes_read = result?;
+            buf.extend_from_slice(&chunk[0..bytes_read]);
+            Ok(bytes_read > 0)
+        }
+        Err(_) => Ok(false),
+    }
+}
+
+/// Read one request and build the response to it. The handler runs on the
+/// blocking pool so that a slow one can't hold up the deadline.
+async fn serve_request<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    peer_addr: Option<SocketAddr>,
+    config: &Arc<Config>,
+    state: &State<AppState>,
+) -> anyhow::Result<Option<(http::Request, http::Response)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some((mut req, head_len)) = read_request_head(stream, buf, config).await? else {
+        return Ok(None);
+    };
+
+    // A client waiting on Expect: 100-continue needn't send a body that's
+    // only going to be turned away, so answer without it
+    let ctx = RequestContext::new(&req, config, state);
+    if buf.len() == head_len && waiting_to_continue(&req) && rejected_before_body(&ctx) {
+        // Only the head is in buf
+        buf.clear();
+    } else if streams_body(&ctx) {
+        return serve_streamed(stream, buf, head_len, req, peer_addr, config, state).await;
+    } else {
+        read_request_body(stream, buf, head_len, &mut req, config).await?;
+    }
+
+    if let Err(status) = decode_body(&mut req, config) {
+        return Ok(Some((req, http::Response::new(status))));
+    }
+
+    let config = Arc::clone(config);
+    let state = state.clone();
+    let handled = tokio::task::spawn_blocking(move || {
+        let ctx = RequestContext::new(&req, &config, &state).with_peer_addr(peer_addr);
+        let response = handle_request(&ctx);
+        (req, response)
+    });
+    Ok(Some(handled.await?))
+}
+
+/// Serve a request while its body is still arriving, handing the body over a
+/// frame at a time as it's read. Any of it the handler leaves is discarded.
+async fn serve_streamed<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    head_len: usize,
+    mut req: http::Request,
+    peer_addr: Option<SocketAddr>,
+    config: &Arc<Config>,
+    state: &State<AppState>,
+) -> anyhow::Result<Option<(http::Request, http::Response)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let body_len = req.get_content_length().filter(|_| !req.is_chunked());
+    if body_len.is_some_and(|len| len > config.max_body_size) {
+        return Err(TooLarge::Body(config.max_body_size).into());
+    }
+    if buf.len() == head_len && waiting_to_continue(&req) {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+    }
+    buf.drain(0..head_len);
+
+    let (frames, body) = body::channel();
+    req.streamed_body = Some(body);
+    let handled = {
+        let config = Arc::clone(config);
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || {
+            let ctx = RequestContext::new(&req, &config, &state).with_peer_addr(peer_addr);
+            let response = handle_request(&ctx);
+            if let Some(body) = &req.streamed_body {
+                body.close();
+            }
+            (req, response)
+        })
+    };
+
+    let read = match body_len {
+        Some(len) => stream_body(stream, buf, len, &frames, config).await,
+        None => stream_chunked_body(stream, buf, &frames, config).await,
+    };
+    // Without the end of the body, the handler sees that it was cut short
+    if read.is_ok() {
+        frames.finish().await;
+    } else {
+        drop(frames);
+    }
+    let handled = handled.await?;
+    read?;
+    Ok(Some(handled))
+}
+
+/// Pass on a body of len bytes, which starts at the start of buf, leaving
+/// anything after it in buf
+async fn stream_body<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    len: usize,
+    frames: &BodySender,
+    config: &Config,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let body_started = Instant::now();
+    let mut remaining = len;
+    while remaining > 0 {
+        let frame_len = remaining.min(body::FRAME_SIZE);
+        while buf.len() < frame_len {
+            if !read_more(stream, buf, config).await? {
+                anyhow::bail!("connection closed partway through request");
+            }
+            check_body_rate(len - remaining + buf.len(), body_started.elapsed(), config)?;
+        }
+        let frame = Bytes::copy_from_slice(&buf[0..frame_len]);
+        buf.drain(0..frame_len);
+        remaining -= frame_len;
+        // Once the handler is done, the rest is read only to reach the
+        // next request
+        frames.send(frame).await;
+    }
+    Ok(())
+}
+
+/// Pass on a chunked body as it's decoded, where it starts at the start of
+/// buf, leaving anything after it in buf
+async fn stream_chunked_body<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    frames: &BodySender,
+    config: &Config,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut decoder = http::ChunkedDecoder::default();
+    let body_started = Instant::now();
+    let mut sent = 0;
+    let mut frame = Vec::new();
+    loop {
+        let done = match decoder.decode(buf)? {
+            http::Chunked::Complete(body, _, len) => {
+                buf.drain(0..len);
+                frame.extend_from_slice(&body);
+                true
+            }
+            http::Chunked::NeedMore => {
+                frame.append(&mut decoder.take_decoded(buf));
+                false
+            }
+        };
+        if sent + frame.len() > config.max_body_size {
+            return Err(TooLarge::Body(config.max_body_size).into());
+        }
+        while frame.len() >= body::FRAME_SIZE || (done && !frame.is_empty()) {
+            let part: Vec<u8> = frame.drain(0..frame.len().min(body::FRAME_SIZE)).collect();
+            sent += part.len();
+            frames.send(Bytes::from(part)).await;
+        }
+        if done {
+            return Ok(());
+        }
+
+        if !read_more(stream, buf, config).await? {
+            anyhow::bail!("connection closed partway through request");
+        }
+        check_body_rate(
+            sent + frame.len() + buf.len(),
+            body_started.elapsed(),
+            config,
+        )?;
+    }
+}
+
+/// Read from the stream until buf holds a complete request, then remove and
+/// return it. Returns None if the connection is closed or idles out between
+/// requests.
+async fn read_request<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    config: &Config,
+) -> anyhow::Result<Option<http::Request>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some((mut req, head_len)) = read_request_head(stream, buf, config).await? else {
+        return Ok(None);
+    };
+    read_request_body(stream, buf, head_len, &mut req, config).await?;
+    Ok(Some(req))
+}
+
+/// Read from the stream until buf holds a request's head, and parse it. The
+/// head is left in buf, and its length returned along with the request.
+/// Returns None if the connection is closed or idles out between requests.
+async fn read_request_head<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    config: &Config,
+) -> anyhow::Result<Option<(http::Request, usize)>>
+where
+    S: AsyncRead + Unpin,
+{
+    let line_endings = line_endings(config);
+    let head_len = loop {
+        let head_len = http::head_len(buf, line_endings);
+        if head_len.unwrap_or(buf.len()) > config.max_head_size {
+            return Err(TooLarge::Head(config.max_head_size).into());
+        }
+        if let Some(head_len) = head_len {
+            break head_len;
+        }
+        if !read_more(stream, buf, config).await? {
+            return Ok(None);
+        }
+    };
+    let head = http::normalize_line_endings(&buf[0..head_len], line_endings);
+    let (req_line, headers, _) = http::parse_request_head(&head)
+        .map_err(|err| err.map(|e| e.input.to_owned()))?
+        .1;
+    let req = http::Request {
+        req_line,
+        headers,
+        body: None,
+        trailers: http::RequestHeaders::new(),
+        streamed_body: None,
+    };
+    Ok(Some((req, head_len)))
+}
+
+/// Read as much body as the request's head says, then remove the request
+/// from buf, where its head is the first head_len bytes
+async fn read_request_body<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    head_len: usize,
+    req: &mut http::Request,
+    config: &Config,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if body_until_close(req) {
+        read_until_close(stream, buf, head_len + config.max_body_size, config).await?;
+        let body = buf.split_off(head_len);
+        buf.clear();
+        req.headers
+            .insert(String::from("content-length"), body.len().to_string());
+        req.body = Some(body);
+        return Ok(());
+    }
+
+    // Turn away a body that's too big before asking for it
+    let body_len = if req.is_chunked() {
+        0
+    } else {
+        req.get_content_length().unwrap_or(0)
+    };
+    if body_len > config.max_body_size {
+        return Err(TooLarge::Body(config.max_body_size).into());
+    }
+
+    // Tell a client waiting on Expect: 100-continue to send the body. If some
+    // of the body is already here then it isn't waiting, and a 100 Continue
+    // would only confuse it.
+    if buf.len() == head_len && waiting_to_continue(req) {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+    }
+
+    if req.is_chunked() {
+        read_chunked_body(stream, buf, head_len, req, config).await?;
+        return Ok(());
+    }
+
+    let body_end = head_len + body_len;
+    let body_started = Instant::now();
+    while buf.len() < body_end {
+        read_more(stream, buf, config).await?;
+        check_body_rate(buf.len() - head_len, body_started.elapsed(), config)?;
+    }
+    req.body = Some(buf[head_len..body_end].to_vec());
+    buf.drain(0..body_end);
+    Ok(())
+}
+
+/// Read a chunked body which starts at head_len in buf, then remove the
+/// request from buf
+async fn read_chunked_body<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    head_len: usize,
+    req: &mut http::Request,
+    config: &Config,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut decoder = http::ChunkedDecoder::default();
+    let body_started = Instant::now();
+    loop {
+        if let http::Chunked::Complete(body, trailers, len) = decoder.decode(&buf[head_len..])? {
+            req.set_chunked_body(body, trailers);
+            buf.drain(0..head_len + len);
+            return Ok(());
+        }
+        // The framing makes the encoded body a little longer than the limit
+        // needs, which doesn't matter for turning away huge ones
+        if buf.len() - head_len > config.max_body_size {
+            return Err(TooLarge::Body(config.max_body_size).into());
+        }
+        read_more(stream, buf, config).await?;
+        check_body_rate(buf.len() - head_len, body_started.elapsed(), config)?;
+    }
+}
+
+/// A request went over one of the configured size limits
+#[derive(Debug, thiserror::Error)]
+enum TooLarge {
+    #[error("request head longer than {0} bytes")]
+    Head(usize),
+    #[error("request body longer than {0} bytes")]
+    Body(usize),
+}
+
+impl TooLarge {
+    fn status(&self) -> http::Status {
+        match self {
+            Self::Head(_) => http::Status::RequestHeaderFieldsTooLarge,
+            Self::Body(_) => http::Status::PayloadTooLarge,
+        }
+    }
+}
+
+/// The request body arrived more slowly than min_body_rate allows, as a
+/// slowloris attack would send it to hold the connection open
+#[derive(Debug, thiserror::Error)]
+#[error("body arriving slower than {0} bytes/s")]
+struct BodyTooSlow(u64);
+
+/// Fail if received bytes of body is too few for the time it's taken, once
+/// it's been arriving for long enough to tell
+fn check_body_rate(received: usize, elapsed: Duration, config: &Config) -> Result<(), BodyTooSlow> {
+    match config.min_body_rate {
+        Some(min_rate)
+            if elapsed >= config.body_rate_window
+                && (received as f64) < min_rate as f64 * elapsed.as_secs_f64() =>
+        {
+            Err(BodyTooSlow(min_rate))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Read the next bytes of a request into buf. Returns false if the
+/// connection is closed or idles out before any of the request has arrived.
+async fn read_more<S>(stream: &mut S, buf: &mut Vec<u8>, config: &Config) -> anyhow::Result<bool>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut chunk = [0u8; 1024];
+    let bytes_read = match timeout(config.keep_alive_timeout, stream.read(&mut chunk)).await {
+        Ok(result) => result?,
+        Err(_) if buf.is_empty() => return Ok(false),
+        Err(_) => anyhow::bail!("timed out reading request"),
+    };
+    if bytes_read == 0 {
+        if buf.is_empty() {
+            return Ok(false);
+        }
+        anyhow::bail!("connection closed partway through request");
+    }
+    buf.extend_from_slice(&chunk[0..bytes_read]);
+    Ok(true)
+}
+
+/// The line endings accepted in requests
+fn line_endings(config: &Config) -> http::LineEndings {
+    if config.lenient_line_endings {
+        http::LineEndings::Lenient
+    } else {
+        http::LineEndings::Strict
+    }
+}
+
+/// Whether a request asks for a 100 Continue before its body is sent
+fn waiting_to_continue(req: &http::Request) -> bool {
+    req.expect_continue()
+        && req.req_line.version.at_least(1, 1)
+        && (req.is_chunked() || req.get_content_length().is_some_and(|len| len > 0))
+}
+
+/// Whether the request body runs until the client closes the connection. An
+/// HTTP/1.0 client may send a body that way, without a Content-Length.
+fn body_until_close(req: &http::Request) -> bool {
+    !req.req_line.version.at_least(1, 1)
+        && !has_connection_option(req, "keep-alive")
+        && matches!(
+            req.method(),
+            http::Method::Post | http::Method::Put | http::Method::Patch
+        )
+        && req.header("content-length").is_none()
+        && req.header("transfer-encoding").is_none()
+}
+
+/// Read everything up to the end of the stream into buf, failing if that
+/// makes buf longer than limit
+async fn read_until_close<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    limit: usize,
+    config: &Config,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut chunk = [0u8; 1024];
+    loop {
+        let bytes_read = match timeout(config.keep_alive_timeout, stream.read(&mut chunk)).await {
+            Ok(result) => result?,
+            Err(_) => anyhow::bail!("timed out reading request body"),
+        };
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[0..bytes_read]);
+        if buf.len() > limit {
+            return Err(TooLarge::Body(config.max_body_size).into());
+        }
+    }
+}
+
+/// Write a response without copying the body into the serialized headers.
+/// Both go out in one vectored write when the stream takes it all at once.
+async fn write_response<S>(stream: &mut S, resp: &http::Response) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    // Chunked bodies are framed as they're serialized, so can't go out as is
+    let mut head = Vec::new();
+    if resp.is_chunked() || resp.serialize_head(&mut head).is_err() {
+        return stream.write_all(&serialize_or_500(resp)).await;
+    }
+    let body = match &resp.body {
+        Some(body) if resp.status_line.status.allows_body() => body.as_slice(),
+        _ => &[],
+    };
+
+    write_all_vectored(stream, &mut [IoSlice::new(&head), IoSlice::new(body)]).await
+}
+
+/// Write all of the slices in order. A vectored write may only take part of
+/// them, so keep going from wherever the last one stopped.
+async fn write_all_vectored<S>(stream: &mut S, mut slices: &mut [IoSlice<'_>]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    // Drop any empty slices at the start so that an empty write isn't
+    // mistaken for the stream refusing more data
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        let written = stream.write_vectored(slices).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut slices, written);
+    }
+    Ok(())
+}
+
+/// Send each chunk of a streamed body as it arrives, each write taking no
+/// longer than write_timeout, then end the body with resp's trailers
+async fn write_streamed_body<S>(
+    stream: &mut S,
+    mut chunks: mpsc::Receiver<Bytes>,
+    resp: &http::Response,
+    write_timeout: Duration,
+) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut frame = Vec::new();
+    loop {
+        frame.clear();
+        let done = match chunks.recv().await {
+            // Without chunked encoding the body ends with the connection
+            Some(data) if !resp.is_chunked() => {
+                frame.extend_from_slice(&data);
+                false
+            }
+            None if !resp.is_chunked() => true,
+            Some(data) => {
+                http::serialize_chunk(&mut frame, &data)?;
+                false
+            }
+            None => {
+                resp.serialize_last_chunk(&mut frame)?;
+                true
+            }
+        };
+        match timeout(write_timeout, stream.write_all(&frame)).await {
+            Ok(result) => result?,
+            Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// Write responses in order, in one go if there are several
+async fn write_responses<S>(stream: &mut S, responses: &[http::Response]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    match responses {
+        [resp] => write_response(stream, resp).await,
+        _ => stream.write_all(&serialize_or_500(responses)).await,
+    }
+}
+
+/// Serialize a response, falling back to a bare 500 if that fails partway
+/// through, so that a half-written response is never sent
+pub fn serialize_or_500<T: Serialize + ?Sized>(resp: &T) -> Vec<u8> {
+    resp.try_to_bytes().unwrap_or_else(|e| {
+        println!("  Serialize response - fail, {e}");
+        http::Response::new(http::Status::Internal)
+            .finalize(false)
+            .to_bytes()
+    })
+}
+
+/// Undo a gzip Content-Encoding on the request body so handlers see the
+/// original bytes. Fails with the status to answer with if that can't be done.
+fn decode_body(req: &mut http::Request, config: &Config) -> Result<(), http::Status> {
+    let is_gzip = req
+        .headers
+        .get("content-encoding")
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("gzip"));
+    let Some(body) = req.body.as_ref().filter(|_| is_gzip) else {
+        return Ok(());
+    };
+
+    match compress::gunzip(body, config.max_decompressed_size) {
+        Ok(decoded) => {
+            log_info!(
+                config,
+                "  Decompressed body - {} -> {} bytes",
+                body.len(),
+                decoded.len()
+            );
+            req.headers.remove("content-encoding");
+            req.headers
+                .insert(String::from("content-length"), decoded.len().to_string());
+            req.body = Some(decoded);
+            Ok(())
+        }
+        Err(e @ DecompressError::TooLarge(_)) => {
+            log_info!(config, "  Decompress body - fail, {e}");
+            Err(http::Status::PayloadTooLarge)
+        }
+        Err(e) => {
+            log_info!(config, "  Decompress body - fail, {e}");
+            Err(http::Status::BadRequest)
+        }
+    }
+}
+
+/// Whether the connection is to be closed after this request. HTTP/1.1 keeps
+/// it open unless the client says otherwise, and HTTP/1.0 the other way round.
+fn wants_close(req: &http::Request) -> bool {
+    if req.req_line.version.at_least(1, 1) {
+        has_connection_option(req, "close")
+    } else {
+        !has_connection_option(req, "keep-alive")
+    }
+}
+
+/// Whether the Connection header lists the option
+fn has_connection_option(req: &http::Request, option: &str) -> bool {
+    req.headers.get("connection").is_some_and(|v| {
+        v.split(',')
+            .any(|token| token.trim().eq_ignore_ascii_case(option))
+    })
+}
+
+pub fn handle_request(ctx: &RequestContext) -> http::Response {
+    let RequestContext {
+        req, config, state, ..
+    } = *ctx;
+    state.hits.fetch_add(1, Ordering::Relaxed);
+
+    let mut resp = route(ctx);
+    if let Some(template) = &state.error_template {
+        resp = with_error_page(resp, template);
+    }
+    let content_type = resp.header("content-type").unwrap_or_default();
+    let body_len = resp.body.as_ref().map_or(0, Vec::len);
+    if compress::should_compress(content_type, body_len, &config.compression_policy) {
+        resp = resp.with_compression(&req.accept_encodings(), &config.encodings());
+    }
+    resp = with_cors(resp, req, config);
+    if config.digest {
+        resp = with_digest(resp);
+    }
+    resp = resp.with_trailers_if(req.accepts_trailers());
+    if !req.req_line.version.at_least(1, 1) {
+        resp = resp.with_version(req.req_line.version);
+    }
+
+    // HEAD gets the same headers as GET, including Content-Length, but no body
+    if req.method() == http::Method::Head {
+        resp.body = None;
+        resp.streamed_body = None;
+    }
+    resp
+}
+
+fn route(ctx: &RequestContext) -> http::Response {
+    let RequestContext {
+        req, config, state, ..
+    } = *ctx;
+    // 100-continue is the only expectation there is
+    if req.header("expect").is_some() && !req.expect_continue() {
+        log_info!(config, "  {} - fail, unknown expectation", req.method_str());
+        return http::Response::error(http::Status::ExpectationFailed);
+    }
+
+    if websocket::is_upgrade_request(req) {
+        log_info!(config, "  WebSocket upgrade - {}", req.req_line.target);
+        return websocket::handshake(req)
+            .unwrap_or_else(|| http::Response::error(http::Status::BadRequest));
+    }
+
+    // Custom routes under /files/ are read-only too
+    if writes_read_only_file(req, config) {
+        log_info!(config, "  {} files - fail, read-only", req.method_str());
+        return http::Response::error_with_message(
+            http::Status::MethodNotAllowed,
+            "Files are read-only",
+        )
+        .with_header("Allow", allow_header(&allowed_methods(ctx)));
+    }
+
+    if let Some(resp) = state.router.handle(ctx) {
+        log_info!(
+            config,
+            "  {} custom - {}",
+            req.method_str(),
+            req.req_line.target
+        );
+        return resp;
+    }
+
+    if req.method() == http::Method::Options {
+        return route_options(ctx);
+    }
+
+    match builtin_routes().find(req.method(), req.req_line.path()) {
+        Some((handler, params)) => handler(ctx, &params),
+        None => route_unmatched(ctx),
+    }
+}
+
+/// Turn away a request that no route handles: with 501 if no route takes its
+/// method at all, with 405 listing the methods that are routed if its path is,
+/// and otherwise with 404
+fn route_unmatched(ctx: &RequestContext) -> http::Response {
+    let RequestContext {
+        req, config, state, ..
+    } = *ctx;
+    let builtin = builtin_routes();
+    if !builtin.handles_method(req.method()) && !state.router.handles_method(req.method()) {
+        log_info!(config, "  {} - fail, not implemented", req.method_str());
+        return http::Response::error(http::Status::NotImplemented);
+    }
+
+    let allowed = allowed_methods(ctx);
+    if allowed.is_empty() {
+        log_info!(
+            config,
+            "  {} unknown ({}) - 404",
+            req.method_str(),
+            req.req_line.target
+        );
+        return http::Response::error(http::Status::NotFound);
+    }
+
+    log_info!(
+        config,
+        "  {} - fail, not allowed for {}",
+        req.method_str(),
+        req.req_line.target
+    );
+    http::Response::error(http::Status::MethodNotAllowed)
+        .with_header("Allow", allow_header(&allowed))
+}
+
+/// The methods routed for the request's path by the custom and built-in
+/// routes, leaving out any that --read-only turns away
+fn allowed_methods(ctx: &RequestContext) -> Vec<http::Method> {
+    let RequestContext {
+        req, config, state, ..
+    } = *ctx;
+    let path = req.req_line.path();
+    let mut allowed = state.router.allowed_methods(path);
+    for method in builtin_routes().allowed_methods(path) {
+        if !allowed.contains(&method) {
+            allowed.push(method);
+        }
+    }
+    if config.read_only && path.starts_with("/files/") {
+        allowed.retain(http::Method::is_safe);
+    }
+    allowed
+}
+
+/// The methods routed for any path by the custom and built-in routes, plus
+/// OPTIONS, which is always answered
+fn server_allowed_methods(ctx: &RequestContext) -> Vec<http::Method> {
+    let RequestContext { config, state, .. } = *ctx;
+    http::Method::ALL
+        .into_iter()
+        .filter(|method| {
+            // The built-in routes only change anything under /files/, which
+            // --read-only turns away
+            let builtin =
+                builtin_routes().handles_method(*method) && (method.is_safe() || !config.read_only);
+            builtin || state.router.handles_method(*method) || *method == http::Method::Options
+        })
+        .collect()
+}
+
+/// An Allow header value listing the methods
+fn allow_header(methods: &[http::Method]) -> String {
+    let methods: Vec<_> = methods.iter().map(http::Method::as_str).collect();
+    methods.join(", ")
+}
+
+/// Handles one of the server's own routes
+type BuiltinHandler = fn(&RequestContext, &Params) -> http::Response;
+
+/// The routes the server always has, which custom routes take precedence over
+fn builtin_routes() -> &'static Router<BuiltinHandler> {
+    static ROUTES: OnceLock<Router<BuiltinHandler>> = OnceLock::new();
+    ROUTES.get_or_init(|| {
+        use http::Method::{Delete, Get, Post, Put};
+        Router::<BuiltinHandler>::new()
+            .add(Get, "/", |ctx, _| route_get_index(ctx))
+            .add(Get, "/echo/{*msg}", |ctx, params| {
+                route_get_echo(ctx.config, params.get("msg").unwrap_or_default())
+            })
+            .add(Get, "/echo-header/{name}", |ctx, params| {
+                route_get_echo_header(ctx, params.get("name").unwrap_or_default())
+            })
+            .add(Get, "/user-agent", |ctx, _| route_get_user_agent(ctx))
+            .add(Get, "/files/{*name}", |ctx, _| route_get_files(ctx))
+            .add(Get, "/count", |ctx, _| route_get_count(ctx))
+            .add(Get, "/metrics", |ctx, _| route_get_metrics(ctx))
+            .add(Get, "/events", |ctx, _| route_get_events(ctx))
+            .add(Post, "/files/{*name}", |ctx, _| route_post_files(ctx))
+            .add(Put, "/files/{*name}", |ctx, _| route_post_files(ctx))
+            .add(Delete, "/files/{*name}", |ctx, _| route_delete_files(ctx))
+    })
+}
+
+/// Whether the request would change a file while they're read-only
+fn writes_read_only_file(req: &http::Request, config: &Config) -> bool {
+    config.read_only && req.req_line.path().starts_with("/files/") && !req.method().is_safe()
+}
+
+/// Whether route will turn the request away whatever its body holds, so
+/// there's no need to read the body first
+fn rejected_before_body(ctx: &RequestContext) -> bool {
+    let RequestContext {
+        req, config, state, ..
+    } = *ctx;
+    if (req.header("expect").is_some() && !req.expect_continue())
+        || writes_read_only_file(req, config)
+    {
+        return true;
+    }
+    !state.router.matches(req)
+        && req.method() != http::Method::Options
+        && !builtin_routes().matches(req)
+}
+
+/// Whether to hand the body over as it arrives rather than read all of it
+/// first. Only uploads to the files directory take a body that way, and only
+/// one that's long, or whose length isn't known up front, needs to.
+fn streams_body(ctx: &RequestContext) -> bool {
+    let RequestContext { req, state, .. } = *ctx;
+    let long = req.is_chunked()
+        || req
+            .get_content_length()
+            .is_some_and(|len| len > STREAMED_BODY_SIZE);
+    long && matches!(req.method(), http::Method::Post | http::Method::Put)
+        && req.req_line.path().starts_with("/files/")
+        // A compressed body is decoded whole before it's handled
+        && req.header("content-encoding").is_none()
+        && !body_until_close(req)
+        && !state.router.matches(req)
+        && !rejected_before_body(ctx)
+}
+
+/// Allow a configured origin to read the response by echoing it back. The
+/// origin is reflected rather than using `*` so that credentialed requests
+/// work too.
+fn with_cors(resp: http::Response, req: &http::Request, config: &Config) -> http::Response {
+    let Some(origin) = req
+        .header("origin")
+        .filter(|_| !config.cors_origins.is_empty())
+    else {
+        return resp;
+    };
+
+    // The response depends on Origin whether or not this one is allowed
+    let resp = resp.append_header("Vary", "Origin");
+    if config.cors_origins.iter().any(|allowed| allowed == origin) {
+        resp.with_header("Access-Control-Allow-Origin", origin)
+    } else {
+        resp
+    }
+}
+
+/// Add a Digest header holding the SHA-256 hash of the body as sent, after
+/// any compression. Chunked bodies and partial ones are left alone, since the
+/// hash has to cover the whole of a fixed body.
+fn with_digest(resp: http::Response) -> http::Response {
+    if resp.is_chunked() || resp.status_line.status == http::Status::PartialContent {
+        return resp;
+    }
+    let Some(body) = &resp.body else {
+        return resp;
+    };
+    let digest = websocket::base64_encode(&sha256::sha256(body));
+    resp.with_header("Digest", format!("sha-256={digest}"))
+}
+
+/// Apply the connection-wide options to a response that's about to be sent
+fn finish_response(resp: http::Response, config: &Config, keep_alive: bool) -> http::Response {
+    // Finalizing fixes a wrong Content-Length, but that hides a bug in
+    // whatever set it, so make it visible
+    if let (Some(wrong), Some(body)) = (resp.wrong_content_length(), &resp.body) {
+        log_info!(
+            config,
+            "  Warning - corrected Content-Length from {wrong} to {}",
+            body.len()
+        );
+    }
+    let mut resp = with_security_headers(resp, config).finalize(keep_alive);
+    resp.status_line.omit_reason = config.no_reason_phrase;
+    resp
+}
+
+/// Headers added to every response with --security-headers
+const SECURITY_HEADERS: [(&str, &str); 3] = [
+    ("X-Content-Type-Options", "nosniff"),
+    ("X-Frame-Options", "DENY"),
+    ("Referrer-Policy", "no-referrer"),
+];
+
+/// Add the security headers if they're enabled, keeping any value a handler
+/// has already chosen
+fn with_security_headers(mut resp: http::Response, config: &Config) -> http::Response {
+    if !config.security_headers {
+        return resp;
+    }
+    for (name, value) in SECURITY_HEADERS {
+        if resp.header(name).is_none() {
+            resp = resp.with_header(name, value);
+        }
+    }
+    resp
+}
+
+/// Give a bodiless error response an HTML body from the template, replacing
+/// `{code}` and `{message}` with the status
+fn with_error_page(resp: http::Response, template: &str) -> http::Response {
+    let status = &resp.status_line.status;
+    // Replace the default error body but not a more specific message
+    let is_default = resp
+        .body
+        .as_ref()
+        .is_none_or(|body| *body == status.to_string().as_bytes());
+    if status.code() < 400 || !is_default {
+        return resp;
+    }
+
+    let page = template
+        .replace("{code}", &status.code().to_string())
+        .replace("{message}", status.text());
+    resp.with_body(page.as_bytes(), "text/html")
+}
+
+fn route_get_index(ctx: &RequestContext) -> http::Response {
+    let config = ctx.config;
+    if let Some(file) = &config.file {
+        route_get_single_file(ctx, file, "application/octet-stream")
+    } else if let Some(index_file) = &config.index_file {
+        route_get_single_file(ctx, index_file, content_type_for(index_file))
+    } else {
+        route_get_root(config)
+    }
+}
+
+fn route_get_root(config: &Config) -> http::Response {
+    log_info!(config, "  GET Root");
+    http::Response::new(http::Status::Ok)
+}
+
+fn route_get_single_file(ctx: &RequestContext, file: &Path, content_type: &str) -> http::Response {
+    let RequestContext {
+        req, config, state, ..
+    } = *ctx;
+    log_info!(config, "  GET single file - {}", file.display());
+    let permit = state.file_permits.acquire();
+    let file_data = fs::read(file);
+    drop(permit);
+    match file_data {
+        Ok(file_data) => ranged_file_response(&file_data, req.header("range"), content_type),
+        Err(e) => {
+            log_info!(config, "  GET single file - fail, {e}");
+            e.into()
+        }
+    }
+}
+
+/// Guess a file's content type from its extension
+fn content_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+fn route_get_echo(config: &Config, path: &str) -> http::Response {
+    log_info!(config, "  GET echo - {path}");
+    http::Response::new(http::Status::Ok).with_body(path.as_bytes(), "text/plain")
+}
+
+fn route_get_echo_header(ctx: &RequestContext, name: &str) -> http::Response {
+    let RequestContext { req, config, .. } = *ctx;
+    let name = http::percent_decode(name);
+    match req.header(&name) {
+        Some(value) => {
+            log_info!(config, "  GET echo-header - {name}: {value}");
+            http::Response::new(http::Status::Ok).with_body(value.as_bytes(), "text/plain")
+        }
+        None => {
+            log_info!(config, "  GET echo-header - fail, no {name} header");
+            http::Response::error(http::Status::NotFound)
+        }
+    }
+}
+
+fn route_get_user_agent(ctx: &RequestContext) -> http::Response {
+    let RequestContext { req, config, .. } = *ctx;
+    let user_agent = req
+        .headers
+        .get("user-agent")
+        .map_or_else(String::new, |ua| ua.clone());
+    log_info!(config, "  GET user-agent - {user_agent}");
+    http::Response::new(http::Status::Ok).with_body(user_agent.as_bytes(), "text/plain")
+}
+
+fn route_get_files(ctx: &RequestContext) -> http::Response {
+    let RequestContext {
+        req, config, state, ..
+    } = *ctx;
+    let Some(dir) = config.directory_for(req.host_only()) else {
+        log_info!(config, "  GET files - fail, no directory configured");
+        return http::Response::error(http::Status::Internal);
+    };
+
+    let query = req.req_line.target.query().unwrap_or_default();
+    let download = config.download || query.split('&').any(|param| param == "download=1");
+
+    // Serve the variant for the client's User-Agent instead, if there is one
+    let variant_dir = config
+        .user_agent_dir(req.header("user-agent"))
+        .map(|subdir| dir.join(subdir))
+        .filter(|variant_dir| file_path(variant_dir, req).is_some_and(|p| p.exists()));
+    let dir = variant_dir.as_deref().unwrap_or(dir);
+
+    log_info!(config, "  GET files - {}", req.req_line.target);
+    let Some(file_path) = file_path(dir, req) else {
+        log_info!(config, "  GET files - fail, invalid path");
+        return http::Response::error(http::Status::BadRequest);
+    };
+    if config.trailing_slash_redirect && file_path.is_dir() && !req.req_line.path().ends_with('/') {
+        let location = match req.req_line.target.query() {
+            Some(query) => format!("{}/?{query}", req.req_line.path()),
+            None => format!("{}/", req.req_line.path()),
+        };
+        log_info!(config, "  GET files - redirect to {location}");
+        return http::Response::new(http::Status::MovedPermanently)
+            .with_header("Location", location);
+    }
+    let file_path = with_index_file(file_path, config);
+
+    // Stat and read through the same handle, and take the length from what
+    // was actually read, so a file changing underneath can't make the headers
+    // disagree with the body
+    let permit = state.file_permits.acquire();
+    let read_file = fs::File::open(&file_path).and_then(|mut file| {
+        let meta = file.metadata()?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Ok((data, meta))
+    });
+    drop(permit);
+    let (file_data, meta) = match read_file {
+        Ok(read_file) => read_file,
+        Err(e) => {
+            log_info!(config, "  GET files - fail, {e}");
+            return e.into();
+        }
+    };
+
+    // If the file has changed, a part of it might not fit with parts the
+    // client already has, so send all of it
+    let range = req
+        .header("range")
+        .filter(|_| unmodified_since(req, meta.modified().ok()));
+    let mut resp = ranged_file_response(&file_data, range, "application/octet-stream");
+    resp = with_cache_headers(resp, config, SystemTime::now());
+    if !config.user_agent_dirs.is_empty() {
+        resp = resp.append_header("Vary", "User-Agent");
+    }
+    if download {
+        if let Some(filename) = req.path_segments().last() {
+            resp = resp.with_attachment(filename);
+        }
+    }
+
+    let etag = if config.strong_etag {
+        state
+            .etag_cache
+            .get_or_compute(&file_path, &meta, &file_data)
+    } else {
+        etag::weak_etag(&meta)
+    };
+    if let Some(resp) = check_etag_preconditions(req, &etag, config) {
+        return resp;
+    }
+
+    // Only worth the hashing if the client will actually receive it
+    if req.accepts_trailers() {
+        if let Some(body) = &resp.body {
+            let checksum = sha1::to_hex(&sha1::sha1(body));
+            resp = resp.with_trailer("X-Checksum-Sha1", checksum);
+        }
+    }
+    resp.with_etag(&etag)
+}
+
+/// A response with the file's data, or just the part of it asked for by a
+/// Range header. Only single byte ranges are supported; any other Range
+/// header is ignored.
+fn ranged_file_response(data: &[u8], range: Option<&str>, content_type: &str) -> http::Response {
+    let len = data.len();
+    match range.and_then(|range| parse_byte_range(range, len)) {
+        None => http::Response::new(http::Status::Ok)
+            .with_body(data, content_type)
+            .with_header("Accept-Ranges", "bytes"),
+        Some(Ok(range)) => http::Response::new(http::Status::PartialContent)
+            .with_content_range(Some((range.start, range.end - 1)), len)
+            .with_body(&data[range], content_type)
+            .with_header("Accept-Ranges", "bytes"),
+        Some(Err(())) => {
+            http::Response::error(http::Status::RangeNotSatisfiable).with_content_range(None, len)
+        }
+    }
+}
+
+/// Parse a Range header holding a single byte range, such as `bytes=0-99`,
+/// `bytes=100-` or `bytes=-100`, for a body of len bytes. Gives None if the
+/// header should be ignored, or Err if the range is entirely past the end.
+fn parse_byte_range(range: &str, len: usize) -> Option<Result<ops::Range<usize>, ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+
+    if start.is_empty() {
+        let suffix: usize = end.parse().ok()?;
+        if suffix == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok(len.saturating_sub(suffix)..len));
+    }
+
+    let start: usize = start.parse().ok()?;
+    let end: usize = match end {
+        "" => usize::MAX,
+        end => end.parse().ok()?,
+    };
+    if end < start {
+        return None;
+    }
+    if start >= len {
+        return Some(Err(()));
+    }
+    Some(Ok(start..end.min(len - 1) + 1))
+}
+
+/// Whether If-Unmodified-Since holds for a file last modified at the given
+/// time. It holds if either the header or the time is missing.
+fn unmodified_since(req: &http::Request, modified: Option<SystemTime>) -> bool {
+    let since = req.header("if-unmodified-since").and_then(parse_http_date);
+    let (Some(since), Some(modified)) = (since, modified) else {
+        return true;
+    };
+
+    // HTTP dates only have whole seconds
+    let modified_secs = modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    UNIX_EPOCH + Duration::from_secs(modified_secs) <= since
+}
+
+/// Apply If-Match and If-None-Match to a resource with the given ETag. Gives
+/// the response to send instead of the resource, if any.
+fn check_etag_preconditions(
+    req: &http::Request,
+    etag: &str,
+    config: &Config,
+) -> Option<http::Response> {
+    let etag = etag::ETag::parse(etag)?;
+
+    if let Some(if_match) = req.header("if-match") {
+        if !etag.matches_list(if_match, etag::Comparison::Strong) {
+            log_info!(config, "  Precondition - fail, If-Match {if_match}");
+            return Some(http::Response::error(http::Status::PreconditionFailed));
+        }
+    }
+
+    let not_modified = etag::check_not_modified(req, &etag.to_string());
+    if not_modified.is_some() {
+        log_info!(config, "  Precondition - not modified");
+    }
+    not_modified
+}
+
+/// Add the configured caching headers for a file response sent at `now`
+fn with_cache_headers(resp: http::Response, config: &Config, now: SystemTime) -> http::Response {
+    let mut resp = resp;
+    if let Some(max_age) = config.cache_max_age {
+        resp = resp
+            .with_header("Cache-Control", format!("public, max-age={max_age}"))
+            .with_header("Date", http_date(now))
+            .with_header("Expires", http_date(now + Duration::from_secs(max_age)));
+    }
+    if let Some(cache_control) = &config.cache_control {
+        resp = resp.with_header("Cache-Control", cache_control);
+    }
+    resp
+}
+
+fn route_get_count(ctx: &RequestContext) -> http::Response {
+    let RequestContext { config, state, .. } = *ctx;
+    let hits = state.hits.load(Ordering::Relaxed);
+    log_info!(config, "  GET count - {hits}");
+    http::Response::new(http::Status::Ok).with_body(hits.to_string().as_bytes(), "text/plain")
+}
+
+fn route_get_metrics(ctx: &RequestContext) -> http::Response {
+    let RequestContext { config, state, .. } = *ctx;
+    log_info!(config, "  GET metrics");
+    http::Response::new(http::Status::Ok).with_body(state.metrics.report().as_bytes(), "text/plain")
+}
+
+fn route_get_events(ctx: &RequestContext) -> http::Response {
+    let RequestContext { config, state, .. } = *ctx;
+    if state.events.is_none() {
+        log_info!(config, "  GET unknown (/events) - 404");
+        return http::Response::error(http::Status::NotFound);
+    }
+    log_info!(config, "  GET events");
+    sse::response()
+}
+
+fn route_post_files(ctx: &RequestContext) -> http::Response {
+    let RequestContext {
+        req, config, state, ..
+    } = *ctx;
+    let Some(dir) = config.directory_for(req.host_only()) else {
+        log_info!(config, "  POST files - fail, no directory configured");
+        return http::Response::error(http::Status::Internal);
+    };
+
+    // A chunked upload that's still arriving has no length until it ends
+    let content_len = req.get_content_length();
+    if content_len.is_none() && req.streamed_body.is_none() {
+        log_info!(config, "  POST files - fail, no content-length");
+        return http::Response::error_with_message(
+            http::Status::BadRequest,
+            "Content-Length required",
+        );
+    }
+
+    // Trust the body that actually arrived, but only if the header agrees.
+    // An empty upload may arrive without even the blank line ending the
+    // headers. A streamed body is read to exactly its length or not at all.
+    let mismatch = match req.body_len().cmp(&content_len.unwrap_or_default()) {
+        _ if req.streamed_body.is_some() => None,
+        cmp::Ordering::Less => Some("Body shorter than Content-Length"),
+        cmp::Ordering::Greater => Some("Body longer than Content-Length"),
+        cmp::Ordering::Equal => None,
+    };
+    if let Some(message) = mismatch {
+        log_info!(config, "  POST files - fail, invalid content-length");
+        return http::Response::error_with_message(http::Status::BadRequest, message);
+    }
+
+    log_info!(config, "  POST files - {}", req.req_line.target);
+    let Some(file_path) = file_path(dir, req) else {
+        log_info!(config, "  POST files - fail, invalid path");
+        return http::Response::error(http::Status::BadRequest);
+    };
+
+    let _permit = state.file_permits.acquire();
+    let existing = fs::metadata(&file_path).ok();
+    let modified = existing.as_ref().and_then(|m| m.modified().ok());
+    if !unmodified_since(req, modified) {
+        log_info!(config, "  POST files - fail, modified since");
+        return http::Response::error(http::Status::PreconditionFailed);
+    }
+
+    // Replacing a file frees up its old size
+    let old_len = existing.map_or(0, |m| m.len());
+    let room = match config.max_dir_size {
+        Some(max_dir_size) => match state.dir_sizes.get(dir) {
+            Ok(size) => Some(max_dir_size.saturating_sub(size.saturating_sub(old_len))),
+            Err(e) => {
+                log_info!(config, "  POST files - fail, {e}");
+                return e.into();
+            }
+        },
+        None => None,
+    };
+    let written = if room.is_some_and(|room| content_len.unwrap_or_default() as u64 > room) {
+        Err(UploadError::NoRoom)
+    } else {
+        write_upload(&file_path, req, room)
+    };
+
+    match written {
+        Ok(new_len) => {
+            state.dir_sizes.update(dir, old_len, new_len);
+            state.publish_event(format!("created {}", req.req_line.path()));
+            http::Response::new(http::Status::Created)
+        }
+        Err(UploadError::NoRoom) => {
+            log_info!(
+                config,
+                "  POST files - fail, directory would exceed {} bytes",
+                config.max_dir_size.unwrap_or_default()
+            );
+            http::Response::error(http::Status::InsufficientStorage)
+        }
+        Err(UploadError::Io(e)) => {
+            log_info!(config, "  POST files - fail, {e}");
+            e.into()
+        }
+    }
+}
+
+/// Failure to write an upload to its file
+#[derive(Debug, thiserror::Error)]
+enum UploadError {
+    #[error("no room for the upload")]
+    NoRoom,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Write the request body to a file a frame at a time, returning its length.
+/// It goes to a temporary file first, so that an upload which fails partway,
+/// or turns out longer than room, leaves the old file as it was.
+fn write_upload(
+    file_path: &Path,
+    req: &http::Request,
+    room: Option<u64>,
+) -> Result<u64, UploadError> {
+    static UPLOADS: AtomicUsize = AtomicUsize::new(0);
+    let name = file_path.file_name().unwrap_or_default().to_string_lossy();
+    let upload = UPLOADS.fetch_add(1, Ordering::Relaxed);
+    let temp_path = file_path.with_file_name(format!(".{name}.{upload}.part"));
+
+    let write = || {
+        let mut file = fs::File::create(&temp_path)?;
+        let mut len = 0;
+        for frame in req.body_stream() {
+            let frame = frame?;
+            len += frame.len() as u64;
+            if room.is_some_and(|room| len > room) {
+                return Err(UploadError::NoRoom);
+            }
+            file.write_all(&frame)?;
+        }
+        fs::rename(&temp_path, file_path)?;
+        Ok(len)
+    };
+    let written = write();
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written
+}
+
+/// Methods the server handles, before any restriction by a route
+fn route_options(ctx: &RequestContext) -> http::Response {
+    let RequestContext { req, config, .. } = *ctx;
+    // `*` asks about the server as a whole rather than any one resource
+    if req.req_line.target == http::Target::Asterisk {
+        log_info!(config, "  OPTIONS server");
+        let allowed = server_allowed_methods(ctx);
+        return http::Response::new(http::Status::Ok).with_header("Allow", allow_header(&allowed));
+    }
+
+    log_info!(config, "  OPTIONS - {}", req.req_line.target);
+    let mut allowed = allowed_methods(ctx);
+    allowed.push(http::Method::Options);
+    http::Response::new(http::Status::Ok).with_header("Allow", allow_header(&allowed))
+}
+
+fn route_delete_files(ctx: &RequestContext) -> http::Response {
+    let RequestContext {
+        req, config, state, ..
+    } = *ctx;
+    let Some(dir) = config.directory_for(req.host_only()) else {
+        log_info!(config, "  DELETE files - fail, no directory configured");
+        return http::Response::error(http::Status::Internal);
+    };
+
+    log_info!(config, "  DELETE files - {}", req.req_line.target);
+    let Some(file_path) = file_path(dir, req) else {
+        log_info!(config, "  DELETE files - fail, invalid path");
+        return http::Response::error(http::Status::BadRequest);
+    };
+    let _permit = state.file_permits.acquire();
+    let old_len = fs::metadata(&file_path).map_or(0, |m| m.len());
+    match fs::remove_file(file_path) {
+        Ok(_) => {
+            state.dir_sizes.update(dir, old_len, 0);
+            state.publish_event(format!("deleted {}", req.req_line.path()));
+            http::Response::new(http::Status::NoContent)
+        }
+        Err(e) => {
+            log_info!(config, "  DELETE files - fail, {e}");
+            e.into()
+        }
+    }
+}
+
+/// For a directory, the first of its configured index files which exists.
+/// Other paths are left alone.
+fn with_index_file(path: PathBuf, config: &Config) -> PathBuf {
+    if !path.is_dir() {
+        return path;
+    }
+    config
+        .index_files
+        .iter()
+        .map(|name| path.join(name))
+        .find(|index| index.is_file())
+        .unwrap_or(path)
+}
+
+/// Location on disk of the file named by the path following /files/, where a
+/// trailing slash names a directory. Returns None for a path which could lead
+/// outside dir, such as one with a `..` segment, even percent-encoded.
+fn file_path(dir: &Path, req: &http::Request) -> Option<PathBuf> {
+    let segments = req.path_segments();
+    let (first, names) = segments.split_first()?;
+    if first != "files" || !req.req_line.path().starts_with("/files/") {
+        return None;
+    }
+
+    let mut file_path = dir.to_owned();
+    for segment in names {
+        // Anything but a plain name, which includes `.`, `..` and absolute
+        // paths, could take the path somewhere else
+        let mut components = Path::new(segment.as_ref()).components();
+        let is_plain_name =
+            matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none();
+        if !is_plain_name || segment.contains(['/', '\\', '\0']) {
+            return None;
+        }
+        file_path.push(segment.as_ref());
+    }
+    file_path.starts_with(dir).then_some(file_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufReader, Read},
+        task::{Context, Poll},
+    };
+
+    use super::*;
+    use crate::{config::Logger, hpack};
+
+    /// Handle a request from no particular peer
+    fn handle(req: &http::Request, config: &Config, state: &State<AppState>) -> http::Response {
+        handle_request(&RequestContext::new(req, config, state))
+    }
+
+    fn get(path: &str) -> http::Request {
+        let input = format!("GET {path} HTTP/1.1\r\n\r\n");
+        http::Request::parser(input.as_bytes()).unwrap().1
+    }
+
+    fn post(path: &str, body: &str) -> http::Request {
+        let input = format!(
+            "POST {path} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        http::Request::parser(input.as_bytes()).unwrap().1
+    }
+
+    /// Create an empty directory unique to the calling test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("server-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_count_increments() {
+        let config = Config::default();
+        let state = State::new(AppState::default());
+
+        let resp = handle(&get("/count"), &config, &state);
+        assert_eq!(resp.body.as_deref(), Some(&b"1"[..]));
+
+        handle(&get("/"), &config, &state);
+        handle(&get("/echo/abc"), &config, &state);
+
+        let resp = handle(&get("/count"), &config, &state.clone());
+        assert_eq!(resp.body.as_deref(), Some(&b"4"[..]));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir = temp_dir("read-only");
+        fs::write(dir.join("a.txt"), "abc").unwrap();
+        let config = Config {
+            directory: Some(dir.clone()),
+            read_only: true,
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        let resp = handle(&post("/files/b.txt", "def"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::MethodNotAllowed);
+        assert_eq!(resp.header("allow"), Some("GET, HEAD"));
+        assert!(!dir.join("b.txt").exists());
+
+        let resp = handle(&get("/files/a.txt"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Ok);
+        assert_eq!(resp.body.as_deref(), Some(&b"abc"[..]));
+
+        // Custom routes can't get around it
+        let router = Router::new().route(http::Method::Post, "/files/{name}", |_, _| {
+            http::Response::new(http::Status::Created)
+        });
+        let state = State::new(AppState {
+            router,
+            ..Default::default()
+        });
+        let resp = handle(&post("/files/b.txt", "def"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::MethodNotAllowed);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_download() {
+        let dir = temp_dir("download");
+        fs::write(dir.join("a.txt"), "abc").unwrap();
+        let config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        let resp = handle(&get("/files/a.txt"), &config, &state);
+        assert!(resp.header("content-disposition").is_none());
+
+        let resp = handle(&get("/files/a.txt?download=1"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Ok);
+        assert_eq!(
+            resp.header("content-disposition").unwrap(),
+            "attachment; filename=\"a.txt\""
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_encoded_path() {
+        let dir = temp_dir("encoded-path");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub").join("a b.txt"), "abc").unwrap();
+        let config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        let resp = handle(&get("/files/sub/a%20b.txt"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Ok);
+        assert_eq!(resp.body.as_deref(), Some(&b"abc"[..]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_binary() {
+        let dir = temp_dir("files-binary");
+        let data = [0xff, 0xfe, 0x00, 0x80];
+        fs::write(dir.join("a.bin"), data).unwrap();
+        let config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        let resp = handle(&get("/files/a.bin"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Ok);
+        assert_eq!(resp.body.as_deref(), Some(&data[..]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_path_traversal() {
+        let root = temp_dir("path-traversal");
+        let dir = root.join("files");
+        fs::create_dir(&dir).unwrap();
+        fs::write(root.join("secret.txt"), "secret").unwrap();
+        let config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        let outside = root.join("secret.txt");
+        let absolute = outside.to_string_lossy().replace('/', "%2F");
+        let paths = [
+            String::from("/files/../secret.txt"),
+            String::from("/files/..%2Fsecret.txt"),
+            String::from("/files/sub/%2E%2E/%2E%2E/secret.txt"),
+            String::from("/files/..%5Csecret.txt"),
+            String::from("/files/a%00.txt"),
+            format!("/files/{absolute}"),
+        ];
+        for path in paths {
+            let resp = handle(&get(&path), &config, &state);
+            assert_eq!(
+                resp.status_line.status,
+                http::Status::BadRequest,
+                "GET {path}"
+            );
+
+            let resp = handle(&post(&path, "pwned"), &config, &state);
+            assert_eq!(
+                resp.status_line.status,
+                http::Status::BadRequest,
+                "POST {path}"
+            );
+            assert_eq!(fs::read(&outside).unwrap(), b"secret");
+
+            let input = format!("DELETE {path} HTTP/1.1\r\n\r\n");
+            let (_, req) = http::Request::parser(input.as_bytes()).unwrap();
+            let resp = handle(&req, &config, &state);
+            assert_eq!(
+                resp.status_line.status,
+                http::Status::BadRequest,
+                "DELETE {path}"
+            );
+            assert!(outside.exists());
+        }
+
+        // Empty segments are skipped, as they are by path_segments
+        fs::write(dir.join("b.txt"), "b").unwrap();
+        let resp = handle(&get("/files//b.txt"), &config, &state);
+        assert_eq!(resp.body.as_deref(), Some(&b"b"[..]));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_echo_header() {
+        let config = Config::default();
+        let state = State::new(AppState::default());
+
+        let input =
+            b"GET /echo-header/X-Forwarded-For HTTP/1.1\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n";
+        let (_, req) = http::Request::parser(input).unwrap();
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Ok);
+        assert_eq!(resp.body.as_deref(), Some(&b"10.0.0.1"[..]));
+
+        let resp = handle(&get("/echo-header/X-Missing"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::NotFound);
+    }
+
+    #[test]
+    fn test_cors_origin() {
+        let config = Config {
+            cors_origins: vec![String::from("https://a.example")],
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        let input = b"GET / HTTP/1.1\r\nOrigin: https://a.example\r\n\r\n";
+        let (_, req) = http::Request::parser(input).unwrap();
+        let resp = handle(&req, &config, &state);
+        assert_eq!(
+            resp.header("access-control-allow-origin"),
+            Some("https://a.example")
+        );
+        assert_eq!(resp.header("vary"), Some("Origin"));
+
+        let input = b"GET / HTTP/1.1\r\nOrigin: https://evil.example\r\n\r\n";
+        let (_, req) = http::Request::parser(input).unwrap();
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.header("access-control-allow-origin"), None);
+        assert_eq!(resp.header("vary"), Some("Origin"));
+    }
+
+    #[test]
+    fn test_options_asterisk() {
+        let (_, req) = http::Request::parser(b"OPTIONS * HTTP/1.1\r\n\r\n").unwrap();
+        let resp = handle(&req, &Config::default(), &State::new(AppState::default()));
+        assert_eq!(resp.status_line.status, http::Status::Ok);
+        assert_eq!(
+            resp.header("allow"),
+            Some("GET, HEAD, POST, PUT, DELETE, OPTIONS")
+        );
+
+        // Custom routes add their methods, and --read-only takes away the
+        // built-in ones that write files
+        let config = Config {
+            read_only: true,
+            ..Default::default()
+        };
+        let state = State::new(AppState {
+            router: Router::new().route(http::Method::Patch, "/custom", |_, _| {
+                http::Response::new(http::Status::Ok)
+            }),
+            ..Default::default()
+        });
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.header("allow"), Some("GET, HEAD, OPTIONS, PATCH"));
+    }
+
+    #[test]
+    fn test_options_path() {
+        let options = |path: &str, config: &Config, state: &State<AppState>| {
+            let input = format!("OPTIONS {path} HTTP/1.1\r\n\r\n");
+            let (_, req) = http::Request::parser(input.as_bytes()).unwrap();
+            let resp = handle(&req, config, state);
+            assert_eq!(resp.status_line.status, http::Status::Ok);
+            resp.header("allow").map(str::to_owned)
+        };
+        let config = Config::default();
+        let state = State::new(AppState::default());
+        assert_eq!(
+            options("/echo/abc", &config, &state).as_deref(),
+            Some("GET, HEAD, OPTIONS")
+        );
+        assert_eq!(
+            options("/files/a.txt", &config, &state).as_deref(),
+            Some("GET, HEAD, POST, PUT, DELETE, OPTIONS")
+        );
+        assert_eq!(
+            options("/nope", &config, &state).as_deref(),
+            Some("OPTIONS")
+        );
+
+        // Custom routes are listed, and read-only files can't be written
+        let router = Router::new().route(http::Method::Patch, "/echo/abc", |_, _| {
+            http::Response::new(http::Status::NoContent)
+        });
+        let state = State::new(AppState {
+            router,
+            ..Default::default()
+        });
+        let config = Config {
+            read_only: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            options("/echo/abc", &config, &state).as_deref(),
+            Some("PATCH, GET, HEAD, OPTIONS")
+        );
+        assert_eq!(
+            options("/files/a.txt", &config, &state).as_deref(),
+            Some("GET, HEAD, OPTIONS")
+        );
+    }
+
+    #[test]
+    fn test_post_empty_file() {
+        let dir = temp_dir("post-empty");
+        let config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        let resp = handle(&post("/files/empty.txt", ""), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Created);
+        assert_eq!(fs::read(dir.join("empty.txt")).unwrap(), b"");
+
+        let input = b"POST /files/none.txt HTTP/1.1\r\nContent-Length: 0\r\n";
+        let (_, req) = http::Request::parser(input).unwrap();
+        assert_eq!(req.body, None);
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Created);
+        assert!(dir.join("none.txt").exists());
+
+        let input = b"POST /files/missing.txt HTTP/1.1\r\n\r\n";
+        let (_, req) = http::Request::parser(input).unwrap();
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::BadRequest);
+        assert!(!dir.join("missing.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_post_file_content_length_mismatch() {
+        let dir = temp_dir("post-mismatch");
+        let config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        let mut req = post("/files/a.txt", "hello");
+        assert_eq!(req.body_len(), req.get_content_length().unwrap());
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Created);
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"hello");
+
+        req.body = Some(b"hello world".to_vec());
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::BadRequest);
+        assert_eq!(resp.body.unwrap(), b"Body longer than Content-Length");
+
+        req.body = Some(b"hi".to_vec());
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::BadRequest);
+        assert_eq!(resp.body.unwrap(), b"Body shorter than Content-Length");
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"hello");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_headers() {
+        let config = Config {
+            cache_max_age: Some(3600),
+            ..Default::default()
+        };
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784111777);
+        let resp = with_cache_headers(http::Response::new(http::Status::Ok), &config, now);
+        assert_eq!(resp.header("cache-control"), Some("public, max-age=3600"));
+        assert_eq!(resp.header("date"), Some("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert_eq!(
+            resp.header("expires"),
+            Some("Sun, 06 Nov 1994 09:49:37 GMT")
+        );
+
+        let config = Config {
+            cache_max_age: Some(60),
+            cache_control: Some(String::from("private, no-transform")),
+            ..Default::default()
+        };
+        let resp = with_cache_headers(http::Response::new(http::Status::Ok), &config, now);
+        assert_eq!(resp.header("cache-control"), Some("private, no-transform"));
+        assert_eq!(
+            resp.header("expires"),
+            Some("Sun, 06 Nov 1994 08:50:37 GMT")
+        );
+    }
+
+    #[test]
+    fn test_files_cache_headers() {
+        let dir = temp_dir("cache-headers");
+        fs::write(dir.join("a.txt"), "abc").unwrap();
+        let config = Config {
+            directory: Some(dir.clone()),
+            cache_max_age: Some(60),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        let resp = handle(&get("/files/a.txt"), &config, &state);
+        assert_eq!(resp.header("cache-control"), Some("public, max-age=60"));
+        assert!(resp.header("expires").is_some());
+
+        // Only file responses are cacheable
+        let resp = handle(&get("/echo/abc"), &config, &state);
+        assert_eq!(resp.header("cache-control"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_serialize_or_500() {
+        /// Response whose body comes from a reader that fails after a few bytes
+        struct FailingResponse;
+
+        impl Serialize for FailingResponse {
+            fn serialize<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+                let failing = Read::chain(&b"abc"[..], FailingReader);
+                writer.write_all(b"HTTP/1.1 200 OK\r\n\r\n")?;
+                io::copy(&mut BufReader::new(failing), writer)?;
+                Ok(())
+            }
+        }
+
+        struct FailingReader;
+
+        impl io::Read for FailingReader {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk on fire"))
+            }
+        }
+
+        let output = String::from_utf8(serialize_or_500(&FailingResponse)).unwrap();
+        assert!(output.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(!output.contains("abc"));
+
+        let output = serialize_or_500(&http::Response::new(http::Status::Ok));
+        assert_eq!(output, b"HTTP/1.1 200 OK\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_write_response() {
+        let resp = http::Response::new(http::Status::Ok)
+            .with_body("x".repeat(10_000).as_bytes(), "text/plain")
+            .finalize(true);
+
+        let mut output = Vec::new();
+        write_response(&mut output, &resp).await.unwrap();
+        assert_eq!(output, resp.to_bytes());
+
+        // A small pipe only takes part of each write
+        let (mut client, mut server) = tokio::io::duplex(100);
+        let reader = tokio::spawn(async move {
+            let mut output = Vec::new();
+            client.read_to_end(&mut output).await.unwrap();
+            output
+        });
+        write_response(&mut server, &resp).await.unwrap();
+        drop(server);
+        assert_eq!(reader.await.unwrap(), resp.to_bytes());
+    }
+
+    /// A writer which takes at most a few bytes per call, like a busy socket
+    #[derive(Debug, Default)]
+    struct TrickleWriter {
+        written: Vec<u8>,
+        calls: usize,
+    }
+
+    impl AsyncWrite for TrickleWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            let mut written = 0;
+            for buf in bufs {
+                let len = buf.len().min(7 - written);
+                self.written.extend_from_slice(&buf[0..len]);
+                written += len;
+                if written == 7 {
+                    break;
+                }
+            }
+            self.calls += 1;
+            Poll::Ready(Ok(written))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_response_partial_writes() {
+        let resp = http::Response::new(http::Status::Ok)
+            .with_body(b"hello world", "text/plain")
+            .finalize(true);
+
+        let mut writer = TrickleWriter::default();
+        write_response(&mut writer, &resp).await.unwrap();
+        assert_eq!(writer.written, resp.to_bytes());
+        assert_eq!(writer.calls, resp.to_bytes().len().div_ceil(7));
+
+        // Nothing to write at all
+        let mut writer = TrickleWriter::default();
+        write_all_vectored(&mut writer, &mut [IoSlice::new(&[]), IoSlice::new(&[])])
+            .await
+            .unwrap();
+        assert_eq!(writer.calls, 0);
+    }
+
+    #[derive(Debug, Default)]
+    struct StubTls {
+        connections: AtomicUsize,
+    }
+
+    impl TlsAcceptor for StubTls {
+        fn serve(
+            &self,
+            _stream: TcpStream,
+            _config: Arc<Config>,
+            _state: State<AppState>,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>> {
+            self.connections.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls_detection() {
+        let tls = Arc::new(StubTls::default());
+        let state = State::new(AppState {
+            tls: Some(tls.clone()),
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            Arc::new(Config::default()),
+            state,
+            std::future::pending(),
+        ));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await.unwrap();
+        assert!(output.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert_eq!(tls.connections.load(Ordering::SeqCst), 0);
+
+        // The start of a ClientHello
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&[0x16, 0x03, 0x01]).await.unwrap();
+        // The stub closes without reading, which may reset the connection
+        let _ = stream.read_to_end(&mut Vec::new()).await;
+        assert_eq!(tls.connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_redirect_to_https() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_redirects(listener, Arc::new(Config::default())));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /files/a.txt?download=1 HTTP/1.1\r\nHost: example.com:8080\r\n\r\n")
+            .await
+            .unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).await.unwrap();
+        assert!(output.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(output.contains("location: https://example.com/files/a.txt?download=1\r\n"));
+    }
+
+    /// Send raw bytes to handle_conn over an in-memory stream, then read
+    /// everything written back until the server closes its end. Date headers
+    /// are removed from the output since they change from run to run.
+    async fn run_conn(config: Config, input: &[u8]) -> String {
+        run_conn_with_state(config, State::new(AppState::default()), input).await
+    }
+
+    async fn run_conn_with_state(config: Config, state: State<AppState>, input: &[u8]) -> String {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let task =
+            tokio::spawn(async move { handle_conn(server, None, Arc::new(config), state).await });
+
+        client.write_all(input).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).await.unwrap();
+        task.await.unwrap().unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .split_inclusive("\r\n")
+            .filter(|line| !line.starts_with("date: "))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_connection_state_keep_alive() {
+        let config = Arc::new(Config::default());
+        let state = State::new(AppState::default());
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut conn = ConnectionState::new(server, None, &config, &state);
+        assert_eq!(conn.phase, Phase::ReadingRequest);
+
+        client
+            .write_all(b"GET /echo/a HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        conn.step().await.unwrap();
+        assert!(matches!(conn.phase, Phase::Handling { .. }));
+        conn.step().await.unwrap();
+        assert!(matches!(conn.phase, Phase::Writing { close: false, .. }));
+        assert_eq!(conn.served, 1);
+        conn.step().await.unwrap();
+        assert_eq!(conn.phase, Phase::ReadingRequest);
+
+        let mut output = vec![0; 1024];
+        let len = client.read(&mut output).await.unwrap();
+        assert!(output[0..len].starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        client.shutdown().await.unwrap();
+        conn.step().await.unwrap();
+        assert!(conn.is_closing());
+    }
+
+    #[tokio::test]
+    async fn test_connection_state_close() {
+        let config = Arc::new(Config::default());
+        let state = State::new(AppState::default());
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut conn = ConnectionState::new(server, None, &config, &state);
+
+        let input = b"GET /echo/a HTTP/1.1\r\nConnection: close\r\n\r\n";
+        client.write_all(input).await.unwrap();
+        conn.step().await.unwrap();
+        conn.step().await.unwrap();
+        assert!(matches!(conn.phase, Phase::Writing { close: true, .. }));
+        conn.step().await.unwrap();
+        assert!(conn.is_closing());
+
+        // Closing is final
+        conn.step().await.unwrap();
+        assert!(conn.is_closing());
+        drop(conn);
+
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).await.unwrap();
+        assert!(output.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_large_request() {
+        let dir = temp_dir("large-request");
+        let config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+
+        // Both the head and the body are far bigger than one read
+        let body = "a".repeat(100_000);
+        let input = format!(
+            "POST /files/big.txt HTTP/1.1\r\nX-Padding: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            "p".repeat(5000),
+            body.len()
+        );
+        let output = run_conn(config, input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert_eq!(fs::read_to_string(dir.join("big.txt")).unwrap(), body);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_streamed_upload() {
+        let dir = temp_dir("streamed-upload");
+        let config = || Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let body: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut input = format!(
+            "PUT /files/a.bin HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        input.extend_from_slice(&body);
+        input.extend_from_slice(b"PUT /files/b.bin HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+        for chunk in body.chunks(100_000) {
+            input.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            input.extend_from_slice(chunk);
+            input.extend_from_slice(b"\r\n");
+        }
+        input.extend_from_slice(b"0\r\n\r\nGET /echo/done HTTP/1.1\r\nConnection: close\r\n\r\n");
+
+        let output = run_conn(config(), &input).await;
+        assert_eq!(output.matches("HTTP/1.1 201 Created\r\n").count(), 2);
+        assert!(output.ends_with("\r\n\r\ndone"));
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), body);
+        assert_eq!(fs::read(dir.join("b.bin")).unwrap(), body);
+
+        // An upload cut short leaves the old file alone
+        let mut input = format!(
+            "PUT /files/a.bin HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        input.extend_from_slice(&body[0..500_000]);
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let state = State::new(AppState::default());
+        let task = tokio::spawn(handle_conn(server, None, Arc::new(config()), state));
+        client.write_all(&input).await.unwrap();
+        drop(client);
+        assert!(task.await.unwrap().is_err());
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), body);
+
+        // A chunked upload has no length up front, so it's turned away once
+        // it's seen to be too big
+        let mut input = b"PUT /files/c.bin HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        input.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+        input.extend_from_slice(&body);
+        input.extend_from_slice(b"\r\n0\r\n\r\n");
+        let config = Config {
+            max_dir_size: Some(2_500_000),
+            ..config()
+        };
+        let output = run_conn(config, &input).await;
+        assert!(output.starts_with("HTTP/1.1 507 Insufficient Storage\r\n"));
+        assert!(!dir.join("c.bin").exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chunked_upload() {
+        let dir = temp_dir("chunked-upload");
+        let config = || Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+
+        let input = "\
+            POST /files/a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            6\r\nhello \r\n5;ext=1\r\nworld\r\n0\r\n\r\n\
+            GET /files/a.txt HTTP/1.1\r\nConnection: close\r\n\r\n\
+        ";
+        let output = run_conn(config(), input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(output.ends_with("\r\n\r\nhello world"));
+        assert_eq!(
+            fs::read_to_string(dir.join("a.txt")).unwrap(),
+            "hello world"
+        );
+
+        let input = "POST /files/b.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nxyz\r\n";
+        let output = run_conn(config(), input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(!dir.join("b.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_too_large() {
+        let config = || Config {
+            max_head_size: 1024,
+            max_body_size: 2048,
+            ..Default::default()
+        };
+
+        let input = format!("GET / HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "p".repeat(2000));
+        let output = run_conn(config(), input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        let input = "POST /echo/a HTTP/1.1\r\nContent-Length: 4096\r\n\r\n";
+        let output = run_conn(config(), input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(output.contains("connection: close\r\n"));
+
+        let input = format!("POST /echo/a HTTP/1.0\r\n\r\n{}", "b".repeat(4096));
+        let output = run_conn(config(), input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let config = Arc::new(Config {
+            write_timeout: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let state = State::new(AppState::default());
+        // The client never reads, so the response fills the pipe and stalls
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut conn = ConnectionState::new(server, None, &config, &state);
+
+        let input = format!("GET /echo/{} HTTP/1.1\r\n\r\n", "a".repeat(900));
+        client.write_all(input.as_bytes()).await.unwrap();
+        conn.step().await.unwrap();
+        conn.step().await.unwrap();
+        assert!(matches!(conn.phase, Phase::Writing { .. }));
+
+        let start = Instant::now();
+        conn.step().await.unwrap();
+        assert!(conn.is_closing());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(start.elapsed() < config.request_timeout);
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let (events, _) = broadcast::channel(8);
+        let state = State::new(AppState {
+            events: Some(events.clone()),
+            ..Default::default()
+        });
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let task = tokio::spawn(async move {
+            handle_conn(server, None, Arc::new(Config::default()), state).await
+        });
+
+        client
+            .write_all(b"GET /events HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n")
+            .await
+            .unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("content-type: text/event-stream\r\n"));
+        assert!(head.contains("connection: close\r\n"));
+        assert!(!head.contains("content-length"));
+        assert!(!head.contains("content-encoding"));
+
+        events.send(String::from("one")).unwrap();
+        events.send(String::from("two\nlines")).unwrap();
+
+        let expected = "data: one\n\ndata: two\ndata: lines\n\n";
+        let mut body = vec![0; expected.len()];
+        client.read_exact(&mut body).await.unwrap();
+        assert_eq!(body, expected.as_bytes());
+
+        // The state holds a sender too, so the stream would go on for as long
+        // as the client stays
+        task.abort();
+    }
+
+    #[test]
+    fn test_events_disabled() {
+        let resp = handle(
+            &get("/events"),
+            &Config::default(),
+            &State::new(AppState::default()),
+        );
+        assert_eq!(resp.status_line.status, http::Status::NotFound);
+    }
+
+    #[test]
+    fn test_request_context() {
+        let req = get("/count");
+        let config = Config {
+            read_only: true,
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+        let peer_addr = "127.0.0.1:5000".parse().ok();
+
+        let ctx = RequestContext::new(&req, &config, &state).with_peer_addr(peer_addr);
+        assert_eq!(ctx.req.req_line.path(), "/count");
+        assert_eq!(ctx.peer_addr, peer_addr);
+        assert!(ctx.config.read_only);
+        assert_eq!(ctx.state.hits.load(Ordering::Relaxed), 0);
+
+        let resp = handle_request(&ctx);
+        assert_eq!(resp.body.as_deref(), Some(&b"1"[..]));
+        assert_eq!(state.hits.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_digest() {
+        let state = State::new(AppState::default());
+        let resp = handle(&get("/echo/hello"), &Config::default(), &state);
+        assert_eq!(resp.header("digest"), None);
+
+        let config = Config {
+            digest: true,
+            ..Default::default()
+        };
+        let resp = handle(&get("/echo/hello"), &config, &state);
+        // echo -n hello | openssl dgst -sha256 -binary | base64
+        assert_eq!(
+            resp.header("digest"),
+            Some("sha-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=")
+        );
+
+        // Nothing to hash
+        let resp = handle(&get("/"), &config, &state);
+        assert_eq!(resp.header("digest"), None);
+    }
+
+    #[test]
+    fn test_method_not_allowed() {
+        let config = Config::default();
+        let state = State::new(AppState::default());
+        let (_, req) = http::Request::parser(b"PUT /echo/abc HTTP/1.1\r\n\r\n").unwrap();
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::MethodNotAllowed);
+        assert_eq!(resp.header("allow"), Some("GET, HEAD"));
+
+        let (_, req) = http::Request::parser(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n").unwrap();
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::NotImplemented);
+        assert_eq!(resp.header("allow"), None);
+
+        let resp = handle(&post("/nope", ""), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::NotFound);
+
+        // Custom routes count too, both for the path and for the method
+        let router = Router::new().route(http::Method::Patch, "/items/{id}", |_, _| {
+            http::Response::new(http::Status::NoContent)
+        });
+        let state = State::new(AppState {
+            router,
+            ..Default::default()
+        });
+        let (_, req) = http::Request::parser(b"GET /items/1 HTTP/1.1\r\n\r\n").unwrap();
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::MethodNotAllowed);
+        assert_eq!(resp.header("allow"), Some("PATCH"));
+
+        let (_, req) = http::Request::parser(b"PATCH /files/a.txt HTTP/1.1\r\n\r\n").unwrap();
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::MethodNotAllowed);
+        assert_eq!(resp.header("allow"), Some("GET, HEAD, POST, PUT, DELETE"));
+    }
+
+    #[test]
+    fn test_check_body_rate() {
+        let config = Config {
+            min_body_rate: Some(100),
+            body_rate_window: Duration::from_secs(5),
+            ..Default::default()
+        };
+        // Too early to judge
+        assert!(check_body_rate(1, Duration::from_secs(4), &config).is_ok());
+        assert!(check_body_rate(500, Duration::from_secs(5), &config).is_ok());
+        assert!(check_body_rate(499, Duration::from_secs(5), &config).is_err());
+        assert!(check_body_rate(1, Duration::from_secs(60), &Config::default()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_body_in_segments() {
+        let dir = temp_dir("body-segments");
+        let config = Arc::new(Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        });
+        let (mut client, server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(handle_conn(server, None, config, State::default()));
+
+        // The body turns up well after the head, as a second segment would
+        client
+            .write_all(b"POST /files/a.txt HTTP/1.1\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello")
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(50)).await;
+        client.write_all(b" world").await.unwrap();
+
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).await.unwrap();
+        task.await.unwrap().unwrap();
+        assert!(output.starts_with(b"HTTP/1.1 201 Created\r\n"));
+        assert_eq!(
+            fs::read_to_string(dir.join("a.txt")).unwrap(),
+            "hello world"
+        );
+
+        // A body cut short by the client closing is never handled
+        let input = "POST /files/b.txt HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello";
+        let (mut client, server) = tokio::io::duplex(1024);
+        let config = Arc::new(Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        });
+        let task = tokio::spawn(handle_conn(server, None, config, State::default()));
+        client.write_all(input.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+        assert!(task.await.unwrap().is_err());
+        assert!(!dir.join("b.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_body() {
+        let config = Config {
+            min_body_rate: Some(1000),
+            body_rate_window: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let (mut client, server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(handle_conn(
+            server,
+            None,
+            Arc::new(config),
+            State::default(),
+        ));
+
+        // Trickle the body a byte at a time until the server gives up
+        client
+            .write_all(b"POST /files/a.txt HTTP/1.1\r\nContent-Length: 100\r\n\r\n")
+            .await
+            .unwrap();
+        let trickle = async {
+            for _ in 0..100 {
+                sleep(Duration::from_millis(20)).await;
+                if client.write_all(b"a").await.is_err() {
+                    break;
+                }
+            }
+        };
+        trickle.await;
+
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).await.unwrap();
+        task.await.unwrap().unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(output.contains("connection: close\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_security_headers() {
+        let input = "GET /echo/a HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let output = run_conn(Config::default(), input.as_bytes()).await;
+        assert!(!output.contains("x-content-type-options"));
+        assert!(!output.contains("x-frame-options"));
+        assert!(!output.contains("referrer-policy"));
+
+        let config = Config {
+            security_headers: true,
+            ..Default::default()
+        };
+        let output = run_conn(config, input.as_bytes()).await;
+        assert!(output.contains("x-content-type-options: nosniff\r\n"));
+        assert!(output.contains("x-frame-options: DENY\r\n"));
+        assert!(output.contains("referrer-policy: no-referrer\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_no_reason_phrase() {
+        let input = "GET /echo/a HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let output = run_conn(Config::default(), input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let config = Config {
+            no_reason_phrase: true,
+            ..Default::default()
+        };
+        let output = run_conn(config, input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 200\r\n"));
+    }
+
+    #[test]
+    fn test_security_headers_override() {
+        let config = Config {
+            security_headers: true,
+            ..Default::default()
+        };
+        let resp =
+            http::Response::new(http::Status::Ok).with_header("X-Frame-Options", "SAMEORIGIN");
+        let resp = with_security_headers(resp, &config);
+        assert_eq!(resp.header("x-frame-options"), Some("SAMEORIGIN"));
+        assert_eq!(resp.header("x-content-type-options"), Some("nosniff"));
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive() {
+        let input = "GET /echo/a HTTP/1.1\r\n\r\nGET /echo/b HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let output = run_conn(Config::default(), input.as_bytes()).await;
+        assert_eq!(
+            output,
+            "HTTP/1.1 200 OK\r\ncontent-length: 1\r\ncontent-type: text/plain\r\n\
+             vary: Accept-Encoding\r\n\r\na\
+             HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 1\r\ncontent-type: text/plain\r\n\
+             vary: Accept-Encoding\r\n\r\nb"
+        );
+
+        // A bodiless response still says where it ends
+        let input = "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let output = run_conn(Config::default(), input.as_bytes()).await;
+        assert_eq!(
+            output,
+            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n\
+             HTTP/1.1 200 OK\r\nconnection: close\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade_closes() {
+        let input = "\
+            GET /chat HTTP/1.1\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n\
+            GET /echo/still-http HTTP/1.1\r\n\r\n\
+        ";
+        let output = run_conn(Config::default(), input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(output.contains("connection: Upgrade\r\n"));
+        assert!(!output.contains("still-http"));
+        assert_eq!(output.matches("HTTP/1.1").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pipelining() {
+        let dir = temp_dir("pipelining");
+        let config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+
+        // Each request is answered in order, including one whose body ends
+        // right where the next request starts
+        let input = "\
+            POST /files/a.txt HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+            GET /files/a.txt HTTP/1.1\r\n\r\n\
+            GET /echo/abc HTTP/1.1\r\nConnection: close\r\n\r\n\
+            GET /echo/ignored HTTP/1.1\r\n\r\n\
+        ";
+        let output = run_conn(config, input.as_bytes()).await;
+        let statuses: Vec<_> = output.match_indices("HTTP/1.1 ").map(|(i, _)| i).collect();
+        assert_eq!(statuses.len(), 3);
+        assert!(output[statuses[0]..].starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(output[statuses[1]..statuses[2]].ends_with("\r\n\r\nhello"));
+        assert!(output.ends_with("\r\n\r\nabc"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// An HTTP/2 GET for path on stream 1, after the client's SETTINGS
+    fn h2_get(path: &str) -> Vec<u8> {
+        let block = hpack::encode([(":method", "GET"), (":scheme", "http"), (":path", path)]);
+        let mut input = vec![0, 0, 0, 0x4, 0, 0, 0, 0, 0];
+        input.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+        input.extend_from_slice(&[0x1, 0x5, 0, 0, 0, 1]);
+        input.extend_from_slice(&block);
+        input
+    }
+
+    /// Read HTTP/2 frames until stream 1 ends, returning the fields and body
+    /// of the response on it
+    async fn read_h2_response(
+        client: &mut tokio::io::DuplexStream,
+    ) -> (Vec<(String, String)>, Vec<u8>) {
+        let (mut block, mut body) = (Vec::new(), Vec::new());
+        loop {
+            let mut head = [0u8; 9];
+            client.read_exact(&mut head).await.unwrap();
+            let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+            let mut payload = vec![0; len];
+            client.read_exact(&mut payload).await.unwrap();
+            if head[5..9] != [0, 0, 0, 1] {
+                continue;
+            }
+            match head[3] {
+                0x0 => body.extend_from_slice(&payload),
+                0x1 | 0x9 => block.extend_from_slice(&payload),
+                _ => (),
+            }
+            if head[3] != 0x9 && head[4] & 0x1 != 0 {
+                let fields = hpack::Decoder::default().decode(&block).unwrap();
+                return (fields, body);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http2_prior_knowledge() {
+        let state = State::new(AppState::default());
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let task = tokio::spawn(handle_conn(
+            server,
+            None,
+            Arc::new(Config::default()),
+            state.clone(),
+        ));
+
+        // The preface may arrive in pieces
+        client.write_all(&http2::PREFACE[0..5]).await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+        client.write_all(&http2::PREFACE[5..]).await.unwrap();
+        client.write_all(&h2_get("/echo/abc")).await.unwrap();
+        let (fields, body) = read_h2_response(&mut client).await;
+        assert_eq!(fields[0], (String::from(":status"), String::from("200")));
+        assert!(fields.contains(&(String::from("content-length"), String::from("3"))));
+        assert_eq!(body, b"abc");
+
+        drop(client);
+        task.await.unwrap().unwrap();
+        assert_eq!(state.metrics.connections(), 1);
+        assert_eq!(state.metrics.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tls_alpn() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let task = tokio::spawn(async move {
+            let config = Arc::new(Config::default());
+            handle_tls_conn(server, Some(b"h2"), None, config, State::default()).await
+        });
+        client.write_all(http2::PREFACE).await.unwrap();
+        client.write_all(&h2_get("/echo/xyz")).await.unwrap();
+        let (fields, body) = read_h2_response(&mut client).await;
+        assert_eq!(fields[0], (String::from(":status"), String::from("200")));
+        assert_eq!(body, b"xyz");
+        drop(client);
+        task.await.unwrap().unwrap();
+
+        // Without ALPN, it's HTTP/1.1
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let task = tokio::spawn(async move {
+            let config = Arc::new(Config::default());
+            handle_tls_conn(server, None, None, config, State::default()).await
+        });
+        client
+            .write_all(b"GET /echo/abc HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).await.unwrap();
+        assert!(output.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_1_0() {
+        // The connection closes after the first request unless it's kept alive
+        let input = "GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n";
+        let output = run_conn(Config::default(), input.as_bytes()).await;
+        assert_eq!(output, "HTTP/1.0 200 OK\r\nconnection: close\r\n\r\n");
+
+        let input = "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n";
+        let output = run_conn(Config::default(), input.as_bytes()).await;
+        assert_eq!(
+            output,
+            "HTTP/1.0 200 OK\r\nconnection: keep-alive\r\ncontent-length: 0\r\n\r\n\
+             HTTP/1.0 200 OK\r\nconnection: close\r\n\r\n"
+        );
+
+        // A streamed body goes without chunked encoding and ends the connection
+        let router = Router::new().route(http::Method::Get, "/stream", |_, _| {
+            let (chunks, receiver) = mpsc::channel(2);
+            chunks.try_send(Bytes::from("hello")).unwrap();
+            chunks.try_send(Bytes::from(" world")).unwrap();
+            http::Response::new(http::Status::Ok).with_streamed_body(receiver)
+        });
+        let state = State::new(AppState {
+            router,
+            ..Default::default()
+        });
+        let input = "GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n";
+        let output = run_conn_with_state(Config::default(), state, input.as_bytes()).await;
+        assert_eq!(
+            output,
+            "HTTP/1.0 200 OK\r\nconnection: close\r\n\r\nhello world"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_1_0_body_until_close() {
+        let dir = temp_dir("body-until-close");
+        let config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+
+        let input = "POST /files/a.txt HTTP/1.0\r\n\r\nhello\r\n\r\nworld";
+        let output = run_conn(config, input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.0 201 Created\r\n"));
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"hello\r\n\r\nworld");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_metrics() {
+        let state = State::new(AppState::default());
+        let input = "GET / HTTP/1.1\r\n\r\n".repeat(3);
+        run_conn_with_state(Config::default(), state.clone(), input.as_bytes()).await;
+        assert_eq!(state.metrics.connections(), 1);
+        assert_eq!(state.metrics.requests(), 3);
+
+        let input = "GET /metrics HTTP/1.1\r\n\r\n";
+        let output = run_conn_with_state(Config::default(), state.clone(), input.as_bytes()).await;
+        assert!(output.ends_with("connections 1\nrequests 3\nrequests_per_connection 3.00\n"));
+        assert_eq!(state.metrics.connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_shutting_down() {
+        let state = State::new(AppState::default());
+        state.shutting_down.store(true, Ordering::Relaxed);
+
+        let input = "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let output = run_conn_with_state(Config::default(), state, input.as_bytes()).await;
+        assert_eq!(
+            output,
+            "HTTP/1.1 503 Service Unavailable\r\nconnection: close\r\nretry-after: 5\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let state = State::new(AppState::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            Arc::new(Config::default()),
+            state.clone(),
+            async {
+                let _ = shutdown_rx.await;
+            },
+        ));
+
+        let mut open = TcpStream::connect(addr).await.unwrap();
+        open.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut buf = [0; 1024];
+        let n = open.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        shutdown.send(()).unwrap();
+        while !state.shutting_down.load(Ordering::Relaxed) {
+            tokio::task::yield_now().await;
+        }
+
+        // New connections are turned away while draining
+        let mut late = TcpStream::connect(addr).await.unwrap();
+        late.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut output = Vec::new();
+        late.read_to_end(&mut output).await.unwrap();
+        assert!(output.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+
+        // Open ones are closed after their next response, which lets the
+        // server finish
+        open.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut output = Vec::new();
+        open.read_to_end(&mut output).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.contains("connection: close\r\n"));
+        timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_content_length_warning() {
+        let wrong_length = || {
+            State::new(AppState {
+                router: Router::new().route(http::Method::Get, "/wrong", |_, _| {
+                    http::Response::new(http::Status::Ok)
+                        .with_body(b"hello", "text/plain")
+                        .with_header("Content-Length", 3)
+                }),
+                ..Default::default()
+            })
+        };
+        let input = "GET /wrong HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+        let logger = Logger::capture();
+        let config = Config {
+            logger: logger.clone(),
+            ..Default::default()
+        };
+        let output = run_conn_with_state(config, wrong_length(), input.as_bytes()).await;
+        assert!(output.contains("content-length: 5\r\n"));
+        assert!(output.ends_with("\r\n\r\nhello"));
+        assert!(logger.lines().contains(&String::from(
+            "  Warning - corrected Content-Length from 3 to 5"
+        )));
+
+        // Quiet like the rest of the request logging
+        let logger = Logger::capture();
+        let config = Config {
+            logger: logger.clone(),
+            log_level: LogLevel::Error,
+            ..Default::default()
+        };
+        run_conn_with_state(config, wrong_length(), input.as_bytes()).await;
+        assert!(logger.lines().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_response_delay() {
+        let input = "GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let delay = Duration::from_millis(300);
+
+        let config = Config {
+            debug_routes: true,
+            response_delay: Some(delay),
+            ..Default::default()
+        };
+        let start = std::time::Instant::now();
+        run_conn(config, input.as_bytes()).await;
+        assert!(start.elapsed() >= delay);
+
+        // Ignored without --debug-routes
+        let config = Config {
+            response_delay: Some(delay),
+            ..Default::default()
+        };
+        let start = std::time::Instant::now();
+        run_conn(config, input.as_bytes()).await;
+        assert!(start.elapsed() < delay);
+    }
+
+    #[tokio::test]
+    async fn test_files_trailers() {
+        let dir = temp_dir("trailers");
+        fs::write(dir.join("abc.txt"), "abc").unwrap();
+        let conn = |input: &'static str| {
+            let config = Config {
+                directory: Some(dir.clone()),
+                ..Default::default()
+            };
+            run_conn(config, input.as_bytes())
+        };
+
+        let output = conn("GET /files/abc.txt HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert!(!output.contains("transfer-encoding"));
+        assert!(!output.contains("x-checksum-sha1"));
+        assert!(output.contains("content-length: 3\r\n"));
+        assert!(output.ends_with("\r\n\r\nabc"));
+
+        // The checksum only goes out when the client accepts trailers
+        let output =
+            conn("GET /files/abc.txt HTTP/1.1\r\nTE: trailers\r\nConnection: close\r\n\r\n").await;
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.contains("transfer-encoding: chunked\r\n"));
+        assert!(output.contains("trailer: x-checksum-sha1\r\n"));
+        assert!(!output.contains("content-length"));
+        assert!(output.ends_with(
+            "\r\n\r\n3\r\nabc\r\n0\r\n\
+             x-checksum-sha1: a9993e364706816aba3e25717850c26c9cd0d89d\r\n\r\n"
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue() {
+        let dir = temp_dir("expect-continue");
+        let config = || Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let head =
+            "POST /files/a.txt HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\n";
+
+        // The client waits for the 100 Continue before sending the body
+        let (mut client, server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(handle_conn(
+            server,
+            None,
+            Arc::new(config()),
+            State::default(),
+        ));
+        client.write_all(head.as_bytes()).await.unwrap();
+        let mut interim = [0u8; 25];
+        client.read_exact(&mut interim).await.unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        client.write_all(b"abc").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        task.await.unwrap().unwrap();
+        assert!(output.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"abc");
+
+        // The client sends the body straight away
+        let output = run_conn(config(), format!("{head}xyz").as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(!output.contains("100 Continue"));
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"xyz");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expect_rejected_early() {
+        let dir = temp_dir("expect-rejected");
+        let config = Config {
+            directory: Some(dir.clone()),
+            read_only: true,
+            ..Default::default()
+        };
+
+        // The upload is turned away without asking for the body, and the
+        // connection closed in case the client sends it anyway
+        let (mut client, server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(handle_conn(
+            server,
+            None,
+            Arc::new(config),
+            State::default(),
+        ));
+        client
+            .write_all(
+                b"POST /files/a.txt HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        task.await.unwrap().unwrap();
+        assert!(output.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(!output.contains("100 Continue"));
+        assert!(output.contains("connection: close\r\n"));
+        assert!(!dir.join("a.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_expectation() {
+        let config = Config::default();
+        let state = State::new(AppState::default());
+        let (_, req) = http::Request::parser(b"GET / HTTP/1.1\r\nExpect: 200-ok\r\n\r\n").unwrap();
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::ExpectationFailed);
+    }
+
+    #[tokio::test]
+    async fn test_max_requests() {
+        let config = Config {
+            max_requests: 3,
+            ..Default::default()
+        };
+        let input = "GET / HTTP/1.1\r\n\r\n".repeat(5);
+        let output = run_conn(config, input.as_bytes()).await;
+
+        let responses: Vec<_> = output.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+        assert_eq!(responses.len(), 3);
+        assert!(!responses[1].contains("connection: close"));
+        assert!(responses[2].contains("connection: close"));
+    }
+
+    #[tokio::test]
+    async fn test_post_gzip_body() {
+        let dir = temp_dir("post-gzip");
+        let config = Config {
+            directory: Some(dir.clone()),
+            max_decompressed_size: 100,
+            ..Default::default()
+        };
+
+        let upload = |path: &str, body: &[u8]| {
+            let mut input = format!(
+                "POST {path} HTTP/1.1\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            input.extend_from_slice(body);
+            input
+        };
+
+        let mut input = upload("/files/a.txt", &compress::gzip(b"hello hello hello"));
+        input.extend(upload("/files/big.txt", &compress::gzip(&[b'a'; 1000])));
+        let output = run_conn(config, &input).await;
+
+        assert!(output.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"hello hello hello");
+        assert!(output.contains("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(!dir.join("big.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_error_template() {
+        let dir = temp_dir("error-template");
+        let template_path = dir.join("error.html");
+        fs::write(&template_path, "<h1>{code}</h1><p>{message}</p>").unwrap();
+
+        let config = Config::default();
+        let state = State::new(AppState {
+            error_template: Some(fs::read_to_string(&template_path).unwrap()),
+            ..Default::default()
+        });
+
+        let resp = handle(&get("/missing"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::NotFound);
+        assert_eq!(resp.header("content-type"), Some("text/html"));
+        assert_eq!(
+            resp.body.as_deref(),
+            Some(&b"<h1>404</h1><p>NOT FOUND</p>"[..])
+        );
+
+        // Successful responses are untouched
+        let resp = handle(&get("/"), &config, &state);
+        assert_eq!(resp.body, None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_buffer_returned_to_pool() {
+        let state = State::new(AppState::default());
+        assert_eq!(state.buffer_pool.available(), 0);
+
+        let input = "GET / HTTP/1.1\r\n\r\n";
+        run_conn_with_state(Config::default(), state.clone(), input.as_bytes()).await;
+        assert_eq!(state.buffer_pool.available(), 1);
+
+        // The next connection reuses it rather than allocating
+        run_conn_with_state(Config::default(), state.clone(), input.as_bytes()).await;
+        assert_eq!(state.buffer_pool.available(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_close_on_error_status() {
+        let input = "\
+            POST /files/a.txt HTTP/1.1\r\nContent-Encoding: gzip\r\nContent-Length: 3\r\n\r\nabc\
+            GET / HTTP/1.1\r\n\r\n\
+        ";
+        let output = run_conn(Config::default(), input.as_bytes()).await;
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\nconnection: close\r\n"));
+        assert!(!output.contains("200 OK"));
+
+        // The same request is fine when 400 isn't in the list
+        let config = Config {
+            close_on_status: vec![500],
+            ..Default::default()
+        };
+        let output = run_conn(config, input.as_bytes()).await;
+        assert!(!output.contains("connection: close"));
+        assert!(output.contains("200 OK"));
+    }
+
+    #[tokio::test]
+    async fn test_streamed_body() {
+        let router = Router::new().route(http::Method::Get, "/stream", |_, _| {
+            let (chunks, receiver) = mpsc::channel(1);
+            std::thread::spawn(move || {
+                for data in ["hello", " ", "world"] {
+                    chunks.blocking_send(Bytes::from(data)).unwrap();
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            });
+            http::Response::new(http::Status::Ok)
+                .with_streamed_body(receiver)
+                .with_trailer("X-Checksum", "abc")
+        });
+        let state = State::new(AppState {
+            router,
+            ..Default::default()
+        });
+
+        // The connection carries on after the body ends
+        let input = "\
+            GET /stream HTTP/1.1\r\nTE: trailers\r\n\r\n\
+            GET / HTTP/1.1\r\nConnection: close\r\n\r\n\
+        ";
+        let output = run_conn_with_state(Config::default(), state, input.as_bytes()).await;
+        assert_eq!(
+            output,
+            "HTTP/1.1 200 OK\r\n\
+             trailer: x-checksum\r\n\
+             transfer-encoding: chunked\r\n\r\n\
+             5\r\nhello\r\n1\r\n \r\n5\r\nworld\r\n0\r\nx-checksum: abc\r\n\r\n\
+             HTTP/1.1 200 OK\r\n\
+             connection: close\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_deadline() {
+        let router = Router::new().route(http::Method::Get, "/slow", |_, _| {
+            std::thread::sleep(Duration::from_millis(200));
+            http::Response::new(http::Status::Ok)
+        });
+        let state = State::new(AppState {
+            router,
+            ..Default::default()
+        });
+        let config = Config {
+            request_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        let input = "GET /slow HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n";
+        let output = run_conn_with_state(config, state, input.as_bytes()).await;
+        assert_eq!(
+            output,
+            "HTTP/1.1 503 Service Unavailable\r\nconnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_file_permits() {
+        let dir = temp_dir("file-permits");
+        fs::write(dir.join("a.txt"), "abc").unwrap();
+        let config = Arc::new(Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        });
+        let state = State::new(AppState {
+            file_permits: Semaphore::new(1),
+            ..Default::default()
+        });
+
+        // With the only permit taken, a file GET has to wait for it
+        let permit = state.file_permits.acquire();
+        let request = {
+            let (config, state) = (Arc::clone(&config), state.clone());
+            std::thread::spawn(move || handle(&get("/files/a.txt"), &config, &state))
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!request.is_finished());
+
+        drop(permit);
+        let resp = request.join().unwrap();
+        assert_eq!(resp.status_line.status, http::Status::Ok);
+        assert_eq!(state.file_permits.available(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_etag_preconditions() {
+        let dir = temp_dir("etag-preconditions");
+        fs::write(dir.join("a.txt"), "abc").unwrap();
+        let config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+        let request = |header: &str| {
+            let input = format!("GET /files/a.txt HTTP/1.1\r\n{header}\r\n\r\n");
+            let (_, req) = http::Request::parser(input.as_bytes()).unwrap();
+            handle(&req, &config, &state)
+        };
+
+        // The weak ETag matches itself under If-None-Match, but never
+        // under If-Match
+        let resp = handle(&get("/files/a.txt"), &config, &state);
+        let etag = resp.header("etag").unwrap().to_owned();
+        assert!(etag.starts_with("W/"));
+        let strong = etag.trim_start_matches("W/");
+
+        let resp = request(&format!("If-None-Match: {strong}"));
+        assert_eq!(resp.status_line.status, http::Status::NotModified);
+        let resp = request(&format!("If-Match: {strong}"));
+        assert_eq!(resp.status_line.status, http::Status::PreconditionFailed);
+        let resp = request("If-None-Match: \"other\"");
+        assert_eq!(resp.status_line.status, http::Status::Ok);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_byte_range() {
+        assert_eq!(parse_byte_range("bytes=0-4", 10), Some(Ok(0..5)));
+        assert_eq!(parse_byte_range("bytes=5-", 10), Some(Ok(5..10)));
+        assert_eq!(parse_byte_range("bytes=-3", 10), Some(Ok(7..10)));
+        assert_eq!(parse_byte_range("bytes=8-100", 10), Some(Ok(8..10)));
+        assert_eq!(parse_byte_range("bytes=10-", 10), Some(Err(())));
+        assert_eq!(parse_byte_range("bytes=5-2", 10), None);
+        assert_eq!(parse_byte_range("bytes=0-1,4-5", 10), None);
+        assert_eq!(parse_byte_range("lines=0-1", 10), None);
+    }
+
+    #[test]
+    fn test_single_file_range() {
+        let dir = temp_dir("single-file");
+        fs::write(dir.join("a.txt"), "0123456789").unwrap();
+        let config = Config {
+            file: Some(dir.join("a.txt")),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        let resp = handle(&get("/"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Ok);
+        assert_eq!(resp.body.as_deref(), Some(&b"0123456789"[..]));
+
+        let input = b"GET / HTTP/1.1\r\nRange: bytes=2-4\r\nAccept-Encoding: gzip\r\n\r\n";
+        let (_, req) = http::Request::parser(input).unwrap();
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::PartialContent);
+        assert_eq!(resp.header("content-range"), Some("bytes 2-4/10"));
+        assert_eq!(resp.body.as_deref(), Some(&b"234"[..]));
+
+        let input = b"GET / HTTP/1.1\r\nRange: bytes=20-\r\n\r\n";
+        let (_, req) = http::Request::parser(input).unwrap();
+        let resp = handle(&req, &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::RangeNotSatisfiable);
+        assert_eq!(resp.header("content-range"), Some("bytes */10"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_index_file() {
+        let dir = temp_dir("index-file");
+        fs::create_dir_all(dir.join("files")).unwrap();
+        fs::write(dir.join("home.html"), "<h1>Home</h1>").unwrap();
+        let mut config = Config {
+            directory: Some(dir.join("files")),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        let resp = handle(&get("/"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Ok);
+        assert_eq!(resp.body, None);
+
+        config.index_file = Some(dir.join("home.html"));
+        let resp = handle(&get("/"), &config, &state);
+        assert_eq!(resp.header("content-type"), Some("text/html"));
+        assert_eq!(resp.body.as_deref(), Some(&b"<h1>Home</h1>"[..]));
+
+        // Only served at the root, not from the files directory
+        let resp = handle(&get("/files/home.html"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::NotFound);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_content_type_for() {
+        assert_eq!(content_type_for(Path::new("a/index.HTML")), "text/html");
+        assert_eq!(content_type_for(Path::new("style.css")), "text/css");
+        assert_eq!(
+            content_type_for(Path::new("data")),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn test_index_files() {
+        let dir = temp_dir("index-files");
+        fs::create_dir_all(dir.join("site")).unwrap();
+        fs::write(dir.join("site").join("index.htm"), "htm").unwrap();
+        let mut config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        // Only index.html is tried by default
+        let resp = handle(&get("/files/site/"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::NotFound);
+
+        config.index_files = vec![String::from("index.html"), String::from("index.htm")];
+        let resp = handle(&get("/files/site/"), &config, &state);
+        assert_eq!(resp.body.as_deref(), Some(&b"htm"[..]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_if_unmodified_since() {
+        let dir = temp_dir("unmodified-since");
+        fs::write(dir.join("a.txt"), "0123456789").unwrap();
+        let config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+        let request = |input: String| {
+            let (_, req) = http::Request::parser(input.as_bytes()).unwrap();
+            handle(&req, &config, &state)
+        };
+        let past = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let future = "Fri, 01 Jan 2100 00:00:00 GMT";
+
+        let put = |date| {
+            format!("PUT /files/a.txt HTTP/1.1\r\nIf-Unmodified-Since: {date}\r\nContent-Length: 3\r\n\r\nnew")
+        };
+        let resp = request(put(past));
+        assert_eq!(resp.status_line.status, http::Status::PreconditionFailed);
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"0123456789");
+
+        let resp = request(put(future));
+        assert_eq!(resp.status_line.status, http::Status::Created);
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"new");
+
+        // A GET goes ahead, but the whole file is sent
+        let get = |date| {
+            format!("GET /files/a.txt HTTP/1.1\r\nIf-Unmodified-Since: {date}\r\nRange: bytes=0-0\r\n\r\n")
+        };
+        let resp = request(get(past));
+        assert_eq!(resp.status_line.status, http::Status::Ok);
+        assert_eq!(resp.body.as_deref(), Some(&b"new"[..]));
+
+        let resp = request(get(future));
+        assert_eq!(resp.status_line.status, http::Status::PartialContent);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_vhost_files() {
+        let dir = temp_dir("vhosts");
+        for host in ["files", "static"] {
+            fs::create_dir_all(dir.join(host)).unwrap();
+            fs::write(dir.join(host).join("a.txt"), host).unwrap();
+        }
+        let config = Config {
+            vhosts: vec![
+                (String::from("files.example.com"), dir.join("files")),
+                (String::from("static.example.com"), dir.join("static")),
+            ],
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        for host in ["files", "static"] {
+            let input =
+                format!("GET /files/a.txt HTTP/1.1\r\nHost: {host}.example.com:4221\r\n\r\n");
+            let (_, req) = http::Request::parser(input.as_bytes()).unwrap();
+            let resp = handle(&req, &config, &state);
+            assert_eq!(resp.body.as_deref(), Some(host.as_bytes()));
+        }
+
+        // No default directory to fall back to
+        let resp = handle(&get("/files/a.txt"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Internal);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_dir_size() {
+        let dir = temp_dir("max-dir-size");
+        fs::write(dir.join("a.txt"), "12345").unwrap();
+        let config = Config {
+            directory: Some(dir.clone()),
+            max_dir_size: Some(10),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        let resp = handle(&post("/files/b.txt", "123456"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::InsufficientStorage);
+        assert!(!dir.join("b.txt").exists());
+
+        let resp = handle(&post("/files/b.txt", "12345"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Created);
+
+        // Replacing a file only counts the difference in size
+        let resp = handle(&post("/files/a.txt", "1234"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Created);
+
+        // Deleting makes room
+        let (_, req) = http::Request::parser(b"DELETE /files/b.txt HTTP/1.1\r\n\r\n").unwrap();
+        handle(&req, &config, &state);
+        let resp = handle(&post("/files/c.txt", "123456"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::Created);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_trailing_slash_redirect() {
+        let dir = temp_dir("trailing-slash");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub").join("index.html"), "index").unwrap();
+        let mut config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        // Off by default, so the index file is served directly
+        let resp = handle(&get("/files/sub"), &config, &state);
+        assert_eq!(resp.body.as_deref(), Some(&b"index"[..]));
+
+        config.trailing_slash_redirect = true;
+        let resp = handle(&get("/files/sub"), &config, &state);
+        assert_eq!(resp.status_line.status, http::Status::MovedPermanently);
+        assert_eq!(resp.header("location"), Some("/files/sub/"));
+
+        let resp = handle(&get("/files/sub?download=1"), &config, &state);
+        assert_eq!(resp.header("location"), Some("/files/sub/?download=1"));
+
+        let resp = handle(&get("/files/sub/"), &config, &state);
+        assert_eq!(resp.body.as_deref(), Some(&b"index"[..]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_user_agent_files() {
+        let dir = temp_dir("user-agent");
+        fs::create_dir_all(dir.join("mobile")).unwrap();
+        fs::write(dir.join("a.txt"), "desktop").unwrap();
+        fs::write(dir.join("b.txt"), "desktop").unwrap();
+        fs::write(dir.join("mobile").join("a.txt"), "mobile").unwrap();
+        let config = Config {
+            directory: Some(dir.clone()),
+            user_agent_dirs: vec![(String::from("Mobile"), String::from("mobile"))],
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        let request = |path: &str, user_agent: &str| {
+            let input = format!("GET {path} HTTP/1.1\r\nUser-Agent: {user_agent}\r\n\r\n");
+            let (_, req) = http::Request::parser(input.as_bytes()).unwrap();
+            handle(&req, &config, &state)
+        };
+
+        let resp = request(
+            "/files/a.txt",
+            "Mozilla/5.0 (Linux; Android 14) Mobile Safari",
+        );
+        assert_eq!(resp.body.as_deref(), Some(&b"mobile"[..]));
+        assert_eq!(resp.header("vary"), Some("User-Agent"));
+
+        let resp = request("/files/a.txt", "Mozilla/5.0 (X11; Linux x86_64)");
+        assert_eq!(resp.body.as_deref(), Some(&b"desktop"[..]));
+
+        // Files without a mobile variant fall back to the default
+        let resp = request(
+            "/files/b.txt",
+            "Mozilla/5.0 (Linux; Android 14) Mobile Safari",
+        );
+        assert_eq!(resp.body.as_deref(), Some(&b"desktop"[..]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_length_matches_body() {
+        let dir = temp_dir("files-race");
+        fs::write(dir.join("a.txt"), "").unwrap();
+        let config = Config {
+            directory: Some(dir.clone()),
+            ..Default::default()
+        };
+        let state = State::new(AppState::default());
+
+        // Keep rewriting the file with different lengths while serving it
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let writer = {
+            let (path, done) = (dir.join("a.txt"), Arc::clone(&done));
+            std::thread::spawn(move || {
+                for len in (0..1000).cycle() {
+                    if done.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    fs::write(&path, "x".repeat(len % 100)).unwrap();
+                }
+            })
+        };
+
+        for _ in 0..200 {
+            // Before finalize, which would correct a wrong Content-Length
+            let resp = handle(&get("/files/a.txt"), &config, &state);
+            if resp.status_line.status != http::Status::Ok {
+                continue;
+            }
+            let body_len = resp.body.as_ref().map_or(0, Vec::len);
+            assert_eq!(resp.header("content-length"), Some(&*body_len.to_string()));
+        }
+
+        done.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
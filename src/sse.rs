@@ -0,0 +1,48 @@
+use crate::http;
+
+/// Content type of a server-sent event stream
+const EVENT_STREAM: &str = "text/event-stream";
+
+/// The head of an event stream response. The events follow as the body until
+/// the connection closes, so there's no Content-Length.
+pub fn response() -> http::Response {
+    http::Response::new(http::Status::Ok)
+        .with_header("Content-Type", EVENT_STREAM)
+        .with_header("Cache-Control", "no-cache")
+        // Ask any proxy in front not to hold events back
+        .with_header("X-Accel-Buffering", "no")
+}
+
+/// Whether the response starts an event stream
+pub fn is_event_stream(resp: &http::Response) -> bool {
+    resp.status_line.status == http::Status::Ok && resp.header("content-type") == Some(EVENT_STREAM)
+}
+
+/// Frame data as a single event. Each line gets its own `data:` field so
+/// that newlines in the data survive.
+pub fn frame(data: &str) -> String {
+    let mut frame: String = data.lines().map(|line| format!("data: {line}\n")).collect();
+    if frame.is_empty() {
+        frame.push_str("data: \n");
+    }
+    frame.push('\n');
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame() {
+        assert_eq!(frame("hello"), "data: hello\n\n");
+        assert_eq!(frame("one\ntwo"), "data: one\ndata: two\n\n");
+        assert_eq!(frame(""), "data: \n\n");
+    }
+
+    #[test]
+    fn test_is_event_stream() {
+        assert!(is_event_stream(&response()));
+        assert!(!is_event_stream(&http::Response::new(http::Status::Ok)));
+    }
+}
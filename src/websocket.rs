@@ -0,0 +1,114 @@
+use crate::{http, sha1::sha1};
+
+/// Fixed GUID from RFC 6455 that is appended to the client's key
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Whether the request is asking to upgrade the connection to a WebSocket
+pub fn is_upgrade_request(req: &http::Request) -> bool {
+    let upgrade = req
+        .headers
+        .get("upgrade")
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let connection = req.headers.get("connection").is_some_and(|v| {
+        v.split(',')
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+    });
+    let has_key = req.headers.contains_key("sec-websocket-key");
+
+    upgrade && connection && has_key
+}
+
+/// Build the 101 response completing the opening handshake. Returns None if
+/// the request has no key to answer.
+pub fn handshake(req: &http::Request) -> Option<http::Response> {
+    let key = req.headers.get("sec-websocket-key")?;
+    let resp = http::Response::new(http::Status::SwitchingProtocols)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key(key));
+    Some(resp)
+}
+
+/// Compute the Sec-WebSocket-Accept value for a client's Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    let mut input = key.trim().as_bytes().to_vec();
+    input.extend_from_slice(ACCEPT_GUID.as_bytes());
+    base64_encode(&sha1(&input))
+}
+
+/// Encode bytes as standard, padded base64
+pub fn base64_encode(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+
+        output.push(ALPHABET[(n >> 18) as usize & 0x3f] as char);
+        output.push(ALPHABET[(n >> 12) as usize & 0x3f] as char);
+        if chunk.len() > 1 {
+            output.push(ALPHABET[(n >> 6) as usize & 0x3f] as char);
+        } else {
+            output.push('=');
+        }
+        if chunk.len() > 2 {
+            output.push(ALPHABET[n as usize & 0x3f] as char);
+        } else {
+            output.push('=');
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // Example from RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_handshake() {
+        let input = b"\
+            GET /chat HTTP/1.1\r\n\
+            Host: localhost:4221\r\n\
+            Upgrade: websocket\r\n\
+            Connection: keep-alive, Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            \r\n\
+        ";
+        let (_, req) = http::Request::parser(input).unwrap();
+        assert!(is_upgrade_request(&req));
+
+        let resp = handshake(&req).unwrap();
+        assert_eq!(resp.status_line.status, http::Status::SwitchingProtocols);
+        assert_eq!(
+            resp.header("sec-websocket-accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+    }
+
+    #[test]
+    fn test_not_upgrade_request() {
+        let input = b"\
+            GET /chat HTTP/1.1\r\n\
+            Upgrade: websocket\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            \r\n\
+        ";
+        let (_, req) = http::Request::parser(input).unwrap();
+        assert!(!is_upgrade_request(&req));
+    }
+}
@@ -0,0 +1,80 @@
+use std::{env, fs, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use http_server_starter_rust::{
+    config::Config,
+    server::{self, AppState, State},
+};
+
+/// Send one request on a new connection and return the whole response
+async fn send(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut output = Vec::new();
+    stream.read_to_end(&mut output).await.unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+#[tokio::test]
+async fn test_files_lifecycle() {
+    let dir = env::temp_dir().join(format!("http-server-lifecycle-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = Arc::new(Config {
+        directory: Some(dir.clone()),
+        ..Default::default()
+    });
+    tokio::spawn(server::serve(
+        listener,
+        config,
+        State::new(AppState::default()),
+        std::future::pending(),
+    ));
+
+    let output = send(
+        addr,
+        "POST /files/a.txt HTTP/1.1\r\nConnection: close\r\nContent-Length: 11\r\n\r\nhello world",
+    )
+    .await;
+    assert!(output.starts_with("HTTP/1.1 201 Created\r\n"));
+
+    let output = send(
+        addr,
+        "GET /files/a.txt HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(output.ends_with("\r\n\r\nhello world"));
+
+    let output = send(
+        addr,
+        "HEAD /files/a.txt HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(output.contains("content-length: 11\r\n"));
+    assert!(output.ends_with("\r\n\r\n"));
+
+    let output = send(
+        addr,
+        "DELETE /files/a.txt HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(output.starts_with("HTTP/1.1 204 No Content\r\n"));
+    assert!(!dir.join("a.txt").exists());
+
+    let output = send(
+        addr,
+        "GET /files/a.txt HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(output.starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}